@@ -0,0 +1,56 @@
+// benches/submit_bench.rs
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use criterion::{Criterion, criterion_group, criterion_main};
+use tokio::runtime::Runtime;
+use vrc_log::provider::{Provider, ProviderKind, submit_each};
+
+/// Stands in for a provider answering after a fixed round trip
+struct MockProvider {
+    max_concurrency: usize,
+}
+
+#[async_trait]
+impl Provider for MockProvider {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::iter().next().unwrap()
+    }
+
+    fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    async fn send_avatar_id(&self, _avatar_id: &str) -> Result<bool> {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        Ok(true)
+    }
+}
+
+fn bench_submit_each(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+
+    // One file's worth of new avatars
+    let avatar_ids = (0..100).map(|i| format!("avtr_{i:08}")).collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("submit 100 avatars with 5ms round trips");
+    group.sample_size(10);
+    for max_concurrency in [1, 4, 16] {
+        let provider = MockProvider { max_concurrency };
+        group.bench_function(format!("{max_concurrency} at a time"), |b| {
+            b.iter(|| {
+                rt.block_on(async {
+                    let results = submit_each(&provider, &avatar_ids).await;
+
+                    // Sanity check
+                    assert_eq!(results.len(), avatar_ids.len());
+                });
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(submit_benches, bench_submit_each);
+criterion_main!(submit_benches);
@@ -0,0 +1,215 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, bail};
+use derive_config::DeriveTomlConfig;
+
+use crate::settings::Settings;
+
+/// # Avatar and author IDs that are never submitted
+///
+/// Stored as plain text next to the config file, one `avtr_` or `usr_` ID per line.
+/// Empty lines and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    avatars: HashSet<String>,
+    authors: HashSet<String>,
+}
+
+impl Blocklist {
+    /// # Errors
+    /// Will return `Err` if the config directory couldn't be found
+    pub fn path() -> Result<PathBuf> {
+        let file = format!("{}-blocklist.txt", env!("CARGO_PKG_NAME"));
+        Ok(Settings::path()?.with_file_name(file))
+    }
+
+    /// # Errors
+    /// Will return `Err` if the blocklist exists but couldn't be read
+    pub fn load() -> Result<Self> {
+        Self::load_from(&Self::path()?)
+    }
+
+    /// Like `Blocklist::load`, but blocks nothing if the file couldn't be read
+    #[must_use]
+    pub fn load_or_warn() -> Self {
+        Self::load().unwrap_or_else(|error| {
+            warn!("Failed to load the blocklist: {error}");
+            Self::default()
+        })
+    }
+
+    /// # Errors
+    /// Will return `Err` if the file exists but couldn't be read
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let mut blocklist = Self::default();
+        blocklist.extend(Self::read_ids(path)?);
+
+        Ok(blocklist)
+    }
+
+    /// Blocks more `avtr_` or `usr_` IDs without saving them
+    pub fn extend<I: IntoIterator<Item = String>>(&mut self, ids: I) {
+        for id in ids {
+            if id.starts_with("usr_") {
+                self.authors.insert(id);
+            } else {
+                self.avatars.insert(id);
+            }
+        }
+    }
+
+    /// Adds the ID to the file, returns false if it was already blocked
+    ///
+    /// # Errors
+    /// Will return `Err` if the ID isn't an avatar or author ID, or the file couldn't be written
+    pub fn block(path: &Path, id: &str) -> Result<bool> {
+        validate(id)?;
+        if Self::read_ids(path)?.iter().any(|blocked| blocked == id) {
+            return Ok(false);
+        }
+
+        let mut contents = read_to_string(path)?;
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(id);
+        contents.push('\n');
+        std::fs::write(path, contents)?;
+
+        Ok(true)
+    }
+
+    /// Removes the ID from the file, returns false if it wasn't blocked
+    ///
+    /// # Errors
+    /// Will return `Err` if the ID isn't an avatar or author ID, or the file couldn't be written
+    pub fn unblock(path: &Path, id: &str) -> Result<bool> {
+        validate(id)?;
+        let contents = read_to_string(path)?;
+        let lines = contents
+            .lines()
+            .filter(|line| line.trim() != id)
+            .collect::<Vec<_>>();
+        if lines.len() == contents.lines().count() {
+            return Ok(false);
+        }
+
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        std::fs::write(path, contents)?;
+
+        Ok(true)
+    }
+
+    #[must_use]
+    pub fn has_authors(&self) -> bool {
+        !self.authors.is_empty()
+    }
+
+    /// Drops blocked avatars, `author_ids` maps avatar IDs to known authors
+    pub fn filter<I: IntoIterator<Item = String>>(
+        &self,
+        avatar_ids: I,
+        author_ids: &HashMap<String, String>,
+    ) -> Vec<String> {
+        avatar_ids
+            .into_iter()
+            .filter(|avatar_id| {
+                if self.avatars.contains(avatar_id) {
+                    debug!("{avatar_id} is blocked, skipping");
+                    return false;
+                }
+                if let Some(author_id) = author_ids.get(avatar_id)
+                    && self.authors.contains(author_id)
+                {
+                    debug!("{avatar_id} by {author_id} is blocked, skipping");
+                    return false;
+                }
+
+                true
+            })
+            .collect()
+    }
+
+    fn read_ids(path: &Path) -> Result<Vec<String>> {
+        let ids = read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+
+        Ok(ids)
+    }
+}
+
+fn read_to_string(path: &Path) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn validate(id: &str) -> Result<()> {
+    if !id.starts_with("avtr_") && !id.starts_with("usr_") {
+        bail!("Expected an avatar (avtr_...) or author (usr_...) ID, got {id}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AVATAR: &str = "avtr_c38a1615-5bf5-42b4-84eb-a8b6c37cbd11";
+    const AUTHOR: &str = "usr_c1644b5b-3ca4-45b4-97c6-a2a0de70d469";
+
+    #[test]
+    fn blocks_and_unblocks_keeping_comments() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("blocklist.txt");
+        std::fs::write(&path, "# friends' private avatars").unwrap();
+
+        assert!(Blocklist::block(&path, AVATAR).unwrap());
+        assert!(!Blocklist::block(&path, AVATAR).unwrap());
+        assert!(Blocklist::block(&path, AUTHOR).unwrap());
+        assert!(Blocklist::block(&path, "wrld_1234").is_err());
+
+        let blocklist = Blocklist::load_from(&path).unwrap();
+        assert!(blocklist.has_authors());
+        assert!(blocklist.avatars.contains(AVATAR));
+
+        assert!(Blocklist::unblock(&path, AUTHOR).unwrap());
+        assert!(!Blocklist::unblock(&path, AUTHOR).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("# friends' private avatars\n{AVATAR}\n")
+        );
+    }
+
+    #[test]
+    fn filters_blocked_avatars_and_authors() {
+        let blocklist = Blocklist {
+            avatars: HashSet::from([String::from("avtr_blocked")]),
+            authors: HashSet::from([String::from(AUTHOR)]),
+        };
+        let author_ids = HashMap::from([(String::from("avtr_by_author"), String::from(AUTHOR))]);
+
+        let avatar_ids = ["avtr_blocked", "avtr_by_author", "avtr_allowed"].map(String::from);
+        assert_eq!(blocklist.filter(avatar_ids, &author_ids), ["avtr_allowed"]);
+    }
+
+    #[test]
+    fn missing_file_blocks_nothing() {
+        let directory = tempfile::tempdir().unwrap();
+        let blocklist = Blocklist::load_from(&directory.path().join("missing.txt")).unwrap();
+        assert!(blocklist.avatars.is_empty());
+        assert!(!blocklist.has_authors());
+    }
+}
@@ -1,24 +1,276 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::{File, TryLockError},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{Result, bail};
+use chrono::Local;
 use itertools::Itertools;
-use rusqlite::{Connection as RusqliteConnection, named_params, params_from_iter};
+use rusqlite::{
+    Connection as RusqliteConnection,
+    ErrorCode,
+    OpenFlags,
+    OptionalExtension,
+    TransactionBehavior,
+    named_params,
+    params,
+    params_from_iter,
+};
 use tokio_rusqlite_new::Connection;
 
-use crate::vrchat::VRCHAT_LOW_PATH;
+use crate::{
+    Source,
+    provider::{AvatarMetadata, ProviderKind},
+    vrchat::VRCHAT_LOW_PATH,
+};
 
+pub const DATABASE_NAME: &str = "avatars.sqlite";
+pub const BACKUP_DIRECTORY_NAME: &str = "avatars-backups";
+
+/// How long to wait for another process holding the write lock
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Provider bits of a merged avatar, leaving out a side older than 30 days
+///
+/// Those bits would be resubmitted, the other side's newer `updated_at` mustn't revive them.
+const MERGED_BITS: &str = "CASE
+        WHEN excluded.updated_at < datetime('now', '-30 days') THEN COALESCE(provider_bits, 0)
+        WHEN updated_at < datetime('now', '-30 days') THEN excluded.provider_bits
+        ELSE COALESCE(provider_bits, 0) | excluded.provider_bits
+    END";
+
+#[derive(Clone)]
 pub struct Cache {
     connection: Connection,
 }
 
 pub type AvatarIDWithProvider<S> = (S, u32);
 
+/// # Avatar IDs a batching provider hasn't flushed yet
+///
+/// Survives crashes, so the IDs can be replayed on the next start.
+#[derive(Clone)]
+pub struct Outbox {
+    connection:   Connection,
+    provider_bit: u32,
+}
+
+impl Outbox {
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn push(&self, avatar_id: &str) -> Result<()> {
+        let (avatar_id, provider_bit) = (avatar_id.to_string(), self.provider_bit);
+        self.connection
+            .call(move |c| {
+                c.execute(
+                    "INSERT OR IGNORE INTO outbox (id, provider_bit) VALUES (?1, ?2)",
+                    params![avatar_id, provider_bit],
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes delivered avatar IDs and marks the provider bit for them
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn complete(&self, avatar_ids: &[String]) -> Result<()> {
+        let (avatar_ids, provider_bit) = (avatar_ids.to_vec(), self.provider_bit);
+        self.connection
+            .call(move |c| -> Result<(), rusqlite::Error> {
+                let tx = c.transaction()?;
+                for id in avatar_ids {
+                    tx.execute(
+                        "DELETE FROM outbox WHERE id = ?1 AND provider_bit = ?2",
+                        params![id, provider_bit],
+                    )?;
+                    Cache::add_provider_bit_in(&tx, &id, provider_bit)?;
+                }
+                tx.commit()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Pending avatar IDs, oldest first
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn load(&self) -> Result<Vec<String>> {
+        let provider_bit = self.provider_bit;
+        self.connection
+            .call(move |c| {
+                let mut statement = c.prepare(
+                    "SELECT id FROM outbox WHERE provider_bit = ?1 ORDER BY created_at, rowid",
+                )?;
+                statement
+                    .query_map([provider_bit], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+}
+
+#[derive(Debug)]
+pub struct RetryEntry {
+    pub avatar_id:       String,
+    pub provider_bit:    u32,
+    pub attempts:        u32,
+    pub last_error:      Option<String>,
+    pub created_at:      String,
+    pub next_attempt_at: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SourceStats {
+    pub source:    Option<Source>,
+    pub avatars:   i64,
+    pub sightings: i64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    pub added:     usize,
+    pub updated:   usize,
+    pub unchanged: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SearchResult {
+    pub avatar_id: String,
+    pub name:      Option<String>,
+    pub author:    Option<String>,
+    pub worlds:    Option<String>,
+}
+
+/// # Exclusive lock on the cache file
+///
+/// The live watcher holds this for its whole runtime,
+/// maintenance commands refuse to run while it's held elsewhere.
+pub struct CacheLock {
+    _file: File,
+}
+
+impl CacheLock {
+    /// # Errors
+    /// Will return `Err` if the lock file can't be opened or another instance holds the lock
+    pub fn try_acquire() -> Result<Self> {
+        Self::try_acquire_at_location(&VRCHAT_LOW_PATH.join(format!("{DATABASE_NAME}.lock")))
+    }
+
+    /// # Errors
+    /// Will return `Err` if the lock file can't be opened or another instance holds the lock
+    pub fn try_acquire_at_location(path: &Path) -> Result<Self> {
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => bail!("VRC-LOG is already running, close it first"),
+            Err(TryLockError::Error(error)) => Err(error.into()),
+        }
+    }
+}
+
 impl Cache {
+    /// Opens the default cache, restoring the latest backup if it's corrupted
+    ///
     /// # Errors
     /// Will return `Err` if `sqlite::open` errors
     pub async fn new() -> Result<Self> {
-        debug!("Trying to open SQLite cache database.");
-        Self::new_at_location(&VRCHAT_LOW_PATH.join("avatars.sqlite")).await
+        let path = VRCHAT_LOW_PATH.join(DATABASE_NAME);
+        Self::open_or_recover(&path, &VRCHAT_LOW_PATH.join(BACKUP_DIRECTORY_NAME)).await
+    }
+
+    /// Opens the cache, restoring the latest backup only if `SQLite` reports it's corrupted
+    ///
+    /// Anything else, like another process holding the lock or a full disk, is returned as is.
+    ///
+    /// # Errors
+    /// Will return `Err` if `sqlite::open` errors for any reason but corruption
+    pub async fn open_or_recover(path: &Path, backup_directory: &Path) -> Result<Self> {
+        match Self::new_at_location(&path.to_path_buf()).await {
+            Ok(cache) => {
+                if cache.quick_check().await? {
+                    return Ok(cache);
+                }
+
+                warn!("The avatar cache failed the integrity check");
+                cache.close().await?;
+            }
+            Err(error) if Self::is_corrupt(&error) => {
+                warn!("The avatar cache is corrupted: {error}");
+            }
+            Err(error) => return Err(error),
+        }
+
+        Self::recover(path, backup_directory).await
+    }
+
+    /// Whether `SQLite` gave up on the file itself, rather than on access to it
+    fn is_corrupt(error: &anyhow::Error) -> bool {
+        error
+            .chain()
+            .filter_map(|error| error.downcast_ref::<rusqlite::Error>())
+            .any(|error| {
+                matches!(
+                    error.sqlite_error_code(),
+                    Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase)
+                )
+            })
+    }
+
+    /// Moves a corrupted cache aside and restores the latest backup, or starts fresh
+    ///
+    /// # Errors
+    /// Will return `Err` if the file can't be moved or a fresh cache can't be created
+    pub async fn recover(path: &Path, backup_directory: &Path) -> Result<Self> {
+        let timestamp = Local::now().format("%Y%m%d-%H%M%S");
+        let corrupt_path = path.with_extension(format!("sqlite.corrupt-{timestamp}"));
+        if path.exists() {
+            std::fs::rename(path, &corrupt_path)?;
+            warn!("Moved the corrupted cache to {corrupt_path:?}");
+        }
+
+        // SQLite would otherwise replay a leftover journal into the restored backup
+        for suffix in ["-journal", "-wal", "-shm"] {
+            let sidecar = Self::sidecar(path, suffix);
+            if sidecar.exists() {
+                std::fs::rename(&sidecar, Self::sidecar(&corrupt_path, suffix))?;
+            }
+        }
+
+        if let Some(backup) = Self::list_backups(backup_directory)?.pop() {
+            std::fs::copy(&backup, path)?;
+            match Self::new_at_location(&path.to_path_buf()).await {
+                Ok(cache) if cache.quick_check().await.unwrap_or_default() => {
+                    warn!("Restored the avatar cache from {backup:?}");
+                    return Ok(cache);
+                }
+                Ok(cache) => cache.close().await?,
+                Err(error) => warn!("The backup {backup:?} failed to open: {error}"),
+            }
+
+            std::fs::remove_file(path)?;
+        }
+
+        warn!("Starting with an empty avatar cache");
+        Self::new_at_location(&path.to_path_buf()).await
+    }
+
+    /// Path of a file `SQLite` keeps next to the database, like its `-journal`
+    fn sidecar(path: &Path, suffix: &str) -> PathBuf {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(suffix);
+        PathBuf::from(sidecar)
     }
 
     /// # Errors
@@ -27,8 +279,65 @@ impl Cache {
         debug!("Trying to open SQLite cache database.");
         let connection = Connection::open(path).await?;
 
+        // Wait for another process writing to the cache instead of failing right away
         connection
-            .call(|connection| Self::setup_database(connection))
+            .call(|connection| {
+                connection.busy_timeout(BUSY_TIMEOUT)?;
+                Self::setup_database(connection)
+            })
+            .await?;
+
+        Ok(Self { connection })
+    }
+
+    /// Opens the default cache for reading only, without migrating or recovering it
+    ///
+    /// # Errors
+    /// Will return `Err` if `sqlite::open` errors
+    pub async fn read_only() -> Result<Self> {
+        Self::read_only_at_location(&VRCHAT_LOW_PATH.join(DATABASE_NAME)).await
+    }
+
+    /// Opens the cache for reading only, a missing or outdated one reads as empty
+    ///
+    /// # Errors
+    /// Will return `Err` if `sqlite::open` errors
+    pub async fn read_only_at_location(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Self::new_in_memory().await;
+        }
+
+        let cache = Self::open_read_only(path).await?;
+        let is_current = cache
+            .connection
+            .call(|connection| {
+                connection
+                    .prepare("SELECT 1 FROM sqlite_master WHERE name = 'avatar_search_ids'")?
+                    .exists([])
+            })
+            .await?;
+        if is_current {
+            return Ok(cache);
+        }
+
+        // It's only migrated when opened for writing
+        warn!("The avatar cache is from an older version, every avatar is treated as new");
+        Self::new_in_memory().await
+    }
+
+    /// Opens the cache for reading only as it is, whatever version it's from
+    ///
+    /// # Errors
+    /// Will return `Err` if the cache doesn't exist or `sqlite::open` errors
+    pub async fn open_read_only(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            bail!("There is no avatar cache at {}", path.display());
+        }
+
+        let connection =
+            Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).await?;
+        connection
+            .call(|connection| connection.busy_timeout(BUSY_TIMEOUT))
             .await?;
 
         Ok(Self { connection })
@@ -49,7 +358,10 @@ impl Cache {
                     id TEXT PRIMARY KEY,
                     created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
                     updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                    provider_bits INT DEFAULT 0
+                    provider_bits INT DEFAULT 0,
+                    first_source TEXT,
+                    seen_count INT DEFAULT 0,
+                    last_seen_at DATETIME
                 )";
 
         debug!("Trying to create avatars table...");
@@ -95,6 +407,31 @@ impl Cache {
                         ", [])?;
             }
 
+            if !columns.contains(&"first_source".to_string()) {
+                debug!("Trying to create the first_source column.");
+                #[rustfmt::skip]
+                        connection.execute("
+                            ALTER TABLE avatars
+                            ADD COLUMN first_source TEXT
+                        ", [])?;
+            }
+            if !columns.contains(&"seen_count".to_string()) {
+                debug!("Trying to create the seen_count column.");
+                #[rustfmt::skip]
+                        connection.execute("
+                            ALTER TABLE avatars
+                            ADD COLUMN seen_count INT DEFAULT 0
+                        ", [])?;
+            }
+            if !columns.contains(&"last_seen_at".to_string()) {
+                debug!("Trying to create the last_seen_at column.");
+                #[rustfmt::skip]
+                        connection.execute("
+                            ALTER TABLE avatars
+                            ADD COLUMN last_seen_at DATETIME
+                        ", [])?;
+            }
+
             debug!("Updating all rows with missing updated_at");
             #[rustfmt::skip]
                     connection.execute("
@@ -120,6 +457,8 @@ impl Cache {
                     ON avatars(id)
                 ", [])?;
 
+        Self::setup_tables(connection)?;
+
         if let Ok(mut statement) = connection.prepare("SELECT COUNT(*) FROM avatars")
             && let Ok(count) = statement.query_row([], |row| row.get::<_, i64>(0))
         {
@@ -169,184 +508,1808 @@ impl Cache {
             .map_err(anyhow::Error::from)
     }
 
-    const CHUNK_SIZE: usize = 950; // sqlite parameter limit is 999
+    /// Tables next to `avatars`, these were added after it so they're created if missing
+    fn setup_tables(connection: &RusqliteConnection) -> Result<(), rusqlite::Error> {
+        debug!("Trying to create the retry_queue table.");
+        #[rustfmt::skip]
+                connection.execute("
+                    CREATE TABLE IF NOT EXISTS retry_queue (
+                        id TEXT NOT NULL,
+                        provider_bit INT NOT NULL,
+                        attempts INT DEFAULT 0,
+                        last_error TEXT,
+                        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                        next_attempt_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                        PRIMARY KEY (id, provider_bit)
+                    )
+                ", [])?;
 
-    /// # Errors
-    /// Will return `Err` if `Connection::call(...)` errors
-    pub async fn check_all_ids<I: IntoIterator<Item = String>>(
-        &self,
-        ids: I,
-    ) -> Result<HashMap<String, u32>> {
-        let ids: Vec<_> = ids.into_iter().collect();
-        self.connection
-            .call(|c| -> Result<_, rusqlite::Error> {
-                let mut output = HashMap::new();
+        debug!("Trying to create the outbox table.");
+        #[rustfmt::skip]
+                connection.execute("
+                    CREATE TABLE IF NOT EXISTS outbox (
+                        id TEXT NOT NULL,
+                        provider_bit INT NOT NULL,
+                        created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                        PRIMARY KEY (id, provider_bit)
+                    )
+                ", [])?;
 
-                for chunk in &ids.into_iter().chunks(Self::CHUNK_SIZE) {
-                    let chunk: Vec<String> = chunk.collect();
-                    for id in &chunk {
-                        output.insert(id.clone(), 0);
-                    }
-                    let found_ids = Self::check_batch_ids(c, chunk.into_iter())?;
-                    output.extend(found_ids);
-                }
+        debug!("Trying to create the avatar_metadata table.");
+        #[rustfmt::skip]
+                connection.execute("
+                    CREATE TABLE IF NOT EXISTS avatar_metadata (
+                        id TEXT PRIMARY KEY,
+                        name TEXT,
+                        author TEXT,
+                        author_id TEXT,
+                        platform TEXT,
+                        thumbnail_url TEXT,
+                        updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                    )
+                ", [])?;
 
-                Ok(output)
-            })
-            .await
-            .map_err(|e| anyhow::anyhow!(e))
-    }
+        if !Self::columns(connection, "main", "avatar_metadata")?.contains(&"author_id".to_string())
+        {
+            debug!("Trying to create the author_id column.");
+            #[rustfmt::skip]
+                connection.execute("
+                    ALTER TABLE avatar_metadata
+                    ADD COLUMN author_id TEXT
+                ", [])?;
+        }
 
-    fn check_batch_ids<I: Iterator<Item = String>>(
-        conn: &RusqliteConnection,
-        chunk: I,
-    ) -> std::result::Result<HashMap<String, u32>, rusqlite::Error> {
-        let chunk: Vec<_> = chunk.collect();
-        assert!(chunk.len() <= Self::CHUNK_SIZE);
+        debug!("Trying to create the avatar_worlds table.");
+        #[rustfmt::skip]
+                connection.execute("
+                    CREATE TABLE IF NOT EXISTS avatar_worlds (
+                        avatar_id TEXT NOT NULL,
+                        world TEXT NOT NULL,
+                        first_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                        PRIMARY KEY (avatar_id, world)
+                    )
+                ", [])?;
 
-        let placeholders = std::iter::repeat_n("?", chunk.len())
-            .collect::<Vec<_>>()
-            .join(",");
-        let sql = format!(
-            "SELECT id, provider_bits FROM avatars WHERE id IN ({placeholders}) AND updated_at >= datetime('now', '-30 days')"
-        );
+        debug!("Trying to create the own_ids table.");
+        #[rustfmt::skip]
+                connection.execute("
+                    CREATE TABLE IF NOT EXISTS own_ids (
+                        id TEXT PRIMARY KEY,
+                        created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                    )
+                ", [])?;
 
-        let mut stmt = conn.prepare(&sql)?;
-        stmt.query_map(params_from_iter(chunk.iter()), |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
-        })?
-        .collect::<Result<HashMap<_, _>, _>>()
-    }
-}
+        Self::setup_search(connection)?;
 
-#[cfg(test)]
-mod tests {
-    use super::Cache;
-    /// Helper to create a cache for tests
-    #[allow(dead_code)]
-    async fn cache() -> Cache {
-        Cache::new_in_memory().await.unwrap()
+        Ok(())
     }
 
-    #[tokio::test]
-    async fn creates_database_and_table() {
-        let cache = cache().await;
+    /// Full-text index over `avatars`, `avatar_metadata` and `avatar_worlds`, kept in sync by triggers
+    ///
+    /// Each avatar is indexed under the key `avatar_search_ids` gives it, `VACUUM` renumbers the
+    /// implicit rowids of `avatars`.
+    fn setup_search(connection: &RusqliteConnection) -> Result<(), rusqlite::Error> {
+        // Indexes the avatars the `avatar_search_ids` rows point to
+        let indexed = "
+            SELECT avatar_search_ids.key, avatars.id, avatar_metadata.name, avatar_metadata.author,
+                   (SELECT group_concat(world, ', ') FROM avatar_worlds
+                    WHERE avatar_worlds.avatar_id = avatars.id)
+            FROM avatars
+            JOIN avatar_search_ids ON avatar_search_ids.id = avatars.id
+            LEFT JOIN avatar_metadata ON avatar_metadata.id = avatars.id";
+        // Not `OR IGNORE`, the statement firing the trigger would override it
+        let reindex = |id: &str| {
+            format!(
+                "INSERT INTO avatar_search_ids (id)
+                 SELECT {id} WHERE NOT EXISTS (SELECT 1 FROM avatar_search_ids WHERE id = {id});
+                 DELETE FROM avatar_search
+                 WHERE rowid = (SELECT key FROM avatar_search_ids WHERE id = {id});
+                 INSERT INTO avatar_search (rowid, id, name, author, worlds)
+                 {indexed} WHERE avatars.id = {id};"
+            )
+        };
 
-        // Simple sanity check: storing should not fail
-        cache
-            .store_avatar_ids_with_providers(vec![("avatar_1", 1u32)].into_iter())
-            .await
-            .unwrap();
-    }
+        let is_indexed = connection
+            .prepare("SELECT 1 FROM sqlite_master WHERE name = 'avatar_search_ids'")?
+            .exists([])?;
+        if !is_indexed {
+            // Older versions rebuilt the index on every search, without keys
+            debug!("Trying to create the avatar_search index.");
+            connection.execute_batch(&format!(
+                "BEGIN;
+                 DROP TABLE IF EXISTS avatar_search;
+                 CREATE VIRTUAL TABLE avatar_search USING fts5(id, name, author, worlds);
+                 CREATE TABLE avatar_search_ids (
+                     key INTEGER PRIMARY KEY,
+                     id TEXT NOT NULL UNIQUE
+                 );
+                 INSERT INTO avatar_search_ids (id) SELECT id FROM avatars;
+                 INSERT INTO avatar_search (rowid, id, name, author, worlds) {indexed};
+                 COMMIT;"
+            ))?;
+        }
 
-    #[tokio::test]
-    async fn inserts_and_reads_avatar_ids() {
-        let cache = cache().await;
+        debug!("Trying to create the avatar_search triggers.");
+        connection.execute_batch(&format!(
+            "CREATE TRIGGER IF NOT EXISTS avatar_search_avatar_insert
+             AFTER INSERT ON avatars
+             BEGIN {avatar} END;
 
-        cache
-            .store_avatar_ids_with_providers(
-                vec![("avatar_a", 1u32), ("avatar_b", 2u32)].into_iter(),
-            )
-            .await
-            .unwrap();
+             CREATE TRIGGER IF NOT EXISTS avatar_search_avatar_delete
+             AFTER DELETE ON avatars
+             BEGIN
+                 DELETE FROM avatar_search
+                 WHERE rowid = (SELECT key FROM avatar_search_ids WHERE id = old.id);
+                 DELETE FROM avatar_search_ids WHERE id = old.id;
+             END;
 
-        let result = cache
-            .check_all_ids(vec!["avatar_a".into(), "avatar_b".into()])
-            .await
-            .unwrap();
+             CREATE TRIGGER IF NOT EXISTS avatar_search_metadata_insert
+             AFTER INSERT ON avatar_metadata
+             WHEN EXISTS (SELECT 1 FROM avatars WHERE id = new.id)
+             BEGIN {avatar} END;
 
-        assert_eq!(result.len(), 2);
-        assert_eq!(result["avatar_a"], 1);
-        assert_eq!(result["avatar_b"], 2);
+             CREATE TRIGGER IF NOT EXISTS avatar_search_metadata_update
+             AFTER UPDATE OF name, author ON avatar_metadata
+             WHEN EXISTS (SELECT 1 FROM avatars WHERE id = new.id)
+             BEGIN {avatar} END;
+
+             CREATE TRIGGER IF NOT EXISTS avatar_search_world_insert
+             AFTER INSERT ON avatar_worlds
+             WHEN EXISTS (SELECT 1 FROM avatars WHERE id = new.avatar_id)
+             BEGIN {world} END;",
+            avatar = reindex("new.id"),
+            world = reindex("new.avatar_id"),
+        ))
     }
 
-    #[tokio::test]
-    async fn returns_none_for_missing_ids() {
-        let cache = cache().await;
+    fn columns(
+        connection: &RusqliteConnection,
+        schema: &str,
+        table: &str,
+    ) -> Result<Vec<String>, rusqlite::Error> {
+        connection
+            .prepare(&format!("PRAGMA {schema}.table_info({table})"))?
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect()
+    }
 
-        let result = cache
-            .check_all_ids(vec!["missing_avatar".into()])
-            .await
-            .unwrap();
+    /// Saves what a provider knew about an avatar, keeping fields it left out
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn store_metadata(&self, avatar_id: &str, metadata: AvatarMetadata) -> Result<()> {
+        let avatar_id = avatar_id.to_string();
+        self.connection
+            .call(move |c| {
+                c.execute(
+                    "INSERT INTO avatar_metadata
+                        (id, name, author, author_id, platform, thumbnail_url)
+                     VALUES (:id, :name, :author, :author_id, :platform, :thumbnail_url)
+                     ON CONFLICT(id) DO UPDATE SET
+                        name = COALESCE(excluded.name, name),
+                        author = COALESCE(excluded.author, author),
+                        author_id = COALESCE(excluded.author_id, author_id),
+                        platform = COALESCE(excluded.platform, platform),
+                        thumbnail_url = COALESCE(excluded.thumbnail_url, thumbnail_url),
+                        updated_at = CURRENT_TIMESTAMP",
+                    named_params! {
+                        ":id": avatar_id,
+                        ":name": metadata.name,
+                        ":author": metadata.author,
+                        ":author_id": metadata.author_id,
+                        ":platform": metadata.platform,
+                        ":thumbnail_url": metadata.thumbnail_url,
+                    },
+                )
+            })
+            .await?;
 
-        assert_eq!(result["missing_avatar"], 0);
+        Ok(())
     }
 
-    #[tokio::test]
-    async fn updates_provider_bits_on_conflict() {
-        let cache = cache().await;
-
-        cache
-            .store_avatar_ids_with_providers(vec![("avatar_x", 1u32)].into_iter())
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn metadata(&self, avatar_id: &str) -> Result<Option<AvatarMetadata>> {
+        let avatar_id = avatar_id.to_string();
+        self.connection
+            .call(move |c| {
+                c.query_row(
+                    "SELECT name, author, author_id, platform, thumbnail_url
+                     FROM avatar_metadata
+                     WHERE id = ?1",
+                    [avatar_id],
+                    |row| {
+                        Ok(AvatarMetadata {
+                            name:          row.get(0)?,
+                            author:        row.get(1)?,
+                            author_id:     row.get(2)?,
+                            platform:      row.get(3)?,
+                            thumbnail_url: row.get(4)?,
+                        })
+                    },
+                )
+                .optional()
+            })
             .await
-            .unwrap();
-
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Counts a sighting of each avatar, remembering the source it was first seen in
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn record_sightings<S: ToString, I: IntoIterator<Item = S>>(
+        &self,
+        source: Source,
+        avatar_ids: I,
+    ) -> Result<()> {
+        let query = "
+            INSERT INTO avatars (id, created_at, updated_at, first_source, seen_count, last_seen_at)
+            VALUES (:id, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP, :source, 1, CURRENT_TIMESTAMP)
+            ON CONFLICT (id) DO UPDATE
+                SET first_source = COALESCE(first_source, :source),
+                    seen_count = COALESCE(seen_count, 0) + 1,
+                    last_seen_at = CURRENT_TIMESTAMP
+        ";
+
+        let avatar_ids: Vec<_> = avatar_ids.into_iter().map(|id| id.to_string()).collect();
+        if avatar_ids.is_empty() {
+            return Ok(());
+        }
+
+        self.connection
+            .call(move |c| -> Result<(), rusqlite::Error> {
+                let tx = c.transaction()?;
+                for id in avatar_ids {
+                    tx.execute(
+                        query,
+                        named_params! {
+                            ":id": id,
+                            ":source": source.as_ref(),
+                        },
+                    )?;
+                }
+                tx.commit()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Author user IDs of the given avatars, as far as providers returned them
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn author_ids(&self, avatar_ids: &[String]) -> Result<HashMap<String, String>> {
+        let avatar_ids = avatar_ids.to_vec();
+        self.connection
+            .call(move |c| -> Result<_, rusqlite::Error> {
+                let mut author_ids = HashMap::new();
+                for chunk in avatar_ids.chunks(Self::CHUNK_SIZE) {
+                    let placeholders = std::iter::repeat_n("?", chunk.len()).join(",");
+                    let mut statement = c.prepare(&format!(
+                        "SELECT id, author_id FROM avatar_metadata
+                         WHERE author_id IS NOT NULL AND id IN ({placeholders})"
+                    ))?;
+                    let rows = statement.query_map(params_from_iter(chunk), |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                    })?;
+                    for row in rows {
+                        let (avatar_id, author_id) = row?;
+                        author_ids.insert(avatar_id, author_id);
+                    }
+                }
+
+                Ok(author_ids)
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Remembers the `usr_` IDs of local users and the `avtr_` IDs they wore
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn store_own_ids(&self, ids: Vec<String>) -> Result<()> {
+        self.connection
+            .call(move |c| -> Result<(), rusqlite::Error> {
+                let tx = c.transaction()?;
+                for id in ids {
+                    tx.execute("INSERT OR IGNORE INTO own_ids (id) VALUES (?1)", [id])?;
+                }
+                tx.commit()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn own_ids(&self) -> Result<Vec<String>> {
+        self.connection
+            .call(|c| {
+                c.prepare("SELECT id FROM own_ids")?
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Remembers the worlds avatars were seen in
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn record_worlds<I: IntoIterator<Item = (String, String)>>(
+        &self,
+        avatar_worlds: I,
+    ) -> Result<()> {
+        let avatar_worlds = avatar_worlds.into_iter().collect::<Vec<_>>();
+        if avatar_worlds.is_empty() {
+            return Ok(());
+        }
+
+        self.connection
+            .call(move |c| -> Result<(), rusqlite::Error> {
+                let tx = c.transaction()?;
+                for (avatar_id, world) in avatar_worlds {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO avatar_worlds (avatar_id, world) VALUES (?1, ?2)",
+                        params![avatar_id, world],
+                    )?;
+                }
+                tx.commit()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Full-text search over avatar IDs, names, authors and worlds, best matches first
+    ///
+    /// Every word is matched as a prefix, so `avtr_c38a` or `kitsu` find partial matches.
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn search(&self, text: &str, limit: u32) -> Result<Vec<SearchResult>> {
+        // Quote every word, FTS5 would otherwise read `-` or `:` in IDs and names as operators
+        let query = text
+            .split_whitespace()
+            .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+            .join(" ");
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.connection
+            .call(move |c| {
+                c.prepare(
+                    "SELECT id, name, author, worlds
+                     FROM avatar_search
+                     WHERE avatar_search MATCH ?1
+                     ORDER BY rank
+                     LIMIT ?2",
+                )?
+                .query_map(params![query, limit], |row| {
+                    Ok(SearchResult {
+                        avatar_id: row.get(0)?,
+                        name:      row.get(1)?,
+                        author:    row.get(2)?,
+                        worlds:    row.get(3)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Number of avatars and sightings per first source, unknown sources come from older versions
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn source_stats(&self) -> Result<Vec<SourceStats>> {
+        self.connection
+            .call(|c| {
+                let mut statement = c.prepare(
+                    "SELECT first_source, COUNT(*), COALESCE(SUM(seen_count), 0)
+                     FROM avatars
+                     GROUP BY first_source
+                     ORDER BY COUNT(*) DESC",
+                )?;
+                statement
+                    .query_map([], |row| {
+                        Ok(SourceStats {
+                            source:    row
+                                .get::<_, Option<String>>(0)?
+                                .and_then(|source| source.parse().ok()),
+                            avatars:   row.get(1)?,
+                            sightings: row.get(2)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    #[must_use]
+    pub fn outbox(&self, kind: ProviderKind) -> Outbox {
+        Outbox {
+            connection:   self.connection.clone(),
+            provider_bit: kind.bit(),
+        }
+    }
+
+    /// # Errors
+    /// Will return `Err` if `Connection::close` errors
+    pub async fn close(self) -> Result<()> {
+        self.connection.close().await.map_err(anyhow::Error::from)
+    }
+
+    /// Deletes avatars that haven't been seen within the given duration, with everything kept about them
+    ///
+    /// Avatars from before sightings were counted fall back to when they were last updated.
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn prune(&self, older_than: Duration) -> Result<usize> {
+        let modifier = format!("-{} seconds", older_than.as_secs());
+        self.connection
+            .call(move |c| -> Result<usize, rusqlite::Error> {
+                let tx = c.transaction()?;
+                let is_stale = "COALESCE(last_seen_at, updated_at) < datetime('now', ?1)";
+                let stale = format!("SELECT id FROM avatars WHERE {is_stale}");
+                for (table, column) in [
+                    ("avatar_metadata", "id"),
+                    ("avatar_worlds", "avatar_id"),
+                    ("retry_queue", "id"),
+                ] {
+                    tx.execute(
+                        &format!("DELETE FROM {table} WHERE {column} IN ({stale})"),
+                        [&modifier],
+                    )?;
+                }
+
+                let pruned = tx.execute(
+                    &format!("DELETE FROM avatars WHERE {is_stale}"),
+                    [&modifier],
+                )?;
+                tx.commit()?;
+                Ok(pruned)
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn vacuum(&self) -> Result<()> {
+        self.connection
+            .call(|c| c.execute_batch("VACUUM"))
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Runs `PRAGMA integrity_check`, a healthy database only reports `ok`
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn integrity_check(&self) -> Result<Vec<String>> {
+        self.connection
+            .call(|c| {
+                let mut statement = c.prepare("PRAGMA integrity_check")?;
+                statement
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// `Ok(false)` when the database is corrupted, `Err` when it couldn't be checked
+    async fn quick_check(&self) -> Result<bool> {
+        let result = self
+            .connection
+            .call(|c| c.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0)))
+            .await
+            .map_err(anyhow::Error::from);
+
+        match result {
+            Ok(result) => Ok(result == "ok"),
+            Err(error) if Self::is_corrupt(&error) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Writes a consistent copy of the cache and keeps only the newest `keep` copies
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` or removing old backups errors
+    pub async fn backup(&self, directory: &Path, keep: usize) -> Result<PathBuf> {
+        std::fs::create_dir_all(directory)?;
+
+        let timestamp = Local::now().format("%Y%m%d-%H%M%S%3f").to_string();
+        let path = Self::backup_path(directory, &timestamp)?;
+        let target = path.to_string_lossy().to_string();
+        self.connection
+            .call(move |c| c.execute("VACUUM INTO ?1", [target]))
+            .await?;
+
+        let backups = Self::list_backups(directory)?;
+        for old in backups.iter().take(backups.len().saturating_sub(keep)) {
+            debug!("Removing old backup {old:?}");
+            std::fs::remove_file(old)?;
+        }
+
+        Ok(path)
+    }
+
+    /// Backups within the same millisecond are numbered, `VACUUM INTO` won't overwrite a file
+    fn backup_path(directory: &Path, timestamp: &str) -> Result<PathBuf> {
+        let Some(path) = (0..=u16::MAX)
+            .map(|number| directory.join(format!("avatars-{timestamp}-{number:03}.sqlite")))
+            .find(|path| !path.exists())
+        else {
+            bail!(
+                "Every backup name for {timestamp} is taken in {}",
+                directory.display()
+            );
+        };
+
+        Ok(path)
+    }
+
+    /// Backs up the cache unless the newest backup is less than a day old
+    ///
+    /// # Errors
+    /// Will return `Err` if `Cache::backup` errors
+    pub async fn backup_daily(&self, directory: &Path, keep: usize) -> Result<Option<PathBuf>> {
+        let is_fresh = Self::list_backups(directory)?
+            .last()
+            .and_then(|newest| newest.metadata().ok()?.modified().ok()?.elapsed().ok())
+            .is_some_and(|age| age < Duration::from_hours(24));
+
+        if is_fresh {
+            return Ok(None);
+        }
+
+        self.backup(directory, keep).await.map(Some)
+    }
+
+    /// Unions another cache file into this one in a single transaction, the other file is only read
+    ///
+    /// Provider bits are OR'd together, the earliest `created_at` and latest `updated_at` win.
+    ///
+    /// # Errors
+    /// Will return `Err` if the file doesn't exist, isn't a cache, or `Connection::call(...)` errors
+    pub async fn merge(&self, other: &Path) -> Result<MergeReport> {
+        if !other.is_file() {
+            bail!("{} doesn't exist", other.display());
+        }
+
+        let other = other.to_string_lossy().to_string();
+        self.connection
+            .call(move |c| {
+                c.execute("ATTACH DATABASE ?1 AS other", [other])?;
+                let report = Self::merge_attached(c);
+                c.execute_batch("DETACH DATABASE other")?;
+                report
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    fn merge_attached(connection: &mut RusqliteConnection) -> Result<MergeReport, rusqlite::Error> {
+        let tables = connection
+            .prepare("SELECT name FROM other.sqlite_master WHERE type = 'table'")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let avatar_columns = Self::columns(connection, "other", "avatars")?;
+        let metadata_columns = Self::columns(connection, "other", "avatar_metadata")?;
+
+        // Caches from older versions lack the columns added since
+        let column = |name: &str| {
+            if avatar_columns.iter().any(|column| column == name) {
+                format!("other.avatars.{name}")
+            } else {
+                String::from("NULL")
+            }
+        };
+
+        // Immediate, so a running watcher waits instead of failing halfway through
+        let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let count = |query| {
+            tx.query_row(query, [], |row| row.get::<_, i64>(0))
+                .map(|count| usize::try_from(count).unwrap_or_default())
+        };
+        let total = count("SELECT COUNT(*) FROM other.avatars")?;
+        let added = count(
+            "SELECT COUNT(*) FROM other.avatars
+             WHERE id NOT IN (SELECT id FROM main.avatars)",
+        )?;
+
+        let changed = tx.execute(
+            &format!(
+                "INSERT INTO main.avatars
+                    (id, created_at, updated_at, provider_bits, first_source, seen_count, last_seen_at)
+                 SELECT id,
+                        COALESCE({created_at}, CURRENT_TIMESTAMP),
+                        COALESCE({updated_at}, CURRENT_TIMESTAMP),
+                        COALESCE({provider_bits}, 0),
+                        {first_source},
+                        COALESCE({seen_count}, 0),
+                        {last_seen_at}
+                 FROM other.avatars
+                 WHERE true
+                 ON CONFLICT (id) DO UPDATE SET
+                    created_at = MIN(COALESCE(created_at, excluded.created_at), excluded.created_at),
+                    updated_at = MAX(COALESCE(updated_at, excluded.updated_at), excluded.updated_at),
+                    provider_bits = {MERGED_BITS},
+                    first_source = COALESCE(first_source, excluded.first_source),
+                    seen_count = MAX(COALESCE(seen_count, 0), excluded.seen_count),
+                    last_seen_at = COALESCE(
+                        MAX(last_seen_at, excluded.last_seen_at),
+                        last_seen_at,
+                        excluded.last_seen_at
+                    )
+                 WHERE excluded.created_at < created_at
+                    OR excluded.updated_at > updated_at
+                    OR {MERGED_BITS} != COALESCE(provider_bits, 0)
+                    OR (first_source IS NULL AND excluded.first_source IS NOT NULL)
+                    OR excluded.seen_count > COALESCE(seen_count, 0)
+                    OR excluded.last_seen_at > COALESCE(last_seen_at, '')",
+                created_at = column("created_at"),
+                updated_at = column("updated_at"),
+                provider_bits = column("provider_bits"),
+                first_source = column("first_source"),
+                seen_count = column("seen_count"),
+                last_seen_at = column("last_seen_at"),
+            ),
+            [],
+        )?;
+
+        if tables.iter().any(|table| table == "avatar_worlds") {
+            tx.execute(
+                "INSERT OR IGNORE INTO main.avatar_worlds (avatar_id, world, first_seen_at)
+                 SELECT avatar_id, world, first_seen_at FROM other.avatar_worlds",
+                [],
+            )?;
+        }
+        if tables.iter().any(|table| table == "avatar_metadata") {
+            let author_id = if metadata_columns.iter().any(|column| column == "author_id") {
+                "author_id"
+            } else {
+                "NULL"
+            };
+            tx.execute(
+                &format!(
+                    "INSERT INTO main.avatar_metadata
+                        (id, name, author, author_id, platform, thumbnail_url, updated_at)
+                     SELECT id, name, author, {author_id}, platform, thumbnail_url, updated_at
+                     FROM other.avatar_metadata
+                     WHERE true
+                     ON CONFLICT (id) DO UPDATE SET
+                        name = COALESCE(name, excluded.name),
+                        author = COALESCE(author, excluded.author),
+                        author_id = COALESCE(author_id, excluded.author_id),
+                        platform = COALESCE(platform, excluded.platform),
+                        thumbnail_url = COALESCE(thumbnail_url, excluded.thumbnail_url)"
+                ),
+                [],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(MergeReport {
+            added,
+            updated: changed - added,
+            unchanged: total - changed,
+        })
+    }
+
+    /// Lists backups in the directory, oldest first
+    ///
+    /// # Errors
+    /// Will return `Err` if the directory exists but can't be read
+    pub fn list_backups(directory: &Path) -> Result<Vec<PathBuf>> {
+        if !directory.exists() {
+            return Ok(Vec::new());
+        }
+
+        let backups = std::fs::read_dir(directory)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("avatars-") && name.ends_with(".sqlite"))
+            })
+            .sorted()
+            .collect();
+
+        Ok(backups)
+    }
+
+    /// Adds a provider bit to the avatars, resetting bits older than 30 days like a fresh store
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn add_provider_bit<S: ToString, I: IntoIterator<Item = S>>(
+        &self,
+        avatar_ids: I,
+        provider_bit: u32,
+    ) -> Result<()> {
+        let avatar_ids: Vec<_> = avatar_ids.into_iter().map(|id| id.to_string()).collect();
+        self.connection
+            .call(move |c| -> Result<(), rusqlite::Error> {
+                let tx = c.transaction()?;
+                for id in avatar_ids {
+                    Self::add_provider_bit_in(&tx, &id, provider_bit)?;
+                }
+                tx.commit()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    fn add_provider_bit_in(
+        connection: &RusqliteConnection,
+        avatar_id: &str,
+        provider_bit: u32,
+    ) -> Result<usize, rusqlite::Error> {
+        connection.execute(
+            "INSERT INTO avatars (id, provider_bits, created_at, updated_at)
+             VALUES (:id, :bit, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)
+             ON CONFLICT (id) DO UPDATE
+                SET provider_bits = CASE
+                        WHEN updated_at >= datetime('now', '-30 days') THEN provider_bits | :bit
+                        ELSE :bit
+                    END,
+                    updated_at = CURRENT_TIMESTAMP",
+            named_params! {
+                ":id": avatar_id,
+                ":bit": provider_bit,
+            },
+        )
+    }
+
+    /// Queues a failed submission, keeping the schedule if it's already queued
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn enqueue_retry(
+        &self,
+        avatar_id: &str,
+        provider_bit: u32,
+        error: &str,
+        delay: Duration,
+    ) -> Result<()> {
+        let (avatar_id, error) = (avatar_id.to_string(), error.to_string());
+        let modifier = format!("+{} seconds", delay.as_secs());
+        self.connection
+            .call(move |c| {
+                c.execute(
+                    "INSERT INTO retry_queue (id, provider_bit, last_error, next_attempt_at)
+                     VALUES (:id, :bit, :error, datetime('now', :modifier))
+                     ON CONFLICT (id, provider_bit) DO UPDATE
+                        SET last_error = :error",
+                    named_params! {
+                        ":id": avatar_id,
+                        ":bit": provider_bit,
+                        ":error": error,
+                        ":modifier": modifier,
+                    },
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Queued submissions for these provider bits that are due, oldest schedule first
+    ///
+    /// Entries of other providers are left out, or they'd fill every batch while they're disabled.
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn due_retries(&self, provider_bits: u32, limit: usize) -> Result<Vec<RetryEntry>> {
+        self.retries(
+            "WHERE next_attempt_at <= CURRENT_TIMESTAMP AND provider_bit & ?2 != 0
+             ORDER BY next_attempt_at LIMIT ?1",
+            limit,
+            provider_bits,
+        )
+        .await
+    }
+
+    /// Every queued submission, next scheduled first
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn retry_queue(&self) -> Result<Vec<RetryEntry>> {
+        self.retries(
+            "WHERE provider_bit & ?2 != 0 ORDER BY next_attempt_at LIMIT ?1",
+            usize::MAX,
+            u32::MAX,
+        )
+        .await
+    }
+
+    async fn retries(
+        &self,
+        clause: &'static str,
+        limit: usize,
+        provider_bits: u32,
+    ) -> Result<Vec<RetryEntry>> {
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        self.connection
+            .call(move |c| {
+                let mut statement = c.prepare(&format!(
+                    "SELECT id, provider_bit, attempts, last_error, created_at, next_attempt_at
+                     FROM retry_queue {clause}"
+                ))?;
+                statement
+                    .query_map(params![limit, provider_bits], |row| {
+                        Ok(RetryEntry {
+                            avatar_id:       row.get(0)?,
+                            provider_bit:    row.get(1)?,
+                            attempts:        row.get(2)?,
+                            last_error:      row.get(3)?,
+                            created_at:      row.get(4)?,
+                            next_attempt_at: row.get(5)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Counts a failed attempt and pushes the next one back
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn reschedule_retry(
+        &self,
+        avatar_id: &str,
+        provider_bit: u32,
+        error: &str,
+        delay: Duration,
+    ) -> Result<()> {
+        let (avatar_id, error) = (avatar_id.to_string(), error.to_string());
+        let modifier = format!("+{} seconds", delay.as_secs());
+        self.connection
+            .call(move |c| {
+                c.execute(
+                    "UPDATE retry_queue
+                     SET attempts = attempts + 1,
+                         last_error = :error,
+                         next_attempt_at = datetime('now', :modifier)
+                     WHERE id = :id AND provider_bit = :bit",
+                    named_params! {
+                        ":id": avatar_id,
+                        ":bit": provider_bit,
+                        ":error": error,
+                        ":modifier": modifier,
+                    },
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Pushes the next attempt back without counting one, while the provider is skipped
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn postpone_retry(
+        &self,
+        avatar_id: &str,
+        provider_bit: u32,
+        delay: Duration,
+    ) -> Result<()> {
+        let avatar_id = avatar_id.to_string();
+        let modifier = format!("+{} seconds", delay.as_secs());
+        self.connection
+            .call(move |c| {
+                c.execute(
+                    "UPDATE retry_queue
+                     SET next_attempt_at = datetime('now', :modifier)
+                     WHERE id = :id AND provider_bit = :bit",
+                    named_params! {
+                        ":id": avatar_id,
+                        ":bit": provider_bit,
+                        ":modifier": modifier,
+                    },
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes a queued submission, marking the provider bit if it was delivered
+    ///
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn remove_retry(
+        &self,
+        avatar_id: &str,
+        provider_bit: u32,
+        delivered: bool,
+    ) -> Result<()> {
+        let avatar_id = avatar_id.to_string();
+        self.connection
+            .call(move |c| -> Result<(), rusqlite::Error> {
+                let tx = c.transaction()?;
+                tx.execute(
+                    "DELETE FROM retry_queue WHERE id = ?1 AND provider_bit = ?2",
+                    params![avatar_id, provider_bit],
+                )?;
+                if delivered {
+                    Self::add_provider_bit_in(&tx, &avatar_id, provider_bit)?;
+                }
+                tx.commit()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    const CHUNK_SIZE: usize = 950; // sqlite parameter limit is 999
+
+    /// # Errors
+    /// Will return `Err` if `Connection::call(...)` errors
+    pub async fn check_all_ids<I: IntoIterator<Item = String>>(
+        &self,
+        ids: I,
+    ) -> Result<HashMap<String, u32>> {
+        let ids: Vec<_> = ids.into_iter().collect();
+        self.connection
+            .call(|c| -> Result<_, rusqlite::Error> {
+                let mut output = HashMap::new();
+
+                for chunk in &ids.into_iter().chunks(Self::CHUNK_SIZE) {
+                    let chunk: Vec<String> = chunk.collect();
+                    for id in &chunk {
+                        output.insert(id.clone(), 0);
+                    }
+                    let found_ids = Self::check_batch_ids(c, chunk.into_iter())?;
+                    output.extend(found_ids);
+                }
+
+                Ok(output)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!(e))
+    }
+
+    fn check_batch_ids<I: Iterator<Item = String>>(
+        conn: &RusqliteConnection,
+        chunk: I,
+    ) -> std::result::Result<HashMap<String, u32>, rusqlite::Error> {
+        let chunk: Vec<_> = chunk.collect();
+        assert!(chunk.len() <= Self::CHUNK_SIZE);
+
+        let placeholders = std::iter::repeat_n("?", chunk.len())
+            .collect::<Vec<_>>()
+            .join(",");
+        let sql = format!(
+            "SELECT id, provider_bits FROM avatars WHERE id IN ({placeholders}) AND updated_at >= datetime('now', '-30 days')"
+        );
+
+        let mut stmt = conn.prepare(&sql)?;
+        stmt.query_map(params_from_iter(chunk.iter()), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+        })?
+        .collect::<Result<HashMap<_, _>, _>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use itertools::Itertools;
+
+    use super::{
+        BACKUP_DIRECTORY_NAME,
+        Cache,
+        CacheLock,
+        DATABASE_NAME,
+        MergeReport,
+        SearchResult,
+        SourceStats,
+    };
+    use crate::{
+        Source,
+        provider::{AvatarMetadata, ProviderKind},
+    };
+    /// Helper to create a cache for tests
+    #[allow(dead_code)]
+    async fn cache() -> Cache {
+        Cache::new_in_memory().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn creates_database_and_table() {
+        let cache = cache().await;
+
+        // Simple sanity check: storing should not fail
+        cache
+            .store_avatar_ids_with_providers(vec![("avatar_1", 1u32)].into_iter())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn inserts_and_reads_avatar_ids() {
+        let cache = cache().await;
+
+        cache
+            .store_avatar_ids_with_providers(
+                vec![("avatar_a", 1u32), ("avatar_b", 2u32)].into_iter(),
+            )
+            .await
+            .unwrap();
+
+        let result = cache
+            .check_all_ids(vec!["avatar_a".into(), "avatar_b".into()])
+            .await
+            .unwrap();
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(result["avatar_a"], 1);
+        assert_eq!(result["avatar_b"], 2);
+    }
+
+    #[tokio::test]
+    async fn returns_none_for_missing_ids() {
+        let cache = cache().await;
+
+        let result = cache
+            .check_all_ids(vec!["missing_avatar".into()])
+            .await
+            .unwrap();
+
+        assert_eq!(result["missing_avatar"], 0);
+    }
+
+    #[tokio::test]
+    async fn updates_provider_bits_on_conflict() {
+        let cache = cache().await;
+
+        cache
+            .store_avatar_ids_with_providers(vec![("avatar_x", 1u32)].into_iter())
+            .await
+            .unwrap();
+
         cache
             .store_avatar_ids_with_providers(vec![("avatar_x", 42u32)].into_iter())
             .await
             .unwrap();
 
-        let result = cache.check_all_ids(vec!["avatar_x".into()]).await.unwrap();
+        let result = cache.check_all_ids(vec!["avatar_x".into()]).await.unwrap();
+
+        assert_eq!(result["avatar_x"], 42);
+    }
+
+    #[tokio::test]
+    async fn respects_chunking_limits() {
+        let cache = cache().await;
+
+        #[allow(clippy::cast_possible_truncation)]
+        let ids: Vec<(String, u32)> = (0..(Cache::CHUNK_SIZE + 10))
+            .map(|i| (format!("avatar_{i}"), i as u32))
+            .collect();
+
+        cache
+            .store_avatar_ids_with_providers(ids.iter().map(|(id, p)| (id.as_str(), *p)))
+            .await
+            .unwrap();
+
+        let result = cache
+            .check_all_ids(ids.iter().map(|(id, _)| id.clone()))
+            .await
+            .unwrap();
+
+        assert_eq!(result.len(), ids.len());
+
+        for (id, provider) in ids {
+            assert_eq!(result[&id], provider);
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_entries_older_than_30_days() {
+        let cache = cache().await;
+
+        // Insert normally
+        cache
+            .store_avatar_ids_with_providers(vec![("old_avatar", 1u32)].into_iter())
+            .await
+            .unwrap();
+
+        // Manually age the entry
+        cache
+            .connection
+            .call(|c| {
+                c.execute(
+                    "UPDATE avatars
+                     SET updated_at = datetime('now', '-31 days')
+                     WHERE id = 'old_avatar'",
+                    [],
+                )
+            })
+            .await
+            .unwrap();
+
+        let result = cache
+            .check_all_ids(vec!["old_avatar".into()])
+            .await
+            .unwrap();
+
+        // Exists, but filtered out by age
+        assert_eq!(result["old_avatar"], 0);
+    }
+
+    #[tokio::test]
+    async fn prunes_entries_older_than_duration() {
+        let cache = cache().await;
+
+        cache
+            .store_avatar_ids_with_providers(vec![("old_avatar", 1u32), ("new_avatar", 1u32)])
+            .await
+            .unwrap();
+
+        for id in ["old_avatar", "new_avatar"] {
+            let metadata = AvatarMetadata {
+                name: Some(format!("{id} name")),
+                ..Default::default()
+            };
+            cache.store_metadata(id, metadata).await.unwrap();
+            cache
+                .enqueue_retry(id, 2, "timeout", Duration::ZERO)
+                .await
+                .unwrap();
+        }
+        cache
+            .record_worlds([("old_avatar".into(), "Old World".into())])
+            .await
+            .unwrap();
+
+        cache
+            .connection
+            .call(|c| {
+                c.execute(
+                    "UPDATE avatars
+                     SET updated_at = datetime('now', '-91 days')
+                     WHERE id = 'old_avatar'",
+                    [],
+                )
+            })
+            .await
+            .unwrap();
+
+        let pruned = cache.prune(Duration::from_hours(90 * 24)).await.unwrap();
+        assert_eq!(pruned, 1);
 
-        assert_eq!(result["avatar_x"], 42);
+        // Nothing is left of the pruned avatar, not even in search
+        assert_eq!(cache.metadata("old_avatar").await.unwrap(), None);
+        assert!(cache.search("old", 10).await.unwrap().is_empty());
+        assert_eq!(cache.retry_queue().await.unwrap().len(), 1);
+
+        cache.vacuum().await.unwrap();
+        assert_eq!(cache.integrity_check().await.unwrap(), ["ok"]);
     }
 
     #[tokio::test]
-    async fn respects_chunking_limits() {
+    async fn prune_keeps_old_avatars_seen_recently() {
         let cache = cache().await;
 
-        #[allow(clippy::cast_possible_truncation)]
-        let ids: Vec<(String, u32)> = (0..(Cache::CHUNK_SIZE + 10))
-            .map(|i| (format!("avatar_{i}"), i as u32))
-            .collect();
-
         cache
-            .store_avatar_ids_with_providers(ids.iter().map(|(id, p)| (id.as_str(), *p)))
+            .store_avatar_ids_with_providers(vec![("old_avatar", 1u32)])
             .await
             .unwrap();
-
-        let result = cache
-            .check_all_ids(ids.iter().map(|(id, _)| id.clone()))
+        cache
+            .connection
+            .call(|c| {
+                c.execute(
+                    "UPDATE avatars SET updated_at = datetime('now', '-91 days')",
+                    [],
+                )
+            })
+            .await
+            .unwrap();
+        cache
+            .record_sightings(Source::OutputLog, ["old_avatar"])
             .await
             .unwrap();
 
-        assert_eq!(result.len(), ids.len());
+        let pruned = cache.prune(Duration::from_hours(90 * 24)).await.unwrap();
+        assert_eq!(pruned, 0);
+    }
 
-        for (id, provider) in ids {
-            assert_eq!(result[&id], provider);
+    #[tokio::test]
+    async fn rotates_backups() {
+        let cache = cache().await;
+        let directory = tempfile::tempdir().unwrap();
+
+        cache
+            .store_avatar_ids_with_providers(vec![("avatar_a", 1u32)])
+            .await
+            .unwrap();
+
+        for _ in 0..4 {
+            cache.backup(directory.path(), 2).await.unwrap();
         }
+
+        let backups = Cache::list_backups(directory.path()).unwrap();
+        assert_eq!(backups.len(), 2);
+
+        // Backups within the same millisecond get their own file, sorted in the order written
+        let timestamp = "20240101-000000000";
+        let first = Cache::backup_path(directory.path(), timestamp).unwrap();
+        std::fs::write(&first, b"").unwrap();
+        let second = Cache::backup_path(directory.path(), timestamp).unwrap();
+        assert_ne!(first, second);
+        std::fs::write(&second, b"").unwrap();
+        let backups = Cache::list_backups(directory.path()).unwrap();
+        assert_eq!(backups[..2], [first, second]);
+
+        // The daily backup is skipped while the newest one is fresh
+        let backup = cache.backup_daily(directory.path(), 2).await.unwrap();
+        assert!(backup.is_none());
     }
 
     #[tokio::test]
-    async fn ignores_entries_older_than_30_days() {
-        let cache = cache().await;
+    async fn recovers_from_latest_backup() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(DATABASE_NAME);
+        let backups = directory.path().join(BACKUP_DIRECTORY_NAME);
 
-        // Insert normally
+        let cache = Cache::new_at_location(&path).await.unwrap();
         cache
-            .store_avatar_ids_with_providers(vec![("old_avatar", 1u32)].into_iter())
+            .store_avatar_ids_with_providers(vec![("avatar_a", 1u32)])
             .await
             .unwrap();
+        cache.backup(&backups, 1).await.unwrap();
+        cache.close().await.unwrap();
 
-        // Manually age the entry
+        std::fs::write(&path, b"definitely not a sqlite database").unwrap();
+
+        let cache = Cache::recover(&path, &backups).await.unwrap();
+        let result = cache.check_all_ids(vec!["avatar_a".into()]).await.unwrap();
+        assert_eq!(result["avatar_a"], 1);
+    }
+
+    #[tokio::test]
+    async fn recovers_only_from_corruption() {
+        let directory = tempfile::tempdir().unwrap();
+        let backups = directory.path().join(BACKUP_DIRECTORY_NAME);
+
+        // A directory can't be opened, but there's nothing corrupted to move aside
+        let path = directory.path().join("directory.sqlite");
+        std::fs::create_dir(&path).unwrap();
+        assert!(Cache::open_or_recover(&path, &backups).await.is_err());
+        assert!(path.is_dir());
+
+        let path = directory.path().join(DATABASE_NAME);
+        std::fs::write(&path, b"definitely not a sqlite database").unwrap();
+        let cache = Cache::open_or_recover(&path, &backups).await.unwrap();
+        assert!(cache.quick_check().await.unwrap());
+        cache.close().await.unwrap();
+
+        // The journal is moved aside with the corrupted cache
+        let journal = Cache::sidecar(&path, "-journal");
+        std::fs::write(&journal, b"stale journal").unwrap();
+        Cache::recover(&path, &backups).await.unwrap();
+        assert!(!journal.exists());
+
+        let moved = std::fs::read_dir(directory.path())
+            .unwrap()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.contains(".corrupt-"))
+            .sorted()
+            .collect::<Vec<_>>();
+        assert!(moved.iter().any(|name| name.ends_with("-journal")));
+    }
+
+    #[tokio::test]
+    async fn reads_without_writing() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(DATABASE_NAME);
+
+        // A missing cache isn't created
+        let cache = Cache::read_only_at_location(&path).await.unwrap();
+        assert!(cache.own_ids().await.unwrap().is_empty());
+        assert!(!path.exists());
+
+        let cache = Cache::new_at_location(&path).await.unwrap();
         cache
+            .store_avatar_ids_with_providers(vec![("avatar_a", 1u32)])
+            .await
+            .unwrap();
+        cache.close().await.unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        let cache = Cache::read_only_at_location(&path).await.unwrap();
+        let result = cache.check_all_ids(vec!["avatar_a".into()]).await.unwrap();
+        assert_eq!(result["avatar_a"], 1);
+        assert!(cache.store_own_ids(vec!["usr_a".into()]).await.is_err());
+        cache.close().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+
+    #[tokio::test]
+    async fn checks_integrity_without_migrating() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(DATABASE_NAME);
+        assert!(Cache::open_read_only(&path).await.is_err());
+
+        // Older versions only had the avatars table
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        connection
+            .execute("CREATE TABLE avatars (id TEXT PRIMARY KEY)", [])
+            .unwrap();
+        drop(connection);
+        let before = std::fs::read(&path).unwrap();
+
+        let cache = Cache::open_read_only(&path).await.unwrap();
+        assert_eq!(cache.integrity_check().await.unwrap(), ["ok"]);
+        cache.close().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+
+    #[tokio::test]
+    async fn merges_another_cache() {
+        let directory = tempfile::tempdir().unwrap();
+        let other_path = directory.path().join("other.sqlite");
+        // Stored a second apart, the avatar would count as updated
+        let same_timestamps = |cache: &Cache| {
+            let connection = cache.connection.clone();
+            async move {
+                connection
+                    .call(|c| {
+                        c.execute(
+                            "UPDATE avatars
+                             SET created_at = '2020-01-01 00:00:00',
+                                 updated_at = '2020-01-01 00:00:00'
+                             WHERE id = 'avatar_same'",
+                            [],
+                        )
+                    })
+                    .await
+                    .unwrap();
+            }
+        };
+
+        let other = Cache::new_at_location(&other_path).await.unwrap();
+        other
+            .store_avatar_ids_with_providers(vec![
+                ("avatar_shared", ProviderKind::PAW.bit()),
+                ("avatar_new", ProviderKind::AVTRDB.bit()),
+                ("avatar_same", ProviderKind::AVTRDB.bit()),
+            ])
+            .await
+            .unwrap();
+        other
             .connection
             .call(|c| {
                 c.execute(
-                    "UPDATE avatars
-                     SET updated_at = datetime('now', '-31 days')
-                     WHERE id = 'old_avatar'",
+                    "UPDATE avatars SET created_at = '2020-01-01 00:00:00'
+                     WHERE id = 'avatar_shared'",
                     [],
                 )
             })
             .await
             .unwrap();
+        same_timestamps(&other).await;
+        other.close().await.unwrap();
+
+        let cache = cache().await;
+        cache
+            .store_avatar_ids_with_providers(vec![
+                ("avatar_shared", ProviderKind::NSVR.bit()),
+                ("avatar_same", ProviderKind::AVTRDB.bit()),
+            ])
+            .await
+            .unwrap();
+        same_timestamps(&cache).await;
+
+        let report = cache.merge(&other_path).await.unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                added:     1,
+                updated:   1,
+                unchanged: 1,
+            }
+        );
 
         let result = cache
-            .check_all_ids(vec!["old_avatar".into()])
+            .check_all_ids(vec!["avatar_shared".into(), "avatar_new".into()])
             .await
             .unwrap();
+        assert_eq!(
+            result["avatar_shared"],
+            ProviderKind::PAW.bit() | ProviderKind::NSVR.bit()
+        );
+        assert_eq!(result["avatar_new"], ProviderKind::AVTRDB.bit());
 
-        // Exists, but filtered out by age
-        assert_eq!(result["old_avatar"], 0);
+        let created_at = cache
+            .connection
+            .call(|c| {
+                c.query_row(
+                    "SELECT created_at FROM avatars WHERE id = 'avatar_shared'",
+                    [],
+                    |row| row.get::<_, String>(0),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(created_at, "2020-01-01 00:00:00");
+
+        // Merging again changes nothing
+        let report = cache.merge(&other_path).await.unwrap();
+        assert_eq!(report.unchanged, 3);
+    }
+
+    #[tokio::test]
+    async fn merge_drops_bits_older_than_the_resubmit_window() {
+        let directory = tempfile::tempdir().unwrap();
+        let other_path = directory.path().join("other.sqlite");
+        let age = |cache: &Cache, id: &'static str| {
+            let connection = cache.connection.clone();
+            async move {
+                connection
+                    .call(move |c| {
+                        c.execute(
+                            "UPDATE avatars SET updated_at = datetime('now', '-31 days') WHERE id = ?1",
+                            [id],
+                        )
+                    })
+                    .await
+                    .unwrap();
+            }
+        };
+
+        let other = Cache::new_at_location(&other_path).await.unwrap();
+        other
+            .store_avatar_ids_with_providers(vec![
+                ("avatar_stale_there", ProviderKind::PAW.bit()),
+                ("avatar_stale_here", ProviderKind::PAW.bit()),
+            ])
+            .await
+            .unwrap();
+        age(&other, "avatar_stale_there").await;
+        other.close().await.unwrap();
+
+        let cache = cache().await;
+        cache
+            .store_avatar_ids_with_providers(vec![
+                ("avatar_stale_there", ProviderKind::NSVR.bit()),
+                ("avatar_stale_here", ProviderKind::NSVR.bit()),
+            ])
+            .await
+            .unwrap();
+        age(&cache, "avatar_stale_here").await;
+
+        cache.merge(&other_path).await.unwrap();
+
+        let result = cache
+            .check_all_ids(vec![
+                "avatar_stale_there".into(),
+                "avatar_stale_here".into(),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(result["avatar_stale_there"], ProviderKind::NSVR.bit());
+        assert_eq!(result["avatar_stale_here"], ProviderKind::PAW.bit());
+    }
+
+    #[tokio::test]
+    async fn remembers_own_ids() {
+        let cache = cache().await;
+        let ids = vec![String::from("usr_local"), String::from("avtr_worn")];
+        cache.store_own_ids(ids.clone()).await.unwrap();
+        cache.store_own_ids(ids.clone()).await.unwrap();
+
+        let mut own_ids = cache.own_ids().await.unwrap();
+        own_ids.sort();
+        assert_eq!(own_ids, ["avtr_worn", "usr_local"]);
+    }
+
+    #[tokio::test]
+    async fn records_first_source_and_sightings() {
+        let cache = cache().await;
+
+        cache
+            .record_sightings(Source::Amplitude, ["avatar_a"])
+            .await
+            .unwrap();
+        cache
+            .record_sightings(Source::OutputLog, ["avatar_a", "avatar_b"])
+            .await
+            .unwrap();
+
+        // Sightings alone don't count as sent to any provider
+        let result = cache.check_all_ids(vec!["avatar_a".into()]).await.unwrap();
+        assert_eq!(result["avatar_a"], 0);
+
+        let stats = cache.source_stats().await.unwrap();
+        assert!(stats.contains(&SourceStats {
+            source:    Some(Source::Amplitude),
+            avatars:   1,
+            sightings: 2,
+        }));
+        assert!(stats.contains(&SourceStats {
+            source:    Some(Source::OutputLog),
+            avatars:   1,
+            sightings: 1,
+        }));
+    }
+
+    #[tokio::test]
+    async fn queues_and_completes_retries() {
+        let cache = cache().await;
+
+        cache
+            .enqueue_retry("avatar_a", 2, "timed out", Duration::ZERO)
+            .await
+            .unwrap();
+        cache
+            .enqueue_retry("avatar_b", 2, "timed out", Duration::from_hours(1))
+            .await
+            .unwrap();
+
+        let due = cache.due_retries(u32::MAX, 10).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].avatar_id, "avatar_a");
+
+        cache
+            .reschedule_retry("avatar_a", 2, "502 Bad Gateway", Duration::from_hours(1))
+            .await
+            .unwrap();
+        assert!(cache.due_retries(u32::MAX, 10).await.unwrap().is_empty());
+
+        let queue = cache.retry_queue().await.unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue[0].attempts, 1);
+        assert_eq!(queue[0].last_error.as_deref(), Some("502 Bad Gateway"));
+
+        cache
+            .store_avatar_ids_with_providers(vec![("avatar_a", 1u32)])
+            .await
+            .unwrap();
+        cache.remove_retry("avatar_a", 2, true).await.unwrap();
+        cache.remove_retry("avatar_b", 2, false).await.unwrap();
+
+        assert!(cache.retry_queue().await.unwrap().is_empty());
+        let result = cache
+            .check_all_ids(vec!["avatar_a".into(), "avatar_b".into()])
+            .await
+            .unwrap();
+        assert_eq!(result["avatar_a"], 1 | 2);
+        assert_eq!(result["avatar_b"], 0);
+    }
+
+    #[tokio::test]
+    async fn due_retries_only_include_the_given_providers() {
+        let cache = cache().await;
+        for avatar_id in ["avatar_a", "avatar_b"] {
+            cache
+                .enqueue_retry(avatar_id, 4, "timed out", Duration::ZERO)
+                .await
+                .unwrap();
+        }
+        cache
+            .enqueue_retry("avatar_c", 2, "timed out", Duration::ZERO)
+            .await
+            .unwrap();
+
+        // Queued first, but their provider isn't built
+        let due = cache.due_retries(1 | 2, 1).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].avatar_id, "avatar_c");
+        assert_eq!(cache.retry_queue().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn postponed_retries_keep_their_attempts() {
+        let cache = cache().await;
+
+        cache
+            .enqueue_retry("avatar_a", 2, "timed out", Duration::ZERO)
+            .await
+            .unwrap();
+        cache
+            .postpone_retry("avatar_a", 2, Duration::from_hours(1))
+            .await
+            .unwrap();
+        assert!(cache.due_retries(u32::MAX, 10).await.unwrap().is_empty());
+
+        let queue = cache.retry_queue().await.unwrap();
+        assert_eq!(queue[0].attempts, 0);
+        assert_eq!(queue[0].last_error.as_deref(), Some("timed out"));
+    }
+
+    #[tokio::test]
+    async fn outbox_is_kept_per_provider() {
+        let cache = cache().await;
+        let avtrdb = cache.outbox(ProviderKind::AVTRDB);
+        let cutedb = cache.outbox(ProviderKind::CUTEDB);
+
+        avtrdb.push("avatar_a").await.unwrap();
+        avtrdb.push("avatar_b").await.unwrap();
+        avtrdb.push("avatar_a").await.unwrap();
+        cutedb.push("avatar_a").await.unwrap();
+
+        assert_eq!(avtrdb.load().await.unwrap(), ["avatar_a", "avatar_b"]);
+
+        avtrdb.complete(&["avatar_a".into()]).await.unwrap();
+        assert_eq!(avtrdb.load().await.unwrap(), ["avatar_b"]);
+        assert_eq!(cutedb.load().await.unwrap(), ["avatar_a"]);
+
+        let result = cache.check_all_ids(vec!["avatar_a".into()]).await.unwrap();
+        assert_eq!(result["avatar_a"], ProviderKind::AVTRDB.bit());
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("avatars.sqlite.lock");
+
+        let lock = CacheLock::try_acquire_at_location(&path).unwrap();
+        assert!(CacheLock::try_acquire_at_location(&path).is_err());
+
+        drop(lock);
+        assert!(CacheLock::try_acquire_at_location(&path).is_ok());
+    }
+
+    #[tokio::test]
+    async fn metadata_keeps_fields_a_later_provider_left_out() {
+        let cache = cache().await;
+        assert_eq!(cache.metadata("avatar_a").await.unwrap(), None);
+
+        let metadata = AvatarMetadata {
+            name: Some("Avatar A".into()),
+            author: Some("Author".into()),
+            ..Default::default()
+        };
+        cache.store_metadata("avatar_a", metadata).await.unwrap();
+
+        let metadata = AvatarMetadata {
+            author: Some("Renamed".into()),
+            platform: Some("standalonewindows".into()),
+            ..Default::default()
+        };
+        cache.store_metadata("avatar_a", metadata).await.unwrap();
+
+        assert_eq!(
+            cache.metadata("avatar_a").await.unwrap(),
+            Some(AvatarMetadata {
+                name:          Some("Avatar A".into()),
+                author:        Some("Renamed".into()),
+                author_id:     None,
+                platform:      Some("standalonewindows".into()),
+                thumbnail_url: None,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn searches_ids_names_and_worlds() {
+        let cache = cache().await;
+        let ids = [
+            "avtr_c38a1615-5bf5-42b4-84eb-a8b6c37cbd11",
+            "avtr_0e3ea6a4-2b6c-4d0f-9bfe-4ab0b5b6b2f1",
+        ];
+        cache
+            .record_sightings(Source::OutputLog, ids)
+            .await
+            .unwrap();
+
+        let metadata = AvatarMetadata {
+            name: Some("Kitsune Base".into()),
+            author: Some("Foxy".into()),
+            ..Default::default()
+        };
+        cache.store_metadata(ids[0], metadata).await.unwrap();
+        cache
+            .record_worlds([(ids[1].into(), "The Black Cat".into())])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            cache.search("kitsu", 10).await.unwrap(),
+            [SearchResult {
+                avatar_id: ids[0].into(),
+                name:      Some("Kitsune Base".into()),
+                author:    Some("Foxy".into()),
+                worlds:    None,
+            }]
+        );
+
+        let results = cache.search("black cat", 10).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].avatar_id, ids[1]);
+        assert_eq!(results[0].worlds.as_deref(), Some("The Black Cat"));
+
+        let results = cache.search("avtr_0e3ea6a4-2b6c", 10).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].avatar_id, ids[1]);
+
+        assert!(cache.search("\"", 10).await.unwrap().is_empty());
+        assert!(cache.search("   ", 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_index_follows_the_cache() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(DATABASE_NAME);
+        let cache = Cache::new_at_location(&path).await.unwrap();
+        cache
+            .store_avatar_ids_with_providers(vec![("avtr_a", 1u32), ("avtr_b", 1u32)])
+            .await
+            .unwrap();
+        let metadata = |name: &str| AvatarMetadata {
+            name: Some(name.into()),
+            ..Default::default()
+        };
+        cache
+            .store_metadata("avtr_a", metadata("Robot"))
+            .await
+            .unwrap();
+        cache
+            .store_metadata("avtr_b", metadata("Kitsune"))
+            .await
+            .unwrap();
+
+        // Renamed, vacuumed and pruned avatars are found as they are now
+        cache
+            .store_metadata("avtr_a", metadata("Dragon"))
+            .await
+            .unwrap();
+        cache.vacuum().await.unwrap();
+        assert!(cache.search("robot", 10).await.unwrap().is_empty());
+        assert_eq!(
+            cache.search("dragon", 10).await.unwrap()[0].avatar_id,
+            "avtr_a"
+        );
+
+        cache
+            .connection
+            .call(|c| c.execute("DELETE FROM avatars WHERE id = 'avtr_a'", []))
+            .await
+            .unwrap();
+        assert!(cache.search("dragon", 10).await.unwrap().is_empty());
+
+        // Searching only reads
+        let changes = cache
+            .connection
+            .call(|c| Ok::<_, rusqlite::Error>(c.total_changes()))
+            .await
+            .unwrap();
+        assert_eq!(cache.search("kitsune", 10).await.unwrap().len(), 1);
+        let after = cache
+            .connection
+            .call(|c| Ok::<_, rusqlite::Error>(c.total_changes()))
+            .await
+            .unwrap();
+        assert_eq!(changes, after);
+
+        // Caches from older versions are indexed when opened
+        cache
+            .connection
+            .call(|c| c.execute_batch("DROP TABLE avatar_search_ids; DELETE FROM avatar_search"))
+            .await
+            .unwrap();
+        cache.close().await.unwrap();
+        let cache = Cache::new_at_location(&path).await.unwrap();
+        assert_eq!(
+            cache.search("kitsune", 10).await.unwrap()[0].avatar_id,
+            "avtr_b"
+        );
     }
 }
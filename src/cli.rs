@@ -0,0 +1,603 @@
+#[cfg(feature = "cache")]
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use derive_config::DeriveTomlConfig;
+
+#[cfg(all(feature = "cache", feature = "custom"))]
+use crate::provider::custom;
+use crate::{
+    blocklist::Blocklist,
+    http,
+    provider::{
+        check,
+        lookup::{self, Found},
+    },
+    settings::Settings,
+};
+#[cfg(feature = "cache")]
+use crate::{
+    cache::{BACKUP_DIRECTORY_NAME, Cache, CacheLock, DATABASE_NAME},
+    print_colorized,
+    provider::{AvatarMetadata, ProviderKind},
+    vrchat::VRCHAT_LOW_PATH,
+};
+
+pub const USAGE: &str = "\
+Usage:
+  vrc-log [--wizard] [--dry-run] [game launch command...]
+  vrc-log block <avtr_...|usr_...>
+  vrc-log unblock <avtr_...|usr_...>
+  vrc-log queue
+  vrc-log search <text>
+  vrc-log lookup <avtr_...>
+  vrc-log providers check
+  vrc-log cache prune --older-than <duration>   (e.g. 30m, 12h, 90d, 8w)
+  vrc-log cache vacuum
+  vrc-log cache integrity-check
+  vrc-log cache backup [--keep <count>]
+  vrc-log cache stats
+  vrc-log cache merge <other.sqlite>";
+
+pub const DEFAULT_BACKUP_KEEP: usize = 5;
+pub const SEARCH_LIMIT: u32 = 50;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    #[cfg(feature = "cache")]
+    Cache(CacheCommand),
+    /// Never submit this avatar, or any avatar by this author
+    Block(String),
+    Unblock(String),
+    /// List the submissions waiting to be retried
+    #[cfg(feature = "cache")]
+    Queue,
+    /// Search the cache for avatars by ID, name, author or world
+    #[cfg(feature = "cache")]
+    Search(String),
+    /// Ask every enabled provider whether it already has the avatar
+    Lookup(String),
+    Providers(ProvidersCommand),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProvidersCommand {
+    /// Send a harmless request to every enabled provider
+    Check,
+}
+
+#[cfg(feature = "cache")]
+#[derive(Debug, PartialEq, Eq)]
+pub enum CacheCommand {
+    Prune { older_than: Duration },
+    Vacuum,
+    IntegrityCheck,
+    Backup { keep: usize },
+    Stats,
+    Merge { other: PathBuf },
+}
+
+impl Command {
+    /// # Parse a subcommand from the process arguments
+    ///
+    /// `None` means no subcommand was given, so the watcher should run.
+    ///
+    /// # Errors
+    /// Will return `Err` if the subcommand or its arguments are invalid
+    pub fn parse(args: &[String]) -> Result<Option<Self>> {
+        let mut args = args.iter().skip(1).map(String::as_str);
+        match args.next() {
+            #[cfg(feature = "cache")]
+            Some("cache") => Ok(Some(Self::Cache(CacheCommand::parse(args)?))),
+            Some(command @ ("block" | "unblock")) => {
+                let (Some(id), None) = (args.next(), args.next()) else {
+                    bail!("Expected exactly one <avtr_...|usr_...>\n{USAGE}");
+                };
+
+                let id = id.to_string();
+                Ok(Some(if command == "block" {
+                    Self::Block(id)
+                } else {
+                    Self::Unblock(id)
+                }))
+            }
+            #[cfg(feature = "cache")]
+            Some("queue") => Ok(Some(Self::Queue)),
+            #[cfg(feature = "cache")]
+            Some("search") => {
+                let text = args.collect::<Vec<_>>().join(" ");
+                if text.trim().is_empty() {
+                    bail!("Missing <text>\n{USAGE}");
+                }
+
+                Ok(Some(Self::Search(text)))
+            }
+            Some("lookup") => match (args.next(), args.next()) {
+                (Some(id), None) if id.starts_with("avtr_") => Ok(Some(Self::Lookup(id.into()))),
+                _ => bail!("Expected exactly one <avtr_...>\n{USAGE}"),
+            },
+            Some("providers") => match (args.next(), args.next()) {
+                (Some("check"), None) => Ok(Some(Self::Providers(ProvidersCommand::Check))),
+                _ => bail!("{USAGE}"),
+            },
+            Some("help" | "--help" | "-h") => bail!("{USAGE}"),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// # Add an avatar or author to the blocklist
+///
+/// # Errors
+/// Will return `Err` if the ID is invalid or the blocklist couldn't be written
+pub fn block(id: &str) -> Result<()> {
+    if Blocklist::block(&Blocklist::path()?, id)? {
+        info!("Blocked {id}, it will never be submitted");
+    } else {
+        info!("{id} is already blocked");
+    }
+
+    Ok(())
+}
+
+/// # Remove an avatar or author from the blocklist
+///
+/// # Errors
+/// Will return `Err` if the ID is invalid or the blocklist couldn't be written
+pub fn unblock(id: &str) -> Result<()> {
+    if Blocklist::unblock(&Blocklist::path()?, id)? {
+        info!("Unblocked {id}");
+    } else {
+        info!("{id} wasn't blocked");
+    }
+
+    Ok(())
+}
+
+/// # Print the submissions waiting to be retried
+///
+/// # Errors
+/// Will return `Err` if the cache errors
+#[cfg(feature = "cache")]
+pub async fn print_queue() -> Result<()> {
+    let cache = Cache::read_only().await?;
+    let queue = cache.retry_queue().await?;
+    if queue.is_empty() {
+        info!("The retry queue is empty");
+    }
+
+    #[cfg(feature = "custom")]
+    let settings = Settings::load().ok();
+    for entry in queue {
+        let provider = ProviderKind::from_bit(entry.provider_bit).map(|kind| kind.to_string());
+        #[cfg(feature = "custom")]
+        let provider = provider.or_else(|| {
+            let name = custom::name(settings.as_ref()?, entry.provider_bit)?;
+            Some(name.to_string())
+        });
+        let provider = provider.unwrap_or_else(|| format!("Provider {}", entry.provider_bit));
+        info!(
+            "{} -> {provider} | {} attempts | next at {} UTC | {}",
+            entry.avatar_id,
+            entry.attempts,
+            entry.next_attempt_at,
+            entry.last_error.unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+/// # Print the cached avatars matching the text
+///
+/// # Errors
+/// Will return `Err` if the cache errors
+#[cfg(feature = "cache")]
+pub async fn print_search(text: &str) -> Result<()> {
+    let cache = Cache::read_only().await?;
+    let results = cache.search(text, SEARCH_LIMIT).await?;
+    if results.is_empty() {
+        info!("No avatars found for {text:?}");
+    }
+
+    for result in results {
+        print_colorized(&result.avatar_id);
+        let details = [
+            result.name,
+            result.author.map(|author| format!("by {author}")),
+            result.worlds.map(|worlds| format!("seen in {worlds}")),
+        ];
+        let details = details.into_iter().flatten().collect::<Vec<_>>();
+        if !details.is_empty() {
+            info!("  {}", details.join(" | "));
+        }
+    }
+
+    Ok(())
+}
+
+/// # Print which enabled providers already have the avatar, and what they know about it
+///
+/// With the cache, what they know about it is stored.
+/// None of them is marked as sent, the avatar is still submitted like any other.
+///
+/// # Errors
+/// Will return `Err` if the settings can't be loaded or no provider could be asked
+pub async fn lookup(avatar_id: &str) -> Result<()> {
+    let settings =
+        Settings::load().context("Failed to load the settings, run vrc-log once first")?;
+    let searches = lookup::searches(&settings);
+    if searches.is_empty() {
+        bail!("No providers are enabled");
+    }
+
+    let client = http::client(&settings.http)?;
+    let lookups = futures::future::join_all(
+        searches
+            .into_iter()
+            .map(|search| search.run(&client, avatar_id)),
+    )
+    .await;
+
+    // A live instance may hold the cache, so it's never moved aside for recovery here
+    #[cfg(feature = "cache")]
+    let cache = Cache::new_at_location(&VRCHAT_LOW_PATH.join(DATABASE_NAME)).await?;
+    let (mut known, mut asked) = (0, 0);
+    for lookup in lookups {
+        match lookup.found {
+            Ok(Found::Known(metadata)) => {
+                let details = [
+                    metadata.name.clone(),
+                    metadata
+                        .author
+                        .as_ref()
+                        .map(|author| format!("by {author}")),
+                    metadata.platform.clone(),
+                ];
+                let details = details.into_iter().flatten().collect::<Vec<_>>();
+                info!("[{}] Has it | {}", lookup.name, details.join(" | "));
+                #[cfg(feature = "cache")]
+                {
+                    if metadata != AvatarMetadata::default() {
+                        cache.store_metadata(avatar_id, metadata).await?;
+                    }
+                }
+                known += 1;
+                asked += 1;
+            }
+            Ok(Found::Unknown) => {
+                info!("[{}] Doesn't have it", lookup.name);
+                asked += 1;
+            }
+            Ok(Found::NoSearchApi) => info!("[{}] Has no search API to ask", lookup.name),
+            Err(error) => error!("[{}] Lookup failed | {error}", lookup.name),
+        }
+    }
+
+    if asked == 0 {
+        bail!("None of the providers could be asked about {avatar_id}");
+    }
+    info!("{known} of {asked} providers have {avatar_id}");
+
+    Ok(())
+}
+
+impl ProvidersCommand {
+    /// # Errors
+    /// Will return `Err` if the settings can't be loaded or a provider failed its check
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Self::Check => check_providers().await,
+        }
+    }
+}
+
+/// # Report how every enabled provider answers a test submission
+async fn check_providers() -> Result<()> {
+    let settings =
+        Settings::load().context("Failed to load the settings, run vrc-log once first")?;
+    let probes = check::probes(&settings);
+    if probes.is_empty() {
+        bail!("No providers are enabled");
+    }
+
+    info!(
+        "Submitting {} to every enabled provider",
+        check::TEST_AVATAR_ID
+    );
+    let client = http::client(&settings.http)?;
+    let reports =
+        futures::future::join_all(probes.into_iter().map(|probe| probe.run(&client))).await;
+
+    let mut failed = 0;
+    for report in &reports {
+        let tls = if report.https { "HTTPS" } else { "plain HTTP" };
+        let latency = report.latency.as_millis();
+        match &report.answer {
+            Ok(answer) => {
+                let parses = match answer.parses {
+                    Some(true) => " | response parses",
+                    Some(false) => " | response doesn't parse anymore",
+                    None => "",
+                };
+                let line = format!(
+                    "[{}] {} | {latency} ms | {tls}{parses}",
+                    report.name, answer.status
+                );
+                if report.healthy() {
+                    info!("{line}");
+                } else {
+                    error!("{line} | unexpected answer from {}", report.url);
+                }
+            }
+            Err(error) => error!(
+                "[{}] Unreachable after {latency} ms | {tls} | {error}",
+                report.name
+            ),
+        }
+        if !report.healthy() {
+            failed += 1;
+        }
+        if !report.https {
+            warn!(
+                "[{}] Avatars and attribution are sent unencrypted",
+                report.name
+            );
+        }
+    }
+
+    if failed > 0 {
+        bail!("{failed} of {} providers failed the check", reports.len());
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "cache")]
+impl CacheCommand {
+    fn parse<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<Self> {
+        let command = match args.next() {
+            Some("prune") => match (args.next(), args.next()) {
+                (Some("--older-than"), Some(value)) => Self::Prune {
+                    older_than: parse_duration(value)?,
+                },
+                _ => bail!("Missing --older-than <duration>\n{USAGE}"),
+            },
+            Some("vacuum") => Self::Vacuum,
+            Some("integrity-check") => Self::IntegrityCheck,
+            Some("stats") => Self::Stats,
+            Some("backup") => match (args.next(), args.next()) {
+                (None, _) => Self::Backup {
+                    keep: DEFAULT_BACKUP_KEEP,
+                },
+                (Some("--keep"), Some(value)) => Self::Backup {
+                    keep: value.parse().context("--keep must be a number")?,
+                },
+                _ => bail!("Missing --keep <count>\n{USAGE}"),
+            },
+            Some("merge") => match args.next() {
+                Some(other) => Self::Merge {
+                    other: PathBuf::from(other),
+                },
+                None => bail!("Missing <other.sqlite>\n{USAGE}"),
+            },
+            _ => bail!("{USAGE}"),
+        };
+
+        if let Some(extra) = args.next() {
+            bail!("Unexpected argument: {extra}\n{USAGE}");
+        }
+
+        Ok(command)
+    }
+
+    /// # Errors
+    /// Will return `Err` if a live instance holds the cache or the maintenance fails
+    pub async fn run(self) -> Result<()> {
+        // Merging is a single transaction, so it can run next to a live instance
+        let lock = match self {
+            Self::Merge { .. } => None,
+            _ => Some(CacheLock::try_acquire()?),
+        };
+
+        // Read only, so neither migrations nor the automatic recovery change what's checked
+        if self == Self::IntegrityCheck {
+            let cache = Cache::open_read_only(&VRCHAT_LOW_PATH.join(DATABASE_NAME)).await?;
+            let problems = cache.integrity_check().await?;
+            if problems == ["ok"] {
+                info!("The avatar cache is healthy");
+            } else {
+                for problem in &problems {
+                    error!("{problem}");
+                }
+                bail!("The avatar cache is corrupted, the latest backup is restored on next start");
+            }
+
+            return Ok(());
+        }
+
+        // Without the lock a live instance may have the cache open, so it's never recovered
+        let cache = if lock.is_some() {
+            Cache::new().await?
+        } else {
+            Cache::new_at_location(&VRCHAT_LOW_PATH.join(DATABASE_NAME)).await?
+        };
+        match self {
+            Self::Prune { older_than } => {
+                let pruned = cache.prune(older_than).await?;
+                info!("Pruned {pruned} avatars");
+            }
+            Self::Vacuum => {
+                cache.vacuum().await?;
+                info!("Vacuumed the avatar cache");
+            }
+            Self::Backup { keep } => {
+                let path = cache
+                    .backup(&VRCHAT_LOW_PATH.join(BACKUP_DIRECTORY_NAME), keep)
+                    .await?;
+                info!("Backed up the avatar cache to {path:?}");
+            }
+            Self::Stats => {
+                for stats in cache.source_stats().await? {
+                    let source = stats
+                        .source
+                        .map_or_else(|| String::from("unknown"), |source| source.to_string());
+                    info!(
+                        "{source}: {} avatars, {} sightings",
+                        stats.avatars, stats.sightings
+                    );
+                }
+            }
+            Self::Merge { other } => {
+                let path = VRCHAT_LOW_PATH.join(DATABASE_NAME);
+                if other.canonicalize().ok() == path.canonicalize().ok() {
+                    bail!("Can't merge the avatar cache into itself");
+                }
+
+                let report = cache.merge(&other).await?;
+                info!(
+                    "Merged {other:?}: {} added, {} updated, {} unchanged",
+                    report.added, report.updated, report.unchanged
+                );
+            }
+            Self::IntegrityCheck => unreachable!(),
+        }
+
+        Ok(())
+    }
+}
+
+/// # Parse a human duration such as `30m`, `12h`, `90d` or `8w`
+///
+/// # Errors
+/// Will return `Err` if the number or unit is invalid
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .context("Missing a duration unit (s, m, h, d, w)")?;
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<u64>()
+        .with_context(|| format!("Invalid duration: {value}"))?;
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => bail!("Invalid duration unit: {unit}"),
+    };
+
+    Ok(Duration::from_secs(number * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("vrc-log")
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("12h").unwrap(), Duration::from_hours(12));
+        assert_eq!(
+            parse_duration("30d").unwrap(),
+            Duration::from_hours(30 * 24)
+        );
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("3y").is_err());
+    }
+
+    #[test]
+    fn no_subcommand_runs_the_watcher() {
+        assert_eq!(Command::parse(&args(&[])).unwrap(), None);
+        assert_eq!(Command::parse(&args(&["steam.exe", "-vr"])).unwrap(), None);
+    }
+
+    #[test]
+    fn parses_block_and_unblock() {
+        assert_eq!(
+            Command::parse(&args(&["block", "avtr_1"])).unwrap(),
+            Some(Command::Block(String::from("avtr_1")))
+        );
+        assert_eq!(
+            Command::parse(&args(&["unblock", "usr_1"])).unwrap(),
+            Some(Command::Unblock(String::from("usr_1")))
+        );
+        assert!(Command::parse(&args(&["block"])).is_err());
+        assert!(Command::parse(&args(&["block", "avtr_1", "avtr_2"])).is_err());
+    }
+
+    #[test]
+    fn parses_providers_check() {
+        assert_eq!(
+            Command::parse(&args(&["providers", "check"])).unwrap(),
+            Some(Command::Providers(ProvidersCommand::Check))
+        );
+        assert!(Command::parse(&args(&["providers"])).is_err());
+        assert!(Command::parse(&args(&["providers", "check", "extra"])).is_err());
+    }
+
+    #[test]
+    fn parses_lookup() {
+        assert_eq!(
+            Command::parse(&args(&["lookup", "avtr_1"])).unwrap(),
+            Some(Command::Lookup(String::from("avtr_1")))
+        );
+        assert!(Command::parse(&args(&["lookup"])).is_err());
+        assert!(Command::parse(&args(&["lookup", "usr_1"])).is_err());
+        assert!(Command::parse(&args(&["lookup", "avtr_1", "avtr_2"])).is_err());
+    }
+
+    #[cfg(feature = "cache")]
+    #[test]
+    fn parses_cache_commands() {
+        assert_eq!(
+            Command::parse(&args(&["cache", "prune", "--older-than", "90d"])).unwrap(),
+            Some(Command::Cache(CacheCommand::Prune {
+                older_than: Duration::from_hours(90 * 24),
+            }))
+        );
+        assert_eq!(
+            Command::parse(&args(&["cache", "backup"])).unwrap(),
+            Some(Command::Cache(CacheCommand::Backup {
+                keep: DEFAULT_BACKUP_KEEP,
+            }))
+        );
+        assert_eq!(
+            Command::parse(&args(&["cache", "backup", "--keep", "3"])).unwrap(),
+            Some(Command::Cache(CacheCommand::Backup { keep: 3 }))
+        );
+        assert_eq!(
+            Command::parse(&args(&["cache", "stats"])).unwrap(),
+            Some(Command::Cache(CacheCommand::Stats))
+        );
+        assert_eq!(
+            Command::parse(&args(&["queue"])).unwrap(),
+            Some(Command::Queue)
+        );
+        assert_eq!(
+            Command::parse(&args(&["search", "black", "cat"])).unwrap(),
+            Some(Command::Search(String::from("black cat")))
+        );
+        assert!(Command::parse(&args(&["search"])).is_err());
+        assert_eq!(
+            Command::parse(&args(&["cache", "merge", "laptop.sqlite"])).unwrap(),
+            Some(Command::Cache(CacheCommand::Merge {
+                other: PathBuf::from("laptop.sqlite"),
+            }))
+        );
+        assert!(Command::parse(&args(&["cache", "merge"])).is_err());
+        assert!(Command::parse(&args(&["cache", "prune"])).is_err());
+        assert!(Command::parse(&args(&["cache", "vacuum", "now"])).is_err());
+    }
+}
@@ -7,8 +7,9 @@ use discord_presence::{
 };
 use parking_lot::Mutex;
 
+pub use crate::settings::DEVELOPER_ID;
+
 pub const CLIENT_ID: u64 = 1_137_885_877_918_502_923;
-pub const DEVELOPER_ID: &str = "358558305997684739";
 
 pub struct Discord {
     pub client: Client,
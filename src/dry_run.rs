@@ -0,0 +1,97 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use flume::{Receiver, Sender};
+
+#[cfg(feature = "cache")]
+use crate::cache::Cache;
+use crate::{blocklist::Blocklist, provider::preview, scan, settings::Settings};
+
+/// # Print what the watcher would submit, for every file that changes
+///
+/// Nothing is sent, stored in the cache or cleared from `amplitude.cache`.
+///
+/// # Errors
+/// Will return `Err` if the cache errors
+pub async fn watch(
+    settings: &Settings,
+    #[cfg(feature = "cache")] cache: &Cache,
+    (_tx, rx): (Sender<PathBuf>, Receiver<PathBuf>),
+) -> Result<()> {
+    // The cache isn't updated, so without this every change would print the whole file again
+    let mut printed = HashSet::new();
+    while let Ok(path) = rx.recv_async().await {
+        #[cfg(feature = "cache")]
+        print_payloads(settings, cache, &mut printed, &path).await?;
+        #[cfg(not(feature = "cache"))]
+        print_payloads(settings, &mut printed, &path).await?;
+    }
+
+    Ok(())
+}
+
+/// # Print the requests every enabled provider would be sent for the avatars in the file
+///
+/// Filtered like a real submission: blocked, own and already sent avatars are left out.
+async fn print_payloads(
+    settings: &Settings,
+    #[cfg(feature = "cache")] cache: &Cache,
+    printed: &mut HashSet<String>,
+    path: &Path,
+) -> Result<()> {
+    let scanned = scan(path);
+
+    let mut blocklist = Blocklist::load_or_warn();
+    if settings.exclude_own_avatars {
+        #[cfg(feature = "cache")]
+        blocklist.extend(cache.own_ids().await?);
+        let own_ids = scanned.local_user.iter().chain(&scanned.own_avatars);
+        blocklist.extend(own_ids.cloned());
+    }
+
+    let avatar_ids = scanned
+        .avatars
+        .into_keys()
+        .filter(|id| !printed.contains(id))
+        .collect::<Vec<_>>();
+    #[cfg(feature = "cache")]
+    let author_ids = if blocklist.has_authors() {
+        cache.author_ids(&avatar_ids).await?
+    } else {
+        HashMap::new()
+    };
+    #[cfg(not(feature = "cache"))]
+    let author_ids = HashMap::new();
+    let avatar_ids = blocklist.filter(avatar_ids, &author_ids);
+
+    #[cfg(feature = "cache")]
+    let checked = cache.check_all_ids(avatar_ids).await?;
+    #[cfg(not(feature = "cache"))]
+    let checked = avatar_ids
+        .into_iter()
+        .map(|id| (id, 0))
+        .collect::<HashMap<_, _>>();
+    printed.extend(checked.keys().cloned());
+
+    let attribution = settings.attribution.get_user_id().await;
+    for preview in preview::previews(settings, &checked, &attribution) {
+        if preview.avatar_ids.is_empty() {
+            continue;
+        }
+
+        info!(
+            "[{}] Would send {} avatars: {}",
+            preview.name,
+            preview.avatar_ids.len(),
+            preview.avatar_ids.join(", ")
+        );
+        for payload in preview.payloads {
+            info!("{payload}");
+        }
+    }
+
+    Ok(())
+}
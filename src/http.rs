@@ -0,0 +1,64 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, Result};
+use reqwest::{Certificate, Client, Proxy};
+
+use crate::settings::{HttpSettings, IpVersion};
+
+/// # Build the client every provider shares
+///
+/// Timeouts are set per request, since providers can override them.
+///
+/// # Errors
+/// Will return `Err` if the proxy URL is invalid or a certificate can't be read
+pub fn client(settings: &HttpSettings) -> Result<Client> {
+    let mut builder = Client::builder();
+
+    if let Some(proxy) = &settings.proxy {
+        let proxy = Proxy::all(proxy).with_context(|| format!("Invalid proxy {proxy}"))?;
+        builder = builder.proxy(proxy);
+    }
+
+    let mut certificates = Vec::new();
+    for path in &settings.root_certificates {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Failed to read certificate {}", path.display()))?;
+        let bundle = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid certificate {}", path.display()))?;
+        certificates.extend(bundle);
+    }
+    builder = builder.tls_certs_merge(certificates);
+
+    // Binding to an unspecified address only allows connecting over that version
+    builder = match settings.ip_version {
+        IpVersion::Any => builder,
+        IpVersion::V4 => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        IpVersion::V6 => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_from_the_settings() {
+        let settings = HttpSettings {
+            proxy: Some(String::from("socks5://127.0.0.1:1080")),
+            ip_version: IpVersion::V4,
+            ..Default::default()
+        };
+        assert!(client(&settings).is_ok());
+    }
+
+    #[test]
+    fn rejects_missing_certificates() {
+        let settings = HttpSettings {
+            root_certificates: vec!["missing-certificate.pem".into()],
+            ..Default::default()
+        };
+        assert!(client(&settings).is_err());
+    }
+}
@@ -2,7 +2,7 @@
 extern crate tracing;
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
     fmt::Display,
     fs::{create_dir_all, File},
@@ -20,20 +20,28 @@ use flume::{Receiver, Sender};
 use lazy_regex::{lazy_regex, regex_replace_all, Lazy, Regex};
 use notify::{Config, Event, PollWatcher, RecursiveMode, Watcher};
 use parking_lot::RwLock;
+use strum::{AsRefStr, Display, EnumIter, EnumString};
 use terminal_link::Link;
+use tokio::time::Instant;
 
 #[cfg(feature = "cache")]
 use crate::process::process_with_cache;
 #[cfg(not(feature = "cache"))]
 use crate::process::process_without_cache;
-use crate::{provider::Provider, settings::Settings};
+use crate::{blocklist::Blocklist, provider::Provider, settings::Settings};
 
+pub mod blocklist;
 #[cfg(feature = "cache")]
 pub mod cache;
+pub mod cli;
 #[cfg(feature = "discord")]
 pub mod discord;
+pub mod dry_run;
+pub mod http;
 mod process;
 pub mod provider;
+#[cfg(feature = "cache")]
+pub mod queue;
 pub mod settings;
 pub mod vrchat;
 #[cfg(windows)]
@@ -54,8 +62,15 @@ pub fn get_local_time() -> String {
 
 /// # Errors
 /// Will return `Err` if it couldn't get the GitHub repository.
-pub async fn check_for_updates() -> reqwest::Result<bool> {
-    let response = reqwest::get(CARGO_PKG_HOMEPAGE).await?;
+pub async fn check_for_updates(
+    client: &reqwest::Client,
+    timeout: Duration,
+) -> reqwest::Result<bool> {
+    let response = client
+        .get(CARGO_PKG_HOMEPAGE)
+        .timeout(timeout)
+        .send()
+        .await?;
     if let Some(mut segments) = response.url().path_segments()
         && let Some(remote_version) = segments.next_back()
     {
@@ -140,45 +155,234 @@ pub fn launch_game(args: &[String]) -> Result<()> {
     Ok(())
 }
 
+/// # Where an avatar ID was discovered
+#[derive(AsRefStr, Clone, Copy, Debug, Display, EnumIter, EnumString, PartialEq, Eq, Hash)]
+#[strum(serialize_all = "snake_case")]
+pub enum Source {
+    /// `output_log_*.txt`
+    OutputLog,
+    /// `amplitude.cache`
+    Amplitude,
+    /// `Procmon.csv`
+    Procmon,
+}
+
+impl Source {
+    #[must_use]
+    pub fn from_path(path: &Path) -> Self {
+        match path.file_name().and_then(OsStr::to_str) {
+            Some("amplitude.cache") => Self::Amplitude,
+            Some("Procmon.csv") => Self::Procmon,
+            _ => Self::OutputLog,
+        }
+    }
+}
+
+/// How long changes to `amplitude.cache` wait for `output_log` to be read
+///
+/// Amplitude is polled every 100 ms and `output_log` only every second,
+/// so an avatar the user switched into would otherwise be submitted before it's known as theirs.
+const AMPLITUDE_HOLD: Duration = Duration::from_secs(2);
+
 /// # Errors
-/// Will return `Err` if `Sqlite::new` or `Provider::send_avatar_id` errors
+/// Will return `Err` if the cache or `Provider::send_avatar_id` errors
 pub async fn process_avatars(
     providers: Vec<Arc<Box<dyn Provider>>>,
     settings: &Settings,
+    #[cfg(feature = "cache")] cache: cache::Cache,
     (_tx, rx): (Sender<PathBuf>, Receiver<PathBuf>),
 ) -> Result<()> {
+    // Files are re-read whole on every change, only new appearances count as sightings
+    let mut sightings = HashMap::<PathBuf, HashSet<String>>::new();
+
     #[cfg(feature = "cache")]
-    let cache = cache::Cache::new().await?;
-
-    while let Ok(path) = rx.recv_async().await {
-        let avatar_ids = parse_avatar_ids(&path);
-
-        // Clear amplitude file after reading if enabled and it's an amplitude file
-        if settings.clear_amplitude
-            && path.file_name().and_then(|n| n.to_str()) == Some("amplitude.cache")
-        {
-            match std::fs::write(&path, "") {
-                Ok(()) => debug!("Cleared amplitude file: {path:?}"),
-                Err(error) => warn!("Failed to clear amplitude file: {error}"),
+    let mut own_ids = load_own_ids(settings, &cache).await?;
+    #[cfg(not(feature = "cache"))]
+    let mut own_ids = HashSet::new();
+
+    let process = async {
+        let mut held = None;
+        let mut deadline = Instant::now();
+        loop {
+            // Everything already queued goes first, `output_log` included
+            let (path, was_held) = tokio::select! {
+                biased;
+                path = rx.recv_async() => (path.ok(), false),
+                () = tokio::time::sleep_until(deadline), if held.is_some() => (held.take(), true),
+            };
+            let Some(path) = path else {
+                break;
+            };
+
+            let source = Source::from_path(&path);
+            if settings.exclude_own_avatars && !was_held && source == Source::Amplitude {
+                // Later changes are read along with the first, so they don't push it back
+                if held.replace(path).is_none() {
+                    deadline = Instant::now() + AMPLITUDE_HOLD;
+                }
+                continue;
             }
+
+            process_file(
+                &providers,
+                settings,
+                #[cfg(feature = "cache")]
+                &cache,
+                &mut sightings,
+                &mut own_ids,
+                &path,
+            )
+            .await?;
         }
 
-        #[cfg(feature = "cache")]
-        process_with_cache(
-            providers.clone(),
-            settings.print_scanned,
-            &cache,
-            avatar_ids,
-        )
-        .await?;
-        #[cfg(not(feature = "cache"))]
-        process_without_cache(providers.clone(), settings.print_scanned, avatar_ids).await?;
+        if let Some(path) = held {
+            process_file(
+                &providers,
+                settings,
+                #[cfg(feature = "cache")]
+                &cache,
+                &mut sightings,
+                &mut own_ids,
+                &path,
+            )
+            .await?;
+        }
+
+        debug!("Channel closed, stopping avatar processing");
+        Ok(())
+    };
+
+    // The retry task holds providers too, so it must stop with the processing
+    #[cfg(feature = "cache")]
+    tokio::select! {
+        result = process => result,
+        result = queue::run(providers.clone(), settings, cache.clone()) => result,
+    }
+    #[cfg(not(feature = "cache"))]
+    process.await
+}
+
+/// # Submit the avatars in a file that changed
+async fn process_file(
+    providers: &[Arc<Box<dyn Provider>>],
+    settings: &Settings,
+    #[cfg(feature = "cache")] cache: &cache::Cache,
+    sightings: &mut HashMap<PathBuf, HashSet<String>>,
+    own_ids: &mut HashSet<String>,
+    path: &Path,
+) -> Result<()> {
+    let source = Source::from_path(path);
+    let scanned = scan(path);
+    #[cfg(feature = "cache")]
+    remember_own_ids(settings, own_ids, &scanned, cache).await?;
+    #[cfg(not(feature = "cache"))]
+    remember_own_ids(settings, own_ids, &scanned).await?;
+
+    let avatars = scanned.avatars;
+    let avatar_ids = avatars.keys().cloned().collect::<Vec<_>>();
+    let seen = sightings.entry(path.to_path_buf()).or_default();
+    let sighted = avatar_ids
+        .iter()
+        .filter(|id| seen.insert((*id).clone()))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // Clear amplitude file after reading if enabled and it's an amplitude file
+    if settings.clear_amplitude && source == Source::Amplitude {
+        match std::fs::write(path, "") {
+            Ok(()) => {
+                debug!("Cleared amplitude file: {path:?}");
+                sightings.remove(path);
+            }
+            Err(error) => warn!("Failed to clear amplitude file: {error}"),
+        }
+    }
+
+    #[cfg(feature = "cache")]
+    {
+        cache.record_sightings(source, sighted).await?;
+        cache.record_worlds(avatar_worlds(avatars)).await?;
     }
+    #[cfg(not(feature = "cache"))]
+    let _ = (sighted, avatars);
+
+    // Reloaded per change, so `vrc-log block` applies without a restart
+    let mut blocklist = Blocklist::load_or_warn();
+    blocklist.extend(own_ids.iter().cloned());
+
+    #[cfg(feature = "cache")]
+    process_with_cache(
+        providers.to_vec(),
+        settings.print_scanned,
+        cache,
+        &blocklist,
+        avatar_ids,
+    )
+    .await?;
+    #[cfg(not(feature = "cache"))]
+    process_without_cache(
+        providers.to_vec(),
+        settings.print_scanned,
+        &blocklist,
+        avatar_ids,
+    )
+    .await?;
 
-    debug!("Channel closed, stopping avatar processing");
     Ok(())
 }
 
+/// # The local users and avatars they wore in earlier sessions
+#[cfg(feature = "cache")]
+async fn load_own_ids(settings: &Settings, cache: &cache::Cache) -> Result<HashSet<String>> {
+    if !settings.exclude_own_avatars {
+        return Ok(HashSet::new());
+    }
+
+    Ok(cache.own_ids().await?.into_iter().collect())
+}
+
+/// # Remember the local user and the avatars they wore, they're excluded like blocked IDs
+#[cfg_attr(not(feature = "cache"), allow(clippy::unused_async))]
+async fn remember_own_ids(
+    settings: &Settings,
+    own_ids: &mut HashSet<String>,
+    scan: &Scan,
+    #[cfg(feature = "cache")] cache: &cache::Cache,
+) -> Result<()> {
+    if !settings.exclude_own_avatars {
+        return Ok(());
+    }
+
+    let new_ids = scan
+        .local_user
+        .iter()
+        .chain(&scan.own_avatars)
+        .filter(|id| !own_ids.contains(*id))
+        .cloned()
+        .collect::<Vec<_>>();
+    if new_ids.is_empty() {
+        return Ok(());
+    }
+
+    info!("Excluding your own {} from submissions", new_ids.join(", "));
+    #[cfg(feature = "cache")]
+    cache.store_own_ids(new_ids.clone()).await?;
+    own_ids.extend(new_ids);
+
+    Ok(())
+}
+
+#[cfg(feature = "cache")]
+fn avatar_worlds(
+    avatars: HashMap<String, HashSet<String>>,
+) -> impl Iterator<Item = (String, String)> {
+    avatars.into_iter().flat_map(|(avatar_id, worlds)| {
+        worlds
+            .into_iter()
+            .map(move |world| (avatar_id.clone(), world))
+    })
+}
+
 /// # Errors
 /// Will return `Err` if `std::fs::canonicalize` errors
 ///
@@ -202,26 +406,66 @@ pub fn parse_path_env(path: &str) -> Result<PathBuf, Error> {
 
 #[must_use]
 pub fn parse_avatar_ids(path: &Path) -> impl IntoIterator<Item = String> {
+    scan(path).avatars.into_keys()
+}
+
+/// # What a file tells about the avatars in it
+#[derive(Debug, Default)]
+pub struct Scan {
+    /// Avatar IDs with the worlds they were seen in
+    pub avatars:     HashMap<String, HashSet<String>>,
+    /// The `usr_` ID of the logged in user
+    pub local_user:  Option<String>,
+    /// Avatars the logged in user switched into
+    pub own_avatars: HashSet<String>,
+}
+
+/// # Scan a file for avatar IDs
+///
+/// Worlds and the local user are only known for `output_log_*.txt`,
+/// which logs every room it enters and every avatar the local user selects.
+#[must_use]
+pub fn scan(path: &Path) -> Scan {
     #[allow(clippy::non_std_lazy_statics)]
     static RE: Lazy<Regex> = lazy_regex!(r"avtr_\w{8}-\w{4}-\w{4}-\w{4}-\w{12}");
+    #[allow(clippy::non_std_lazy_statics)]
+    static WORLD_RE: Lazy<Regex> = lazy_regex!(r"\[Behaviour\] Entering Room: (.+)");
+    #[allow(clippy::non_std_lazy_statics)]
+    static USER_RE: Lazy<Regex> = lazy_regex!(r"User Authenticated: .+ \((usr_[\w-]+)\)");
+    #[allow(clippy::non_std_lazy_statics)]
+    static SELECT_RE: Lazy<Regex> = lazy_regex!(r"avatars/(avtr_[\w-]+)/select");
 
     let Ok(file) = File::open(path) else {
-        return HashSet::new(); // Directory
+        return Scan::default(); // Directory
     };
 
     let mut reader = BufReader::new(file);
-    let mut avatar_ids = HashSet::new(); // Filter out duplicates
+    let mut scan = Scan::default();
+    let mut world = None;
     let mut buf = Vec::new();
 
     while reader.read_until(b'\n', &mut buf).unwrap_or(0) > 0 {
         let line = String::from_utf8_lossy(&buf);
+        if let Some(captures) = WORLD_RE.captures(&line) {
+            world = Some(captures[1].trim().to_string());
+        }
+        if let Some(captures) = USER_RE.captures(&line) {
+            scan.local_user = Some(captures[1].to_string());
+        }
+        if let Some(captures) = SELECT_RE.captures(&line) {
+            scan.own_avatars.insert(captures[1].to_string());
+        }
+        // Filter out duplicates
         for mat in RE.find_iter(&line) {
-            avatar_ids.insert(mat.as_str().to_string());
+            let worlds = scan.avatars.entry(mat.as_str().to_string()).or_default();
+            if let Some(world) = &world {
+                worlds.insert(world.clone());
+            }
         }
         buf.clear();
     }
 
-    avatar_ids
+    scan
 }
 
 /// # Print with colorized rainbow rows for separation
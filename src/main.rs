@@ -1,7 +1,7 @@
 #[macro_use]
 extern crate tracing;
 
-use std::{io::ErrorKind, sync::OnceLock};
+use std::{io::ErrorKind, sync::OnceLock, time::Duration};
 
 use anyhow::Result;
 use chrono::{Local, Offset};
@@ -9,7 +9,6 @@ use chrono::{Local, Offset};
 use crossterm::{execute, terminal::SetTitle};
 use derive_config::{ConfigError, DeriveTomlConfig};
 use notify::PollWatcher;
-use strum::IntoEnumIterator;
 use terminal_link::Link;
 use time::{UtcOffset, macros::format_description};
 use tokio::signal;
@@ -17,21 +16,27 @@ use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{EnvFilter, fmt::time::OffsetTime};
 use vrc_log::{
     CARGO_PKG_HOMEPAGE,
-    provider,
+    cli::Command,
     provider::{
         ProviderKind,
-        avtrdb::AvtrDBActor,
-        cutedb::CuteDBActor,
-        kitsunedb::KitsuneDBActor,
-        prelude::*,
+        registry::{self, Actor, Built, Context},
     },
     settings::Settings,
     vrchat::{VRCHAT_AMP_PATH, VRCHAT_LOW_PATH},
 };
+#[cfg(feature = "cache")]
+use vrc_log::{
+    cache::{BACKUP_DIRECTORY_NAME, Cache, CacheLock},
+    cli::DEFAULT_BACKUP_KEEP,
+};
 
 /* Watchers will stop working if they get dropped. */
 static WATCHERS: OnceLock<Vec<PollWatcher>> = OnceLock::new();
 
+/* The cache lock is released if it gets dropped. */
+#[cfg(feature = "cache")]
+static CACHE_LOCK: OnceLock<CacheLock> = OnceLock::new();
+
 #[allow(clippy::too_many_lines)]
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -54,13 +59,26 @@ async fn main() -> Result<()> {
         ))
         .init();
 
-    if vrc_log::check_for_updates().await? {
-        let text = "An update is available";
-        let link = Link::new(text, CARGO_PKG_HOMEPAGE);
-        info!("{link}");
+    let mut args = std::env::args().collect::<Vec<_>>();
+
+    // Stripped first, so it isn't passed to the game
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    args.retain(|arg| arg != "--dry-run");
+
+    match Command::parse(&args)? {
+        #[cfg(feature = "cache")]
+        Some(Command::Cache(command)) => return command.run().await,
+        #[cfg(feature = "cache")]
+        Some(Command::Queue) => return vrc_log::cli::print_queue().await,
+        #[cfg(feature = "cache")]
+        Some(Command::Search(text)) => return vrc_log::cli::print_search(&text).await,
+        Some(Command::Block(id)) => return vrc_log::cli::block(&id),
+        Some(Command::Unblock(id)) => return vrc_log::cli::unblock(&id),
+        Some(Command::Lookup(id)) => return vrc_log::cli::lookup(&id).await,
+        Some(Command::Providers(command)) => return command.run().await,
+        None => {}
     }
 
-    let mut args = std::env::args().collect::<Vec<_>>();
     let force_wizard = args.iter().any(|arg| arg == "--wizard" || arg == "-w");
     if force_wizard {
         args.retain(|arg| arg != "--wizard" && arg != "-w");
@@ -68,7 +86,7 @@ async fn main() -> Result<()> {
 
     let mut settings = if force_wizard {
         info!("Setup wizard requested via flag");
-        Settings::try_wizard()
+        Settings::try_wizard(Settings::load().ok())
             .await
             .expect("Failed to setup wizard")
     } else {
@@ -77,14 +95,14 @@ async fn main() -> Result<()> {
             Err(error) => match error {
                 ConfigError::Io(error) if error.kind() == ErrorKind::NotFound => {
                     info!("Welcome to VRC-LOG! Please follow the setup wizard");
-                    Settings::try_wizard()
+                    Settings::try_wizard(None)
                         .await
                         .expect("Failed to setup wizard")
                 }
                 error => {
                     error!("There was an error loading the settings: {error}");
                     error!("Most likely an update. Please follow the setup wizard");
-                    Settings::try_wizard()
+                    Settings::try_wizard(None)
                         .await
                         .expect("Failed to setup wizard")
                 }
@@ -92,13 +110,75 @@ async fn main() -> Result<()> {
         }
     };
 
-    if settings.providers.len() != ProviderKind::iter().count() {
+    if ProviderKind::iter().any(|kind| !settings.providers.contains_key(&kind)) {
         info!("Additional providers have been added, triggering setup wizard");
-        settings = Settings::try_wizard()
+        settings = Settings::try_wizard(Some(settings))
             .await
             .expect("Failed to setup wizard");
     }
 
+    settings.save()?;
+
+    // This is a little wonky, but effectively we are creating a controlled memory leak,
+    // which will be static for the rest of the programs runtime.
+    let settings: &'static Settings = Box::leak(Box::new(settings));
+
+    // Maintenance commands refuse to touch the cache while this is held
+    #[cfg(feature = "cache")]
+    if !dry_run {
+        let _ = CACHE_LOCK.set(CacheLock::try_acquire()?);
+    }
+
+    // Read only for dry runs, they neither migrate nor recover the cache
+    #[cfg(feature = "cache")]
+    let cache = if dry_run {
+        Cache::read_only().await?
+    } else {
+        Cache::new().await?
+    };
+
+    if dry_run {
+        info!("Dry run: nothing will be sent to providers or stored in the cache");
+        let (tx, rx) = flume::unbounded();
+        let _ = WATCHERS.set(vec![
+            vrc_log::watch(tx.clone(), VRCHAT_AMP_PATH.as_path(), 100)?,
+            vrc_log::watch(tx.clone(), VRCHAT_LOW_PATH.as_path(), 1_000)?,
+        ]);
+        vrc_log::launch_game(&args)?;
+
+        #[cfg(feature = "cache")]
+        return vrc_log::dry_run::watch(settings, &cache, (tx, rx)).await;
+        #[cfg(not(feature = "cache"))]
+        return vrc_log::dry_run::watch(settings, (tx, rx)).await;
+    }
+
+    let client = vrc_log::http::client(&settings.http)?;
+
+    // Through the same proxy and certificates as the providers, the settings are loaded by now
+    let timeout = Duration::from_secs(settings.http.timeout_secs);
+    match vrc_log::check_for_updates(&client, timeout).await {
+        Ok(true) => {
+            let text = "An update is available";
+            let link = Link::new(text, CARGO_PKG_HOMEPAGE);
+            info!("{link}");
+        }
+        Ok(false) => {}
+        Err(error) => warn!("Failed to check for updates: {error}"),
+    }
+
+    // Batched IDs survive crashes in the outbox, but only for providers that are still enabled
+    let Built { providers, actors } = registry::build(Context {
+        settings,
+        client,
+        #[cfg(feature = "cache")]
+        cache: Some(cache.clone()),
+    })
+    .await?;
+    let actors = actors
+        .into_iter()
+        .map(|Actor { name, run }| (name, tokio::spawn(run)))
+        .collect::<Vec<_>>();
+
     let (tx, rx) = flume::unbounded();
     let _ = WATCHERS.set(vec![
         vrc_log::watch(tx.clone(), VRCHAT_AMP_PATH.as_path(), 100)?,
@@ -113,46 +193,28 @@ async fn main() -> Result<()> {
         info!("Close Process Monitor manually to begin scans; it will reopen automatically.");
     }
 
-    settings.save()?;
     vrc_log::launch_game(&args)?;
 
-    // This is a little wonky, but effectively we are creating a controlled memory leak,
-    // which will be static for the rest of the programs runtime.
-    let settings: &'static Settings = Box::leak(Box::new(settings));
-
-    let (mut avtrdb_actor, avtrdb_sender) = AvtrDBActor::new(settings);
-    let (mut cutedb_actor, cutedb_sender) = CuteDBActor::new();
-    let (mut kitsunedb_actor, kitsunedb_sender) = KitsuneDBActor::new(settings);
-
-    let providers = settings
-        .providers
-        .iter()
-        .filter(|(_, enabled)| **enabled)
-        .map(|(provider, _)| match provider {
-            #[cfg(feature = "nsvr")]
-            ProviderKind::NSVR => provider!(NSVR::new(settings)),
-            #[cfg(feature = "paw")]
-            ProviderKind::PAW => provider!(Paw::new(settings)),
-            #[cfg(feature = "vrcdb")]
-            ProviderKind::VRCDB => provider!(VrcDB::new(settings)),
-            #[cfg(feature = "vrcwb")]
-            ProviderKind::VRCWB => provider!(VrcWB::new(settings)),
-            #[cfg(feature = "avtrdb")]
-            ProviderKind::AVTRDB => provider!(AvtrDB::new(avtrdb_sender.clone())),
-            #[cfg(feature = "avtrzip")]
-            ProviderKind::AVTRZIP => provider!(AvtrZip::default()),
-            #[cfg(feature = "kitsunedb")]
-            ProviderKind::KITSUNEDB => provider!(KitsuneDB::new(kitsunedb_sender.clone())),
-            #[cfg(feature = "cutedb")]
-            ProviderKind::CUTEDB => provider!(CuteDB::new(cutedb_sender.clone())),
-        })
-        .collect::<Vec<_>>();
-
-    let avtrdb_handle = tokio::spawn(async move { avtrdb_actor.run().await });
-    let cutedb_handle = tokio::spawn(async move { cutedb_actor.run().await });
-    let kitsunedb_handle = tokio::spawn(async move { kitsunedb_actor.run().await });
+    #[cfg(feature = "cache")]
+    if let Err(error) = cache
+        .backup_daily(
+            &VRCHAT_LOW_PATH.join(BACKUP_DIRECTORY_NAME),
+            DEFAULT_BACKUP_KEEP,
+        )
+        .await
+    {
+        warn!("Failed to back up the avatar cache: {error}");
+    }
 
-    let handle = tokio::spawn(vrc_log::process_avatars(providers, settings, (tx, rx)));
+    #[cfg(feature = "cache")]
+    let mut handle = tokio::spawn(vrc_log::process_avatars(
+        providers,
+        settings,
+        cache,
+        (tx, rx),
+    ));
+    #[cfg(not(feature = "cache"))]
+    let mut handle = tokio::spawn(vrc_log::process_avatars(providers, settings, (tx, rx)));
 
     let ctrl_c = async {
         signal::ctrl_c()
@@ -172,9 +234,9 @@ async fn main() -> Result<()> {
     // a system shutdown deliver CTRL_CLOSE/LOGOFF/SHUTDOWN_EVENT, not Ctrl+C.
     // Without catching these too, the vast majority of users (who just click
     // the X button rather than pressing Ctrl+C) would skip the graceful
-    // shutdown below entirely — Windows kills the process outright, silently
-    // discarding whatever avatars are still buffered in the KitsuneDB/avtrDB
-    // actors even though the local cache already marked them as sent.
+    // shutdown below entirely — Windows kills the process outright, leaving
+    // whatever avatars are still buffered in the KitsuneDB/avtrDB actors to
+    // the outbox until the next start.
     #[cfg(windows)]
     let terminate = async {
         let mut close =
@@ -197,34 +259,24 @@ async fn main() -> Result<()> {
     tokio::select! {
         () = ctrl_c => {},
         () = terminate => {},
+        result = &mut handle => match result {
+            Ok(Ok(())) => debug!("Avatar processing finished"),
+            Ok(Err(error)) => error!("Avatar processing failed: {error}"),
+            Err(error) => error!("Avatar processing panicked: {error}"),
+        },
     }
 
     // Graceful shutdown: stop pulling in new avatar IDs, then let each actor
     // drain and flush whatever it already has buffered before we exit — see
     // the matching change in provider/kitsunedb.rs and provider/avtrdb.rs.
+    // Their senders are dropped along with the aborted processing task
     handle.abort();
-    drop(avtrdb_sender);
-    drop(cutedb_sender);
-    drop(kitsunedb_sender);
 
     let shutdown_timeout = std::time::Duration::from_secs(90);
-    if tokio::time::timeout(shutdown_timeout, avtrdb_handle)
-        .await
-        .is_err()
-    {
-        error!("avtrDB actor did not finish flushing before shutdown timed out");
-    }
-    if tokio::time::timeout(shutdown_timeout, cutedb_handle)
-        .await
-        .is_err()
-    {
-        error!("CuteDB actor did not finish flushing before shutdown timed out");
-    }
-    if tokio::time::timeout(shutdown_timeout, kitsunedb_handle)
-        .await
-        .is_err()
-    {
-        error!("KitsuneDB actor did not finish flushing before shutdown timed out");
+    for (name, actor) in actors {
+        if tokio::time::timeout(shutdown_timeout, actor).await.is_err() {
+            error!("{name} actor did not finish flushing before shutdown timed out");
+        }
     }
 
     Ok(())
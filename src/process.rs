@@ -1,16 +1,29 @@
 use std::{collections::HashMap, sync::Arc};
 
+use crate::{
+    blocklist::Blocklist,
+    print_colorized,
+    provider::{Delivery, Outcome, Provider, health},
+};
 #[cfg(feature = "cache")]
-use crate::cache;
-use crate::{print_colorized, provider::Provider};
+use crate::{cache, queue};
 
 #[cfg(feature = "cache")]
 pub async fn process_with_cache<I: IntoIterator<Item = String>>(
     providers: Vec<Arc<Box<dyn Provider>>>,
     print_scanned: bool,
     cache: &cache::Cache,
+    blocklist: &Blocklist,
     avatar_ids: I,
 ) -> anyhow::Result<()> {
+    let avatar_ids = avatar_ids.into_iter().collect::<Vec<_>>();
+    let author_ids = if blocklist.has_authors() {
+        cache.author_ids(&avatar_ids).await?
+    } else {
+        HashMap::new()
+    };
+    let avatar_ids = blocklist.filter(avatar_ids, &author_ids);
+
     let checked_ids = cache
         .check_all_ids(avatar_ids)
         .await?
@@ -19,11 +32,9 @@ pub async fn process_with_cache<I: IntoIterator<Item = String>>(
 
     let (tx, rx) = flume::unbounded();
     let checked_ids = Arc::new(checked_ids);
-    let mut base_bits = HashMap::new();
 
-    for (id, provider_bits) in checked_ids.iter() {
-        base_bits.insert(id.clone(), *provider_bits);
-        if print_scanned {
+    if print_scanned {
+        for (id, _) in checked_ids.iter() {
             print_colorized(id);
         }
     }
@@ -32,78 +43,139 @@ pub async fn process_with_cache<I: IntoIterator<Item = String>>(
         let provider = provider.clone();
         let tx_clone = tx.clone();
         let checked_ids = checked_ids.clone();
+        let cache = cache.clone();
         tokio::spawn(async move {
-            let kind = provider.kind();
-            let kind_bit = kind as u32;
-            for (id, provider_bits) in checked_ids.iter() {
-                if provider_bits & kind_bit != 0 {
-                    continue;
-                }
-                match provider.send_avatar_id(id).await {
-                    Ok(unique) => {
+            let kind = provider.name();
+            let kind_bit = provider.bit();
+            let unsent_ids = checked_ids
+                .iter()
+                .filter(|(_, provider_bits)| provider_bits & kind_bit == 0)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+
+            let results = provider.send_avatar_ids(&unsent_ids).await;
+            let mut pending = Vec::new();
+            for (id, result) in unsent_ids.iter().zip(results) {
+                match result {
+                    Ok(Delivery::Confirmed(outcome)) => {
                         let _ = tx_clone.send_async((id.clone(), kind_bit)).await;
-                        if unique {
-                            print_colorized(format!("{id} You were first to send this to {kind}!"));
-                        } else {
-                            debug!("vrcx://avatar/{id} was already found on {kind}");
+                        print_delivery(id, &kind, &outcome);
+                        if let Some(metadata) = outcome.metadata
+                            && let Err(error) = cache.store_metadata(id, metadata).await
+                        {
+                            error!("Failed to store metadata of {id}: {error}");
                         }
                     }
+                    Ok(Delivery::Pending(receipt)) => pending.push((id.clone(), receipt)),
                     Err(err) => {
-                        error!("^ Failed to submit to {kind}: {err}");
+                        if health::is_down(&err) {
+                            debug!("{id} queued while {kind} is down");
+                        } else {
+                            error!("^ Failed to submit to {kind}: {err}");
+                        }
+                        if let Err(error) = queue::enqueue(&cache, id, kind_bit, &err).await {
+                            error!("Failed to queue {id} for {kind}: {error}");
+                        }
                     }
                 }
             }
+
+            // Batches can take minutes to flush, so don't hold up the caller
+            drop(tx_clone);
+
+            for (id, receipt) in pending {
+                // A dropped receipt means the flush failed, the outbox keeps the ID
+                let Ok(unique) = receipt.await else {
+                    debug!("{id} was not delivered to {kind} yet");
+                    continue;
+                };
+                if let Err(error) = cache.add_provider_bit([&id], kind_bit).await {
+                    error!("Failed to mark {id} as sent to {kind}: {error}");
+                }
+                print_receipt(&id, &kind, unique);
+            }
         });
     }
 
     drop(tx);
 
-    let mut updated_bits = HashMap::new();
+    let mut sent_ids = HashMap::<u32, Vec<String>>::new();
 
     while let Ok((id, kind_bit)) = rx.recv_async().await {
-        let entry = updated_bits.entry(id).or_insert(0);
-        *entry |= kind_bit;
+        sent_ids.entry(kind_bit).or_default().push(id);
     }
 
-    let mut buffer = Vec::new();
-    for (id, bits) in base_bits {
-        if let Some(add_bits) = updated_bits.get(&id) {
-            buffer.push((id, bits | add_bits));
-        }
+    // Added rather than overwritten, batches may have confirmed their bits in the meantime
+    for (kind_bit, ids) in sent_ids {
+        cache.add_provider_bit(ids, kind_bit).await?;
     }
 
-    cache.store_avatar_ids_with_providers(buffer).await
+    Ok(())
+}
+
+/// # Batches that were only partly new don't say which of their avatars were
+fn print_receipt(id: &str, kind: &str, unique: Option<bool>) {
+    if let Some(unique) = unique {
+        print_delivery(id, kind, &unique.into());
+    } else {
+        debug!("vrcx://avatar/{id} was sent to {kind}");
+    }
+}
+
+fn print_delivery(id: &str, kind: &str, outcome: &Outcome) {
+    let name = outcome
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.name.as_deref())
+        .map(|name| format!(" ({name})"))
+        .unwrap_or_default();
+
+    if outcome.unique {
+        print_colorized(format!("{id}{name} You were first to send this to {kind}!"));
+    } else {
+        debug!("vrcx://avatar/{id}{name} was already found on {kind}");
+    }
 }
 
 #[cfg(not(feature = "cache"))]
 pub async fn process_without_cache<I: IntoIterator<Item = String>>(
     providers: Vec<Arc<Box<dyn Provider>>>,
     print_scanned: bool,
+    blocklist: &Blocklist,
     avatar_ids: I,
 ) -> anyhow::Result<()> {
-    for avatar_id in avatar_ids {
-        if print_scanned {
-            print_colorized(&avatar_id);
+    // Authors are only known from cached metadata
+    let avatar_ids = blocklist.filter(avatar_ids, &HashMap::new());
+    if print_scanned {
+        for avatar_id in &avatar_ids {
+            print_colorized(avatar_id);
         }
+    }
 
-        // Collect all provider futures for this avatar_id
-        let futures = providers
-            .iter()
-            .map(|provider| provider.send_avatar_id(&avatar_id));
-        let results = futures::future::join_all(futures).await;
+    // Every provider gets the whole chunk at once
+    let futures = providers
+        .iter()
+        .map(|provider| provider.send_avatar_ids(&avatar_ids));
+    let results = futures::future::join_all(futures).await;
 
-        for (provider, result) in providers.iter().zip(results) {
-            let kind = provider.kind();
+    for (provider, results) in providers.iter().zip(results) {
+        let kind = provider.name();
+        for (avatar_id, result) in avatar_ids.iter().zip(results) {
             match result {
-                Ok(unique) => {
-                    if unique {
-                        print_colorized(format!("{id} You were first to send this to {kind}!"));
-                    } else {
-                        debug!("vrcx://avatar/{id} was already found on {kind}");
-                    }
+                Ok(Delivery::Confirmed(outcome)) => print_delivery(avatar_id, &kind, &outcome),
+                Ok(Delivery::Pending(receipt)) => {
+                    let (avatar_id, kind) = (avatar_id.clone(), kind.clone());
+                    tokio::spawn(async move {
+                        if let Ok(unique) = receipt.await {
+                            print_receipt(&avatar_id, &kind, unique);
+                        }
+                    });
+                }
+                Err(error) if health::is_down(&error) => {
+                    debug!("Skipped {avatar_id} while {kind} is down");
                 }
                 Err(error) => {
-                    error!("^ Failed to submit to {kind}: {error}");
+                    error!("^ Failed to submit {avatar_id} to {kind}: {error}");
                 }
             }
         }
@@ -118,11 +190,12 @@ mod tests {
 
     use anyhow::Result;
     use async_trait::async_trait;
-    use strum::IntoEnumIterator;
     use tokio::sync::Mutex;
 
     use super::*;
     use crate::provider::ProviderKind;
+    #[cfg(feature = "cache")]
+    use crate::provider::Submission;
 
     #[derive(Clone)]
     struct MockProvider {
@@ -157,7 +230,65 @@ mod tests {
 
         async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
             self.sent.lock().await.push(avatar_id.to_string());
-            Ok(self.succeed)
+            // Ok(false) means the provider already had it, only errors are queued for a retry
+            if !self.succeed {
+                anyhow::bail!("{} is unavailable", self.kind);
+            }
+
+            Ok(true)
+        }
+    }
+
+    /// Holds the submissions like a batching actor until the test flushes them
+    #[cfg(feature = "cache")]
+    #[derive(Clone, Default)]
+    struct BatchingProvider {
+        submissions: Arc<Mutex<Vec<Submission>>>,
+    }
+
+    #[cfg(feature = "cache")]
+    #[async_trait]
+    impl Provider for BatchingProvider {
+        fn kind(&self) -> ProviderKind {
+            ProviderKind::AVTRDB
+        }
+
+        async fn send_avatar_id(&self, _avatar_id: &str) -> Result<bool> {
+            unreachable!("batched avatars are submitted")
+        }
+
+        async fn submit_avatar_id(&self, avatar_id: &str) -> Result<Delivery> {
+            let (submission, receipt) = Submission::new(avatar_id);
+            self.submissions.lock().await.push(submission);
+
+            Ok(Delivery::Pending(receipt))
+        }
+    }
+
+    /// Records the chunks it receives, like a provider with a bulk endpoint
+    #[cfg(feature = "cache")]
+    #[derive(Clone, Default)]
+    struct BulkProvider {
+        chunks: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    #[cfg(feature = "cache")]
+    #[async_trait]
+    impl Provider for BulkProvider {
+        fn kind(&self) -> ProviderKind {
+            ProviderKind::VRCWB
+        }
+
+        async fn send_avatar_id(&self, _avatar_id: &str) -> Result<bool> {
+            unreachable!("chunks are sent in bulk")
+        }
+
+        async fn send_avatar_ids(&self, avatar_ids: &[String]) -> Vec<Result<Delivery>> {
+            self.chunks.lock().await.push(avatar_ids.to_vec());
+            avatar_ids
+                .iter()
+                .map(|_| Ok(Delivery::Confirmed(true.into())))
+                .collect()
         }
     }
 
@@ -171,13 +302,15 @@ mod tests {
 
         let avatar_ids = vec!["avtr_1".to_string(), "avtr_2".to_string()];
 
-        process_without_cache(providers, false, avatar_ids).await?;
+        process_without_cache(providers, false, &Blocklist::default(), avatar_ids).await?;
 
         let sent_a = sent_a.lock().await;
         let sent_b = sent_b.lock().await;
 
         assert_eq!(sent_a.len(), 2);
         assert_eq!(sent_b.len(), 2);
+        drop(sent_a);
+        drop(sent_b);
 
         Ok(())
     }
@@ -195,12 +328,12 @@ mod tests {
         // Pre-seed cache: AVTRDB already handled this avatar
         cache
             .store_avatar_ids_with_providers(
-                vec![("avtr_1", ProviderKind::AVTRDB as u32)].into_iter(),
+                vec![("avtr_1", ProviderKind::AVTRDB.bit())].into_iter(),
             )
             .await?;
 
         let avatar_ids = vec!["avtr_1".to_string()];
-        process_with_cache(providers, true, &cache, avatar_ids).await?;
+        process_with_cache(providers, true, &cache, &Blocklist::default(), avatar_ids).await?;
 
         let sent_a = sent_a.lock().await;
         let sent_b = sent_b.lock().await;
@@ -213,6 +346,34 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "cache")]
+    #[tokio::test]
+    async fn process_with_cache_sends_unsent_ids_in_one_chunk() -> Result<()> {
+        let provider = BulkProvider::default();
+        let providers = vec![Arc::new(Box::new(provider.clone()) as Box<dyn Provider>)];
+
+        let cache = cache::Cache::new_in_memory().await?;
+        let bit = ProviderKind::VRCWB.bit();
+        cache
+            .store_avatar_ids_with_providers(vec![("avtr_1", bit)].into_iter())
+            .await?;
+
+        let avatar_ids = ["avtr_1", "avtr_2", "avtr_3"].map(String::from);
+        process_with_cache(providers, false, &cache, &Blocklist::default(), avatar_ids).await?;
+
+        let mut chunks = provider.chunks.lock().await.clone();
+        assert_eq!(chunks.len(), 1, "the chunk must be sent at once");
+        chunks[0].sort();
+        assert_eq!(chunks[0], ["avtr_2", "avtr_3"]);
+
+        let result = cache
+            .check_all_ids(["avtr_2", "avtr_3"].map(String::from).into_iter())
+            .await?;
+        assert!(result.values().all(|bits| bits & bit != 0));
+
+        Ok(())
+    }
+
     #[cfg(feature = "cache")]
     #[tokio::test]
     async fn process_with_cache_updates_provider_bits_on_success() -> Result<()> {
@@ -223,14 +384,14 @@ mod tests {
 
         let avatar_ids = vec!["avtr_42".to_string()];
 
-        process_with_cache(providers, true, &cache, avatar_ids).await?;
+        process_with_cache(providers, true, &cache, &Blocklist::default(), avatar_ids).await?;
 
         let result = cache
             .check_all_ids(vec!["avtr_42".to_string()].into_iter())
             .await?;
 
         let bits = result["avtr_42"];
-        assert_ne!(bits & ProviderKind::PAW as u32, 0);
+        assert_ne!(bits & ProviderKind::PAW.bit(), 0);
 
         Ok(())
     }
@@ -245,7 +406,7 @@ mod tests {
 
         let avatar_ids = vec!["avtr_fail".to_string()];
 
-        process_with_cache(providers, true, &cache, avatar_ids).await?;
+        process_with_cache(providers, true, &cache, &Blocklist::default(), avatar_ids).await?;
 
         let sent = sent.lock().await;
         assert_eq!(sent.len(), 1, "Provider should still be called");
@@ -257,6 +418,54 @@ mod tests {
 
         assert_eq!(result["avtr_fail"], 0, "Cache must not update on failure");
 
+        let queue = cache.retry_queue().await?;
+        assert_eq!(queue.len(), 1, "Failure must be queued for retry");
+        assert_eq!(queue[0].provider_bit, ProviderKind::VRCDB.bit());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "cache")]
+    #[tokio::test]
+    async fn pending_delivery_updates_cache_once_confirmed() -> Result<()> {
+        let provider = BatchingProvider::default();
+        let providers: Vec<Arc<Box<dyn Provider>>> = vec![Arc::new(Box::new(provider.clone()))];
+
+        let cache = cache::Cache::new_in_memory().await?;
+        let avatar_ids = vec!["avtr_flushed".to_string(), "avtr_failed".to_string()];
+        process_with_cache(
+            providers,
+            true,
+            &cache,
+            &Blocklist::default(),
+            avatar_ids.clone(),
+        )
+        .await?;
+
+        let result = cache.check_all_ids(avatar_ids.clone()).await?;
+        assert_eq!(result["avtr_flushed"], 0, "Cache must wait for the flush");
+
+        // Wait for the spawned task to hand over both avatars
+        while provider.submissions.lock().await.len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        for submission in provider.submissions.lock().await.drain(..) {
+            if submission.avatar_id == "avtr_flushed" {
+                submission.receipt.send(Some(true)).unwrap();
+            }
+        }
+
+        let bit = ProviderKind::AVTRDB.bit();
+        let mut result = cache.check_all_ids(avatar_ids.clone()).await?;
+        while result["avtr_flushed"] & bit == 0 {
+            tokio::task::yield_now().await;
+            result = cache.check_all_ids(avatar_ids.clone()).await?;
+        }
+        assert_eq!(
+            result["avtr_failed"], 0,
+            "Cache must not update on a failed flush"
+        );
+
         Ok(())
     }
 
@@ -266,21 +475,17 @@ mod tests {
 
         for (i, a) in kinds.iter().enumerate() {
             for b in kinds.iter().skip(i + 1) {
-                assert_eq!(
-                    (*a as u32) & (*b as u32),
-                    0,
-                    "ProviderKind bits must not overlap"
-                );
+                assert_eq!(a.bit() & b.bit(), 0, "ProviderKind bits must not overlap");
             }
         }
     }
 
     #[test]
     fn provider_bit_check_logic() {
-        let bits = ProviderKind::AVTRDB as u32 | ProviderKind::NSVR as u32;
+        let bits = ProviderKind::AVTRDB.bit() | ProviderKind::NSVR.bit();
 
-        assert_ne!(bits & ProviderKind::AVTRDB as u32, 0);
-        assert_ne!(bits & ProviderKind::NSVR as u32, 0);
-        assert_eq!(bits & ProviderKind::PAW as u32, 0);
+        assert_ne!(bits & ProviderKind::AVTRDB.bit(), 0);
+        assert_ne!(bits & ProviderKind::NSVR.bit(), 0);
+        assert_eq!(bits & ProviderKind::PAW.bit(), 0);
     }
 }
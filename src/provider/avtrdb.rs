@@ -2,82 +2,82 @@ use std::time::Duration;
 
 use anyhow::{Result, bail};
 use colored::{Color, Colorize};
-use flume::{Receiver, Sender};
-use reqwest::{Client, StatusCode, Url};
+use flume::Sender;
+use futures::future::BoxFuture;
+use reqwest::{Client, Method, StatusCode, Url};
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{Value, json};
 use terminal_link::Link;
-use tokio::time::Instant;
 
 use crate::{
     USER_AGENT,
-    provider::{Provider, ProviderKind},
+    provider::{
+        Delivery,
+        Provider,
+        ProviderKind,
+        Submission,
+        batch::{self, BatchActor, Flush},
+        check::{Probe, TEST_ATTRIBUTION, TEST_AVATAR_ID},
+        health::Guarded,
+        limit::RateLimiter,
+        preview::Payload,
+        registry::{Built, Capabilities, Context, Descriptor},
+        retry::RetryPolicy,
+    },
     settings::Settings,
 };
 
 const INGEST_BASE_URL: &str = "https://api.avtrdb.com/v3/";
 
-const DISCORD_URL: &str = "https://avtrdb.com/discord";
+/// VRCX search API, asked by `vrc-log lookup`
+pub const SEARCH_URL: &str = "https://api.avtrdb.com/v1/avatar/search/vrcx";
 
-const FLUSH_INTERVAL: Duration = Duration::from_mins(2);
-const FLUSH_THRESHOLD: usize = 100;
+pub const DESCRIPTOR: Descriptor = Descriptor {
+    kind: ProviderKind::AVTRDB,
+    id: "AVTRDB",
+    aliases: &[],
+    name: "avtrDB - Avatar Search",
+    enabled_by_default: true,
+    build,
+    capabilities: Capabilities {
+        probe:      Some(probe),
+        payloads:   Some(payloads),
+        search_url: Some(SEARCH_URL),
+    },
+};
 
-const RETRY_LIMIT: usize = 5;
+const DISCORD_URL: &str = "https://avtrdb.com/discord";
 
 const LOG_NAME: &str = "avtrDB";
 
 pub struct AvtrDB {
-    sender: Sender<String>,
+    sender: Sender<Submission>,
 }
 
-pub struct AvtrDBActor<'s> {
-    settings:       &'s Settings,
-    client:         Client,
-    buffer:         Vec<String>,
-    base_url:       String,
-    channel:        Receiver<String>,
-    flush_interval: Duration,
-    last_flush:     Instant,
+/// # The ingest API batches are flushed to
+pub struct Ingest<'s> {
+    settings: &'s Settings,
+    client:   Client,
+    base_url: String,
+    retry:    RetryPolicy,
+    limiter:  RateLimiter,
+    timeout:  Duration,
 }
 
-impl<'s> AvtrDBActor<'s> {
-    /// # Errors
-    /// Will return `Err` if anything errors
-    pub async fn run(&mut self) -> anyhow::Result<()> {
-        while let Ok(id) = self.channel.recv_async().await {
-            self.buffer.push(id);
-
-            if self.buffer.len() >= FLUSH_THRESHOLD
-                || self.last_flush.elapsed() > self.flush_interval
-            {
-                match self.flush_buffer().await {
-                    Ok(()) => (),
-                    Err(err) => error!("[{LOG_NAME}]: Failed to flush buffer: {err}"),
-                }
-            }
-        }
-
-        // The channel only closes once every sender has been dropped, which is
-        // how the shutdown path in main.rs signals "no more avatars are coming."
-        // Without this, anything still sitting in the buffer here (below the
-        // flush threshold/interval) would be silently discarded on exit, even
-        // though the local cache already marked those IDs as sent.
-        if !self.buffer.is_empty()
-            && let Err(err) = self.flush_buffer().await
-        {
-            error!("[{LOG_NAME}]: Failed to flush buffer on shutdown: {err}");
-        }
-
-        Ok(())
-    }
+pub type AvtrDBActor<'s> = BatchActor<Ingest<'s>>;
 
+impl<'s> BatchActor<Ingest<'s>> {
     #[must_use]
-    pub fn new(settings: &'s Settings) -> (Self, Sender<String>) {
-        Self::new_with_base_url_and_flush_interval(
-            settings,
-            FLUSH_THRESHOLD,
-            INGEST_BASE_URL.to_string(),
-            FLUSH_INTERVAL,
+    pub fn new(settings: &'s Settings, client: Client) -> (Self, Sender<Submission>) {
+        let provider_settings = settings.provider_settings(ProviderKind::AVTRDB);
+        let base_url = settings.url(ProviderKind::AVTRDB, INGEST_BASE_URL);
+        let ingest = Ingest::new(settings, client, base_url);
+
+        Self::from_flush(
+            LOG_NAME,
+            ingest,
+            provider_settings.flush_threshold,
+            provider_settings.flush_interval(),
         )
     }
 
@@ -87,75 +87,71 @@ impl<'s> AvtrDBActor<'s> {
         capacity: usize,
         base_url: String,
         flush_interval: Duration,
-    ) -> (Self, Sender<String>) {
-        let (tx, rx) = flume::bounded(capacity);
-
-        (
-            Self {
-                settings,
-                client: Client::default(),
-                buffer: Vec::new(),
-                base_url,
-                channel: rx,
-                flush_interval,
-                last_flush: Instant::now(),
-            },
-            tx,
-        )
+    ) -> (Self, Sender<Submission>) {
+        let ingest = Ingest::new(settings, Client::default(), base_url);
+        Self::from_flush(LOG_NAME, ingest, capacity, flush_interval)
     }
+}
 
-    /// # Errors
-    /// Will return `Err` if anything errors
-    pub async fn flush_buffer(&mut self) -> anyhow::Result<()> {
-        let json = json!({
-            "avatar_ids":  self.buffer,
-            "attribution": self.settings.attribution.get_user_id().await,
-        });
+impl<'s> Ingest<'s> {
+    fn new(settings: &'s Settings, client: Client, base_url: String) -> Self {
+        Self {
+            settings,
+            client,
+            base_url,
+            retry: settings
+                .provider_settings(ProviderKind::AVTRDB)
+                .retry
+                .into(),
+            limiter: RateLimiter::new(settings.rate_limit(ProviderKind::AVTRDB)),
+            timeout: settings.timeout(ProviderKind::AVTRDB),
+        }
+    }
+}
 
-        let mut current_try = 0;
-        let mut success = false;
-        let mut ticket = None;
-        while current_try < RETRY_LIMIT && !success {
-            if current_try != 0 {
-                tokio::time::sleep(Duration::from_secs(10)).await;
-            }
-            debug!("[{LOG_NAME}] (try {current_try}) Sending {json:#?}");
-            current_try += 1; // incrementing here to be able to cont later
-            let response: reqwest::Response = self
-                .client
-                .post(Url::parse(&self.base_url)?.join("avatar/ingest")?)
+#[async_trait::async_trait]
+impl Flush for Ingest<'_> {
+    async fn flush(&self, avatar_ids: &[String]) -> Result<Option<bool>> {
+        let json = body(avatar_ids, &self.settings.attribution.get_user_id().await);
+
+        debug!("[{LOG_NAME}] Sending {json:#?}");
+        let url = ingest_url(&self.base_url)?;
+        let request = || {
+            self.client
+                .post(url.clone())
                 .header("User-Agent", USER_AGENT)
                 .json(&json)
-                .timeout(Duration::from_secs(5))
-                .send()
-                .await?;
+                .timeout(self.timeout)
+        };
+        let response = self.retry.send(LOG_NAME, &self.limiter, request).await?;
 
-            let status = response.status();
-            let text = response.text().await?;
-            success = match status {
-                StatusCode::OK => true, // the API checks for things already enqueued
-                StatusCode::TOO_MANY_REQUESTS => {
-                    warn!("[{LOG_NAME}] 429 Rate Limit, trying again in 10 seconds");
-                    false
-                }
-                _ => {
-                    error!("[{LOG_NAME}] Unknown Error: {status} | {text}");
-                    false
-                }
-            };
-            debug!("[{LOG_NAME}] {status} | {text}");
-            if !success {
-                continue;
-            }
-            let data = serde_json::from_str::<IngestResponse>(&text)?;
-            ticket = Some(data.ticket);
+        let status = response.status();
+        let text = response.text().await?;
+        debug!("[{LOG_NAME}] {status} | {text}");
+        if status != StatusCode::OK {
+            bail!("[{LOG_NAME}] Unknown Error: {status} | {text}");
         }
 
-        if current_try >= RETRY_LIMIT {
-            bail!("[{LOG_NAME}] Failed after {current_try} retries to flush buffer, aborting");
-        }
+        // OK means the batch was accepted, the API checks for things already enqueued
+        let ingest = serde_json::from_str::<IngestResponse>(&text).ok();
+        let unique = ingest.as_ref().and_then(|data| {
+            batch::enqueued(data.avatars_enqueued, data.invalid_ids, avatar_ids.len())
+        });
+
+        if let Some(IngestResponse {
+            avatars_enqueued,
+            invalid_ids,
+            ticket,
+        }) = ingest
+        {
+            info!(
+                "[{LOG_NAME}] {avatars_enqueued} of {} avatars were new",
+                avatar_ids.len()
+            );
+            if invalid_ids > 0 {
+                warn!("[{LOG_NAME}] {invalid_ids} avatar IDs were rejected as invalid");
+            }
 
-        if let Some(ticket) = ticket {
             let check_status_url = format!("https://avtrdb.com/check_ticket_status/{ticket}");
             let link = Link::new("here", &check_status_url)
                 .to_string()
@@ -170,22 +166,78 @@ impl<'s> AvtrDBActor<'s> {
             );
         }
 
-        self.buffer.clear();
-        self.last_flush = Instant::now();
-
-        Ok(())
+        Ok(unique)
     }
 }
 
+/// # Batches survive crashes in the outbox when there's a cache
+fn build(context: Context) -> BoxFuture<'static, Result<Built>> {
+    Box::pin(async move {
+        let (actor, sender) = AvtrDBActor::new(context.settings, context.client);
+        let provider = crate::provider!(AvtrDB::new(sender));
+        let provider = Guarded::from_settings(context.settings, provider);
+        let mut actor = actor.with_breaker(provider.breaker());
+        #[cfg(feature = "cache")]
+        if let Some(cache) = &context.cache {
+            actor = actor
+                .with_outbox(cache.outbox(ProviderKind::AVTRDB))
+                .await?;
+        }
+
+        Ok(Built::guarded(provider).with_actor(LOG_NAME, async move { actor.run().await }))
+    })
+}
+
+fn ingest_url(base_url: &str) -> Result<Url> {
+    Ok(Url::parse(base_url)?.join("avatar/ingest")?)
+}
+
+/// # Where the provider sends batches, shown by `--dry-run` and probed by the check
+///
+/// Joined like the batches' own URL, a base URL without a trailing `/` loses its last segment.
+fn ingest_endpoint(settings: &Settings) -> String {
+    let base_url = settings.url(ProviderKind::AVTRDB, INGEST_BASE_URL);
+    ingest_url(&base_url).map_or_else(|_| format!("{base_url}avatar/ingest"), String::from)
+}
+
+fn body(avatar_ids: &[String], attribution: &str) -> Value {
+    json!({ "avatar_ids": avatar_ids, "attribution": attribution })
+}
+
+/// # One request per `flush_threshold` avatar IDs
+#[must_use]
+pub fn payloads(settings: &Settings, avatar_ids: &[String], attribution: &str) -> Vec<Payload> {
+    let url = ingest_endpoint(settings);
+    let flush_threshold = settings
+        .provider_settings(ProviderKind::AVTRDB)
+        .flush_threshold;
+    avatar_ids
+        .chunks(flush_threshold.max(1))
+        .map(|chunk| Payload::new(Method::POST, &url).json(&body(chunk, attribution)))
+        .collect()
+}
+
+/// # Ingests `TEST_AVATAR_ID`, which is already known
+#[must_use]
+pub fn probe(settings: &Settings) -> Probe {
+    let json = body(&[TEST_AVATAR_ID.to_string()], TEST_ATTRIBUTION);
+    Probe::new(
+        ProviderKind::AVTRDB,
+        Method::POST,
+        ingest_endpoint(settings),
+    )
+    .json(&json)
+    .parses(|text| serde_json::from_str::<IngestResponse>(text).is_ok())
+}
+
 impl AvtrDB {
     #[must_use]
-    pub const fn new(sender: Sender<String>) -> Self {
+    pub const fn new(sender: Sender<Submission>) -> Self {
         Self { sender }
     }
 }
 
 #[derive(Debug, Deserialize)]
-#[allow(dead_code)]
 struct IngestResponse {
     avatars_enqueued: u64,
     invalid_ids:      u64,
@@ -199,7 +251,15 @@ impl Provider for AvtrDB {
     }
 
     async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
-        self.sender.send_async(avatar_id.to_string()).await?;
-        Ok(true)
+        let (submission, receipt) = Submission::new(avatar_id);
+        self.sender.send_async(submission).await?;
+        // Unknown counts as not new, `submit_avatar_id` tells the two apart
+        Ok(receipt.await?.unwrap_or_default())
+    }
+
+    async fn submit_avatar_id(&self, avatar_id: &str) -> Result<Delivery> {
+        let (submission, receipt) = Submission::new(avatar_id);
+        self.sender.send_async(submission).await?;
+        Ok(Delivery::Pending(receipt))
     }
 }
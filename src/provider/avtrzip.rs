@@ -2,20 +2,71 @@ use std::time::Duration;
 
 use anyhow::{bail, Result};
 use async_trait::async_trait;
-use reqwest::{Client, StatusCode};
+use futures::future::BoxFuture;
+use reqwest::{Client, Method, StatusCode};
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 
 use crate::{
-    provider::{Provider, ProviderKind},
+    provider::{
+        check::{Probe, TEST_AVATAR_ID},
+        limit::RateLimiter,
+        preview::Payload,
+        registry::{Built, Capabilities, Context, Descriptor},
+        retry::RetryPolicy,
+        Provider,
+        ProviderKind,
+    },
+    settings::Settings,
     USER_AGENT,
 };
 
-const URL: &str = "http://api.avtr.zip/v1/avatars/push";
+const URL: &str = "https://api.avtr.zip/v1/avatars/push";
+
+/// VRCX search API, asked by `vrc-log lookup`
+pub const SEARCH_URL: &str = "https://vrcx.avtr.zip";
+
+pub const DESCRIPTOR: Descriptor = Descriptor {
+    kind:               ProviderKind::AVTRZIP,
+    id:                 "AVTRZIP",
+    aliases:            &[],
+    name:               "avtr․zip - Advanced Avatar Search",
+    enabled_by_default: true,
+    build,
+    capabilities:       Capabilities {
+        probe:      Some(probe),
+        payloads:   Some(|settings, avatar_ids, _| payloads(settings, avatar_ids)),
+        search_url: Some(SEARCH_URL),
+    },
+};
 
-#[derive(Default)]
 pub struct AvtrZip {
-    client: Client,
+    client:  Client,
+    url:     String,
+    retry:   RetryPolicy,
+    limiter: RateLimiter,
+    timeout: Duration,
+}
+
+impl AvtrZip {
+    #[must_use]
+    pub fn new(settings: &Settings, client: Client) -> Self {
+        Self::new_with_url(settings, client, settings.url(ProviderKind::AVTRZIP, URL))
+    }
+
+    #[must_use]
+    pub fn new_with_url(settings: &Settings, client: Client, url: String) -> Self {
+        Self {
+            client,
+            url,
+            retry: settings
+                .provider_settings(ProviderKind::AVTRZIP)
+                .retry
+                .into(),
+            limiter: RateLimiter::new(settings.rate_limit(ProviderKind::AVTRZIP)),
+            timeout: settings.timeout(ProviderKind::AVTRZIP),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,26 +83,61 @@ enum AvtrZipResponse {
     },
 }
 
+fn build(context: Context) -> BoxFuture<'static, Result<Built>> {
+    Box::pin(async move {
+        let provider = AvtrZip::new(context.settings, context.client);
+        Ok(Built::provider(context.settings, crate::provider!(provider)))
+    })
+}
+
+fn body(avatar_id: &str) -> Value {
+    json!({ "avatarId": avatar_id })
+}
+
+/// # One request per avatar ID, without attribution
+#[must_use]
+pub fn payloads(settings: &Settings, avatar_ids: &[String]) -> Vec<Payload> {
+    let url = settings.url(ProviderKind::AVTRZIP, URL);
+    avatar_ids
+        .iter()
+        .map(|avatar_id| Payload::new(Method::POST, &url).json(&body(avatar_id)))
+        .collect()
+}
+
+/// # Submits `TEST_AVATAR_ID`, which is already known
+#[must_use]
+pub fn probe(settings: &Settings) -> Probe {
+    let json = body(TEST_AVATAR_ID);
+    let url = settings.url(ProviderKind::AVTRZIP, URL);
+    Probe::new(ProviderKind::AVTRZIP, Method::POST, url)
+        .json(&json)
+        .parses(|text| serde_json::from_str::<AvtrZipResponse>(text).is_ok())
+}
+
 #[async_trait]
 impl Provider for AvtrZip {
     fn kind(&self) -> ProviderKind {
         ProviderKind::AVTRZIP
     }
 
+    fn max_concurrency(&self) -> usize {
+        self.limiter.max_concurrency()
+    }
+
     async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
         let kind = self.kind();
-        let json = json!({ "avatarId": avatar_id });
+        let json = body(avatar_id);
 
         debug!("[{kind}] Sending {json:#?}");
 
-        let response = self
-            .client
-            .post(URL)
-            .header("User-Agent", USER_AGENT)
-            .json(&json)
-            .timeout(Duration::from_secs(3))
-            .send()
-            .await?;
+        let request = || {
+            self.client
+                .post(&self.url)
+                .header("User-Agent", USER_AGENT)
+                .json(&json)
+                .timeout(self.timeout)
+        };
+        let response = self.retry.send(kind, &self.limiter, request).await?;
 
         let status = response.status();
         let text = response.text().await?;
@@ -65,11 +151,6 @@ impl Provider for AvtrZip {
                     AvtrZipResponse::Error { error } => bail!("[{kind}] {error}"),
                 }
             }
-            StatusCode::TOO_MANY_REQUESTS => {
-                warn!("[{kind}] 429 Rate Limit, Please Wait 10 seconds...");
-                tokio::time::sleep(Duration::from_secs(10)).await;
-                Box::pin(self.send_avatar_id(avatar_id)).await
-            }
             _ => bail!("[{kind}] {status} | {text}"),
         }
     }
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use flume::{Receiver, Sender};
+use tokio::time::Instant;
+
+#[cfg(feature = "cache")]
+use crate::cache::Outbox;
+use crate::provider::{Submission, buffer::Buffer, health::Breaker};
+
+/// # The request a batching provider sends its buffered avatar IDs in
+#[async_trait]
+pub trait Flush: Send + Sync {
+    /// # Send the avatar IDs in one request
+    ///
+    /// Some(true): New/Unique | Some(false): Duplicate/Existing | None: Not known per ID.
+    ///
+    /// # Errors
+    /// Will return `Err` if the request errors or the provider rejects the batch
+    async fn flush(&self, avatar_ids: &[String]) -> Result<Option<bool>>;
+}
+
+/// # Whether the avatars of a batch were new, from how many of them the provider enqueued
+///
+/// The count doesn't say which IDs were new, so it's only known when all or none of them were.
+#[must_use]
+pub fn enqueued(avatars_enqueued: u64, invalid_ids: u64, batch_size: usize) -> Option<bool> {
+    match usize::try_from(avatars_enqueued) {
+        Ok(count) if count == batch_size => Some(true),
+        Ok(0) if invalid_ids == 0 => Some(false),
+        _ => None,
+    }
+}
+
+/// # Actor buffering a batching provider's avatar IDs, flushing them in one request
+///
+/// Flushes once `flush_threshold` IDs are buffered, or `flush_interval` after the last flush.
+pub struct BatchActor<F> {
+    name: String,
+    flush: F,
+    buffer: Buffer,
+    channel: Receiver<Submission>,
+    flush_threshold: usize,
+    flush_interval: Duration,
+    last_flush: Instant,
+    breaker: Option<Breaker>,
+    #[cfg(feature = "cache")]
+    outbox: Option<Outbox>,
+}
+
+impl<F: Flush> BatchActor<F> {
+    /// # The actor and the sender its provider hands avatar IDs to
+    #[must_use]
+    pub fn from_flush(
+        name: &str,
+        flush: F,
+        flush_threshold: usize,
+        flush_interval: Duration,
+    ) -> (Self, Sender<Submission>) {
+        let (tx, rx) = flume::bounded(flush_threshold);
+
+        (
+            Self {
+                name: name.to_string(),
+                flush,
+                buffer: Buffer::default(),
+                channel: rx,
+                flush_threshold,
+                flush_interval,
+                last_flush: Instant::now(),
+                breaker: None,
+                #[cfg(feature = "cache")]
+                outbox: None,
+            },
+            tx,
+        )
+    }
+
+    /// # Errors
+    /// Will never return `Err` (as of now)
+    pub async fn run(&mut self) -> Result<()> {
+        let name = self.name.clone();
+
+        // Flush whatever a previous run left in the outbox
+        if !self.buffer.is_empty() {
+            self.flush_or_log().await;
+        }
+
+        loop {
+            let deadline = self.last_flush + self.flush_interval;
+            tokio::select! {
+                submission = self.channel.recv_async() => {
+                    let Ok(Submission {
+                        avatar_id: id,
+                        receipt,
+                    }) = submission
+                    else {
+                        break;
+                    };
+
+                    if !self.buffer.push(&id, receipt) {
+                        continue;
+                    }
+
+                    #[cfg(feature = "cache")]
+                    if let Some(outbox) = &self.outbox
+                        && let Err(err) = outbox.push(&id).await
+                    {
+                        error!("[{name}]: Failed to write {id} to the outbox: {err}");
+                    }
+
+                    if self.buffer.len() >= self.flush_threshold {
+                        self.flush_or_log().await;
+                    }
+                }
+                // Without this, avatars would wait for the next one to arrive, even for hours
+                () = tokio::time::sleep_until(deadline), if !self.buffer.is_empty() => {
+                    self.flush_or_log().await;
+                }
+            }
+        }
+
+        // The channel only closes once every sender has been dropped, which is
+        // how the shutdown path in main.rs signals "no more avatars are coming."
+        // Without this, anything still sitting in the buffer here (below the
+        // flush threshold/interval) would wait in the outbox until the next
+        // start, or be silently discarded without one, even though the local
+        // cache already marked those IDs as sent.
+        while !self.buffer.is_empty() {
+            if let Err(err) = self.flush_buffer().await {
+                error!("[{name}]: Failed to flush buffer on shutdown: {err}");
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// # Back the buffer with a durable outbox and replay what it still holds
+    ///
+    /// # Errors
+    /// Will return `Err` if `Outbox::load` errors
+    #[cfg(feature = "cache")]
+    pub async fn with_outbox(mut self, outbox: Outbox) -> Result<Self> {
+        self.buffer = Buffer::replay(outbox.load().await?);
+        if !self.buffer.is_empty() {
+            info!(
+                "[{}] Replaying {} unsent avatars",
+                self.name,
+                self.buffer.len()
+            );
+        }
+
+        self.outbox = Some(outbox);
+        Ok(self)
+    }
+
+    /// # Report every flush to the provider's `Breaker`, so a failing provider is skipped
+    #[must_use]
+    pub fn with_breaker(mut self, breaker: Breaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    async fn flush_or_log(&mut self) {
+        let result = self.flush_buffer().await;
+        if let Some(breaker) = &self.breaker {
+            breaker.record(result.is_ok());
+        }
+
+        if let Err(err) = result {
+            error!("[{}]: Failed to flush buffer: {err}", self.name);
+            // Wait a whole interval before the timer tries again
+            self.last_flush = Instant::now();
+        }
+    }
+
+    /// # Send the oldest `flush_threshold` IDs, kept for the next flush if it fails
+    ///
+    /// # Errors
+    /// Will return `Err` if `Flush::flush` errors
+    pub async fn flush_buffer(&mut self) -> Result<()> {
+        let avatar_ids = self.buffer.batch(self.flush_threshold);
+        let unique = self.flush.flush(&avatar_ids).await?;
+
+        #[cfg(feature = "cache")]
+        if let Some(outbox) = &self.outbox
+            && let Err(err) = outbox.complete(&avatar_ids).await
+        {
+            error!(
+                "[{}]: Failed to clear flushed IDs from the outbox: {err}",
+                self.name
+            );
+        }
+
+        self.buffer.complete(avatar_ids.len(), unique);
+        self.last_flush = Instant::now();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::{HashMap, VecDeque};
+
+use tokio::sync::oneshot;
+
+/// # Avatar IDs a batching actor holds until the next flush, each with its own receipts
+///
+/// Receipts stay with their ID, so they're resolved by the flush that actually sent it.
+/// IDs replayed from the outbox have none, nobody is waiting on them anymore.
+#[derive(Default)]
+pub struct Buffer {
+    /// Oldest first
+    avatar_ids: VecDeque<String>,
+    /// Looked up per pushed ID, the buffer grows long while a provider is down
+    receipts:   HashMap<String, Vec<oneshot::Sender<Option<bool>>>>,
+}
+
+impl Buffer {
+    /// # Buffer IDs a previous run left unsent
+    #[must_use]
+    pub fn replay(avatar_ids: Vec<String>) -> Self {
+        let mut buffer = Self::default();
+        for avatar_id in avatar_ids {
+            if !buffer.receipts.contains_key(&avatar_id) {
+                buffer.receipts.insert(avatar_id.clone(), Vec::new());
+                buffer.avatar_ids.push_back(avatar_id);
+            }
+        }
+
+        buffer
+    }
+
+    /// # Add the ID, or only its receipt when it's already buffered
+    ///
+    /// Returns whether the ID is new to the buffer.
+    pub fn push(&mut self, avatar_id: &str, receipt: oneshot::Sender<Option<bool>>) -> bool {
+        if let Some(receipts) = self.receipts.get_mut(avatar_id) {
+            receipts.push(receipt);
+            return false;
+        }
+
+        self.receipts.insert(avatar_id.to_string(), vec![receipt]);
+        self.avatar_ids.push_back(avatar_id.to_string());
+        true
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.avatar_ids.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.avatar_ids.is_empty()
+    }
+
+    /// # The oldest `limit` IDs, sent together in the next flush
+    ///
+    /// The rest wait for the following flush, a buffer that grew while the provider was down
+    /// still goes out in requests the provider accepts.
+    #[must_use]
+    pub fn batch(&self, limit: usize) -> Vec<String> {
+        self.avatar_ids.iter().take(limit.max(1)).cloned().collect()
+    }
+
+    /// # Remove the first `count` IDs after they were sent, resolving their receipts
+    pub fn complete(&mut self, count: usize, unique: Option<bool>) {
+        let count = count.min(self.avatar_ids.len());
+        for avatar_id in self.avatar_ids.drain(..count) {
+            for receipt in self.receipts.remove(&avatar_id).unwrap_or_default() {
+                let _ = receipt.send(unique);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receipts_follow_their_ids() {
+        let mut buffer = Buffer::replay(vec!["avtr_replayed".to_string()]);
+
+        let (first, mut first_receipt) = oneshot::channel();
+        let (again, mut again_receipt) = oneshot::channel();
+        let (second, mut second_receipt) = oneshot::channel();
+        assert!(buffer.push("avtr_a", first));
+        assert!(!buffer.push("avtr_a", again));
+        assert!(buffer.push("avtr_b", second));
+        assert_eq!(buffer.batch(10), ["avtr_replayed", "avtr_a", "avtr_b"]);
+        assert_eq!(buffer.batch(2), ["avtr_replayed", "avtr_a"]);
+
+        // The replayed ID has no receipt, so it can't resolve the live ones
+        buffer.complete(2, Some(true));
+        assert_eq!(first_receipt.try_recv(), Ok(Some(true)));
+        assert_eq!(again_receipt.try_recv(), Ok(Some(true)));
+        assert!(second_receipt.try_recv().is_err());
+        assert_eq!(buffer.batch(10), ["avtr_b"]);
+
+        buffer.complete(1, None);
+        assert_eq!(second_receipt.try_recv(), Ok(None));
+        assert!(buffer.is_empty());
+    }
+}
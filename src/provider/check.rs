@@ -0,0 +1,214 @@
+use std::time::Duration;
+
+use reqwest::{Client, Method, StatusCode, header::CONTENT_TYPE};
+use tokio::time::Instant;
+
+use crate::{
+    USER_AGENT,
+    provider::{ProviderKind, registry},
+    settings::{DEVELOPER_ID, Settings},
+};
+
+/// The default robot avatar, every provider already has it, so submitting it changes nothing
+pub const TEST_AVATAR_ID: &str = "avtr_c38a1615-5bf5-42b4-84eb-a8b6c37cbd11";
+
+/// Credited for test submissions instead of the user
+pub const TEST_ATTRIBUTION: &str = DEVELOPER_ID;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// # Harmless request a provider is checked with
+///
+/// Built next to the request the provider really sends, so they don't drift apart.
+pub struct Probe {
+    pub name:     String,
+    pub method:   Method,
+    pub url:      String,
+    pub headers:  Vec<(String, String)>,
+    pub body:     Option<String>,
+    /// Statuses the provider answers `TEST_AVATAR_ID` with
+    pub accepted: Vec<u16>,
+    /// Whether the response still deserializes into what the provider reads
+    pub parses:   Option<fn(&str) -> bool>,
+}
+
+impl Probe {
+    #[must_use]
+    pub fn new(kind: ProviderKind, method: Method, url: impl Into<String>) -> Self {
+        Self {
+            name: kind.to_string(),
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: None,
+            accepted: vec![200],
+            parses: None,
+        }
+    }
+
+    #[must_use]
+    pub fn json(mut self, body: &serde_json::Value) -> Self {
+        self.body = Some(body.to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn accepted(mut self, accepted: &[u16]) -> Self {
+        self.accepted = accepted.to_vec();
+        self
+    }
+
+    #[must_use]
+    pub fn parses(mut self, parses: fn(&str) -> bool) -> Self {
+        self.parses = Some(parses);
+        self
+    }
+
+    /// # Send the request and describe how the provider answered
+    pub async fn run(self, client: &Client) -> Report {
+        let mut request = client
+            .request(self.method, &self.url)
+            .header("User-Agent", USER_AGENT)
+            .timeout(TIMEOUT);
+        let has_content_type = self
+            .headers
+            .iter()
+            .any(|(header, _)| header.eq_ignore_ascii_case(CONTENT_TYPE.as_str()));
+        for (header, value) in &self.headers {
+            request = request.header(header, value);
+        }
+        if let Some(body) = self.body {
+            if !has_content_type {
+                request = request.header(CONTENT_TYPE, "application/json");
+            }
+            request = request.body(body);
+        }
+
+        let started = Instant::now();
+        let answer = match request.send().await {
+            Ok(response) => {
+                let status = response.status();
+                match response.text().await {
+                    Ok(text) => Ok(Answer {
+                        status,
+                        accepted: self.accepted.contains(&status.as_u16()),
+                        parses: self.parses.map(|parses| parses(&text)),
+                    }),
+                    Err(error) => Err(describe(&error)),
+                }
+            }
+            Err(error) => Err(describe(&error)),
+        };
+
+        Report {
+            name: self.name,
+            https: self.url.starts_with("https://"),
+            url: self.url,
+            latency: started.elapsed(),
+            answer,
+        }
+    }
+}
+
+/// # How a provider answered its probe
+pub struct Report {
+    pub name:    String,
+    pub url:     String,
+    /// Plain HTTP sends the attribution unencrypted
+    pub https:   bool,
+    pub latency: Duration,
+    /// `Err` when it couldn't be reached
+    pub answer:  Result<Answer, String>,
+}
+
+pub struct Answer {
+    pub status:   StatusCode,
+    pub accepted: bool,
+    /// `None` for providers that only look at the status
+    pub parses:   Option<bool>,
+}
+
+impl Report {
+    #[must_use]
+    pub fn healthy(&self) -> bool {
+        self.answer
+            .as_ref()
+            .is_ok_and(|answer| answer.accepted && answer.parses != Some(false))
+    }
+}
+
+/// Timeouts, TLS and DNS failures read alike otherwise
+pub(crate) fn describe(error: &reqwest::Error) -> String {
+    let kind = if error.is_timeout() {
+        "Timed out"
+    } else if error.is_connect() {
+        "Couldn't connect (DNS, TLS or refused)"
+    } else {
+        "Request failed"
+    };
+
+    let mut source = std::error::Error::source(error);
+    let mut causes = Vec::new();
+    while let Some(cause) = source {
+        causes.push(cause.to_string());
+        source = cause.source();
+    }
+
+    if causes.is_empty() {
+        kind.to_string()
+    } else {
+        format!("{kind}: {}", causes.join(": "))
+    }
+}
+
+/// # Probes for every enabled provider
+#[must_use]
+pub fn probes(settings: &Settings) -> Vec<Probe> {
+    #[cfg_attr(not(feature = "custom"), allow(unused_mut))]
+    let mut probes = registry::descriptors()
+        .into_iter()
+        .filter(|descriptor| settings.is_enabled(descriptor.kind))
+        .filter_map(|descriptor| Some((descriptor.capabilities.probe?)(settings)))
+        .collect::<Vec<_>>();
+
+    // One probe for each of the `custom_providers`
+    #[cfg(feature = "custom")]
+    if settings.is_enabled(ProviderKind::CUSTOM) {
+        probes.extend(super::custom::probes(settings));
+    }
+    probes.sort_by(|a, b| a.name.cmp(&b.name));
+
+    probes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(answer: Result<Answer, String>) -> Report {
+        Report {
+            name: String::from("Test"),
+            url: String::from("https://example.com"),
+            https: true,
+            latency: Duration::ZERO,
+            answer,
+        }
+    }
+
+    #[test]
+    fn healthy_needs_an_accepted_status_and_a_parsing_body() {
+        let answer = |accepted, parses| {
+            Ok(Answer {
+                status: StatusCode::OK,
+                accepted,
+                parses,
+            })
+        };
+
+        assert!(report(answer(true, None)).healthy());
+        assert!(report(answer(true, Some(true))).healthy());
+        assert!(!report(answer(true, Some(false))).healthy());
+        assert!(!report(answer(false, None)).healthy());
+        assert!(!report(Err(String::from("Timed out"))).healthy());
+    }
+}
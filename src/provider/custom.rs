@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow, bail};
+use async_trait::async_trait;
+use flume::Sender;
+use futures::future::BoxFuture;
+use reqwest::{Client, Method, header::CONTENT_TYPE};
+
+use crate::{
+    USER_AGENT,
+    provider::{
+        Delivery,
+        Provider,
+        ProviderKind,
+        Submission,
+        batch::{BatchActor, Flush},
+        check::{Probe, TEST_ATTRIBUTION, TEST_AVATAR_ID},
+        health::Guarded,
+        limit::RateLimiter,
+        preview::{self, Payload, Preview},
+        registry::{Actor, Built, Capabilities, Context, Descriptor},
+        retry::RetryPolicy,
+        submit_each,
+    },
+    settings::{CustomProviderSettings, HealthSettings, MAX_CUSTOM_PROVIDERS, Settings},
+};
+
+/// Bits below are reserved for the built-in providers
+const FIRST_BIT: u32 = 16;
+
+#[allow(clippy::literal_string_with_formatting_args)]
+const AVATAR_ID: &str = "{avatar_id}";
+#[allow(clippy::literal_string_with_formatting_args)]
+const AVATAR_IDS: &str = "{avatar_ids}";
+#[allow(clippy::literal_string_with_formatting_args)]
+const ATTRIBUTION: &str = "{attribution}";
+
+/// Most avatar IDs sent in one request by providers taking `{avatar_ids}`
+const BULK_LIMIT: usize = 100;
+
+/// Checked, probed, previewed and looked up one by one, as named in `custom_providers`
+pub const DESCRIPTOR: Descriptor = Descriptor {
+    kind: ProviderKind::CUSTOM,
+    id: "CUSTOM",
+    aliases: &[],
+    name: "Custom - Providers from the config file",
+    enabled_by_default: false,
+    build,
+    capabilities: Capabilities {
+        probe:      None,
+        payloads:   None,
+        search_url: None,
+    },
+};
+
+/// # Cache bit of the custom provider with this `id`
+///
+/// One bit each, up to the last bit of the `u32` stored in the cache.
+#[must_use]
+pub fn bit(id: u8) -> Option<u32> {
+    if usize::from(id) >= MAX_CUSTOM_PROVIDERS {
+        return None;
+    }
+
+    Some(1 << (FIRST_BIT + u32::from(id)))
+}
+
+/// # Name of the custom provider stored in the cache as this bit
+#[must_use]
+pub fn name(settings: &Settings, bit: u32) -> Option<&str> {
+    settings
+        .custom_providers
+        .iter()
+        .find(|provider| self::bit(provider.id) == Some(bit))
+        .map(|provider| provider.name.as_str())
+}
+
+/// # Build the enabled custom providers, and the actors of the batching ones
+///
+/// # Errors
+/// Will return `Err` if a provider is misconfigured
+pub fn providers<'s>(
+    settings: &'s Settings,
+    client: &Client,
+) -> Result<(Vec<Custom<'s>>, Vec<CustomActor<'s>>)> {
+    let (providers, actors) = endpoints(settings, client)?
+        .into_iter()
+        .unzip::<_, _, _, Vec<_>>();
+    Ok((providers, actors.into_iter().flatten().collect()))
+}
+
+/// # Each enabled custom provider, with its actor if it batches
+fn endpoints<'s>(
+    settings: &'s Settings,
+    client: &Client,
+) -> Result<Vec<(Custom<'s>, Option<CustomActor<'s>>)>> {
+    let mut endpoints = Vec::new();
+    for config in &settings.custom_providers {
+        let Some(bit) = bit(config.id) else {
+            bail!(
+                "{} has ID {}, custom provider IDs go up to {}",
+                config.name,
+                config.id,
+                MAX_CUSTOM_PROVIDERS - 1
+            );
+        };
+        if !config.enabled {
+            continue;
+        }
+
+        let endpoint = Endpoint::new(settings, config, client.clone())?;
+        if let Some(batch) = &config.batch {
+            let (actor, tx) = CustomActor::from_flush(
+                &config.name,
+                endpoint.clone(),
+                batch.flush_threshold,
+                batch.flush_interval(),
+            );
+            let custom = Custom {
+                endpoint,
+                bit,
+                sender: Some(tx),
+            };
+            endpoints.push((custom, Some(actor)));
+        } else {
+            let custom = Custom {
+                endpoint,
+                bit,
+                sender: None,
+            };
+            endpoints.push((custom, None));
+        }
+    }
+
+    Ok(endpoints)
+}
+
+/// # Batches survive crashes in an outbox of their own bit when there's a cache
+fn build(context: Context) -> BoxFuture<'static, Result<Built>> {
+    Box::pin(async move {
+        let mut built = Built::default();
+        for (custom, actor) in endpoints(context.settings, &context.client)? {
+            let health = custom.health();
+            let provider = Guarded::new(crate::provider!(custom), health);
+            if let Some(actor) = actor {
+                // Each actor reports its flushes to the breaker of its own provider
+                let mut actor = actor.with_breaker(provider.breaker());
+                #[cfg(feature = "cache")]
+                if let Some(cache) = &context.cache {
+                    let outbox = cache.outbox(ProviderKind::new(provider.bit()));
+                    actor = actor.with_outbox(outbox).await?;
+                }
+
+                built.actors.push(Actor {
+                    name: provider.name(),
+                    run:  Box::pin(async move { actor.run().await }),
+                });
+            }
+            built.providers.push(crate::provider!(provider));
+        }
+
+        Ok(built)
+    })
+}
+
+/// # Probes for the enabled custom providers, sending `TEST_AVATAR_ID`
+#[must_use]
+pub fn probes(settings: &Settings) -> Vec<Probe> {
+    let avatar_ids = [TEST_AVATAR_ID.to_string()];
+
+    settings
+        .custom_providers
+        .iter()
+        .filter(|config| config.enabled)
+        .map(|config| {
+            let payload = payload(config, &avatar_ids, TEST_ATTRIBUTION);
+            Probe {
+                name:     config.name.clone(),
+                method:   payload.method,
+                url:      payload.url,
+                headers:  payload.headers,
+                body:     payload.body,
+                accepted: [&config.unique_status[..], &config.duplicate_status[..]].concat(),
+                parses:   None,
+            }
+        })
+        .collect()
+}
+
+/// # What the enabled custom providers would be sent, in requests as large as they'd be sent
+#[must_use]
+pub(crate) fn previews(
+    settings: &Settings,
+    checked: &HashMap<String, u32>,
+    attribution: &str,
+) -> Vec<Preview> {
+    settings
+        .custom_providers
+        .iter()
+        .filter(|config| config.enabled)
+        .filter_map(|config| {
+            let avatar_ids = preview::unsent(checked, bit(config.id)?);
+            let chunk_size = match &config.batch {
+                Some(batch) => batch.flush_threshold.max(1),
+                None if !uses(config, AVATAR_ID) => BULK_LIMIT,
+                None => 1,
+            };
+            let payloads = avatar_ids
+                .chunks(chunk_size)
+                .map(|chunk| payload(config, chunk, attribution))
+                .collect();
+
+            Some(Preview {
+                name: config.name.clone(),
+                avatar_ids,
+                payloads,
+            })
+        })
+        .collect()
+}
+
+/// # Whether the URL, headers or body contain the placeholder
+fn uses(config: &CustomProviderSettings, placeholder: &str) -> bool {
+    config.url.contains(placeholder)
+        || config
+            .headers
+            .values()
+            .any(|value| value.contains(placeholder))
+        || config
+            .body
+            .as_ref()
+            .is_some_and(|body| body.contains(placeholder))
+}
+
+/// # The request for these avatar IDs, with the placeholders replaced
+fn payload(config: &CustomProviderSettings, avatar_ids: &[String], attribution: &str) -> Payload {
+    let render = |template: &str| render(template, avatar_ids, attribution);
+
+    Payload {
+        method:  Method::from_bytes(config.method.to_uppercase().as_bytes())
+            .unwrap_or(Method::POST),
+        url:     render(&config.url),
+        headers: config
+            .headers
+            .iter()
+            .map(|(header, value)| (header.clone(), render(value)))
+            .collect(),
+        body:    config.body.as_deref().map(render),
+    }
+}
+
+/// # Request described by a `CustomProviderSettings`
+#[derive(Clone)]
+pub struct Endpoint<'s> {
+    settings: &'s Settings,
+    config:   &'s CustomProviderSettings,
+    method:   Method,
+    client:   Client,
+    retry:    RetryPolicy,
+    /// Shared with the batching actor
+    limiter:  RateLimiter,
+    /// Takes `{avatar_ids}` instead of `{avatar_id}`
+    bulk:     bool,
+}
+
+impl<'s> Endpoint<'s> {
+    fn new(
+        settings: &'s Settings,
+        config: &'s CustomProviderSettings,
+        client: Client,
+    ) -> Result<Self> {
+        let name = &config.name;
+        if name.is_empty() {
+            bail!("Custom provider for {} has no name", config.url);
+        }
+
+        let Ok(method) = Method::from_bytes(config.method.to_uppercase().as_bytes()) else {
+            bail!("[{name}] Unknown HTTP method {}", config.method);
+        };
+
+        // Every ID has to end up in the request, or it would be marked as sent without being sent
+        let uses = |placeholder| uses(config, placeholder);
+        if config.batch.is_some() && !uses(AVATAR_IDS) {
+            bail!("[{name}] The URL, headers or body has to contain {AVATAR_IDS} when batching");
+        }
+        if !uses(AVATAR_ID) && !uses(AVATAR_IDS) {
+            bail!("[{name}] The URL, headers or body has to contain {AVATAR_ID} or {AVATAR_IDS}");
+        }
+
+        Ok(Self {
+            settings,
+            config,
+            method,
+            client,
+            retry: config.retry.into(),
+            limiter: RateLimiter::new(config.rate_limit),
+            bulk: !uses(AVATAR_ID),
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// # Send the avatar IDs in one request
+    ///
+    /// True: New/Unique | False: Duplicate/Existing.
+    async fn send(&self, avatar_ids: &[String]) -> Result<bool> {
+        let name = self.name();
+        let attribution = self.settings.attribution.get_user_id().await;
+        let payload = payload(self.config, avatar_ids, &attribution);
+        if let Some(body) = &payload.body {
+            debug!("[{name}] Sending {body}");
+        }
+        let has_content_type = payload
+            .headers
+            .iter()
+            .any(|(header, _)| header.eq_ignore_ascii_case(CONTENT_TYPE.as_str()));
+
+        let request = || {
+            let mut request = self
+                .client
+                .request(self.method.clone(), &payload.url)
+                .header("User-Agent", USER_AGENT)
+                .timeout(self.config.timeout(&self.settings.http));
+            for (header, value) in &payload.headers {
+                request = request.header(header, value);
+            }
+            if let Some(body) = &payload.body {
+                if !has_content_type {
+                    request = request.header(CONTENT_TYPE, "application/json");
+                }
+                request = request.body(body.clone());
+            }
+
+            request
+        };
+        let response = self.retry.send(name, &self.limiter, request).await?;
+        let status = response.status();
+        let text = response.text().await?;
+        debug!("[{name}] {status} | {text}");
+
+        let code = status.as_u16();
+        if self.config.unique_status.contains(&code) {
+            Ok(true)
+        } else if self.config.duplicate_status.contains(&code) {
+            Ok(false)
+        } else {
+            bail!("[{name}] {status} | {text}")
+        }
+    }
+}
+
+fn render(template: &str, avatar_ids: &[String], attribution: &str) -> String {
+    let avatar_id = avatar_ids.first().map_or("", String::as_str);
+    let avatar_ids = serde_json::to_string(avatar_ids).unwrap_or_default();
+
+    template
+        .replace(AVATAR_IDS, &avatar_ids)
+        .replace(AVATAR_ID, avatar_id)
+        .replace(ATTRIBUTION, attribution)
+}
+
+#[async_trait]
+impl Flush for Endpoint<'_> {
+    async fn flush(&self, avatar_ids: &[String]) -> Result<Option<bool>> {
+        self.send(avatar_ids).await.map(Some)
+    }
+}
+
+pub type CustomActor<'s> = BatchActor<Endpoint<'s>>;
+
+pub struct Custom<'s> {
+    endpoint: Endpoint<'s>,
+    bit:      u32,
+    sender:   Option<Sender<Submission>>,
+}
+
+impl Custom<'_> {
+    #[must_use]
+    pub const fn health(&self) -> HealthSettings {
+        self.endpoint.config.health
+    }
+}
+
+#[async_trait]
+impl Provider for Custom<'_> {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::CUSTOM
+    }
+
+    fn bit(&self) -> u32 {
+        self.bit
+    }
+
+    fn name(&self) -> String {
+        self.endpoint.name().to_string()
+    }
+
+    fn max_concurrency(&self) -> usize {
+        self.endpoint.limiter.max_concurrency()
+    }
+
+    fn sends_in_bulk(&self) -> bool {
+        self.sender.is_none() && self.endpoint.bulk
+    }
+
+    async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
+        match self.submit_avatar_id(avatar_id).await? {
+            Delivery::Confirmed(outcome) => Ok(outcome.unique),
+            Delivery::Pending(receipt) => Ok(receipt.await?.unwrap_or_default()),
+        }
+    }
+
+    async fn submit_avatar_id(&self, avatar_id: &str) -> Result<Delivery> {
+        if let Some(sender) = &self.sender {
+            let (submission, receipt) = Submission::new(avatar_id);
+            sender.send_async(submission).await?;
+            return Ok(Delivery::Pending(receipt));
+        }
+
+        let unique = self.endpoint.send(&[avatar_id.to_string()]).await?;
+        Ok(Delivery::Confirmed(unique.into()))
+    }
+
+    async fn send_avatar_ids(&self, avatar_ids: &[String]) -> Vec<Result<Delivery>> {
+        if !self.sends_in_bulk() {
+            return submit_each(self, avatar_ids).await;
+        }
+
+        let mut results = Vec::with_capacity(avatar_ids.len());
+        for chunk in avatar_ids.chunks(BULK_LIMIT) {
+            match self.endpoint.send(chunk).await {
+                Ok(unique) => {
+                    results.extend(chunk.iter().map(|_| Ok(Delivery::Confirmed(unique.into()))));
+                }
+                Err(error) => results.extend(chunk.iter().map(|_| Err(anyhow!("{error}")))),
+            }
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn renders_placeholders() {
+        let avatar_ids = ["avtr_a", "avtr_b"].map(String::from);
+        assert_eq!(
+            render(
+                r#"{"id":"{avatar_id}","ids":{avatar_ids},"userid":"{attribution}"}"#,
+                &avatar_ids,
+                "1234"
+            ),
+            r#"{"id":"avtr_a","ids":["avtr_a","avtr_b"],"userid":"1234"}"#
+        );
+    }
+
+    #[test]
+    fn bits_follow_the_built_in_providers() {
+        assert_eq!(bit(0), Some(1 << 16));
+        assert_eq!(bit(15), Some(1 << 31));
+        assert_eq!(bit(16), None);
+        assert!(ProviderKind::iter().all(|kind| kind.bit() < 1 << FIRST_BIT));
+    }
+}
@@ -1,133 +1,176 @@
 use std::time::Duration;
 
 use anyhow::{Result, bail};
-use flume::{Receiver, Sender};
-use reqwest::{Client, StatusCode};
-use serde_json::json;
-use tokio::time::Instant;
+use flume::Sender;
+use futures::future::BoxFuture;
+use reqwest::{Client, Method, StatusCode};
+use serde_json::{Value, json};
 
 use crate::{
     USER_AGENT,
-    provider::{Provider, ProviderKind},
+    provider::{
+        Delivery,
+        Provider,
+        ProviderKind,
+        Submission,
+        batch::{BatchActor, Flush},
+        check::{Probe, TEST_AVATAR_ID},
+        health::Guarded,
+        limit::RateLimiter,
+        preview::Payload,
+        registry::{Built, Capabilities, Context, Descriptor},
+        retry::RetryPolicy,
+    },
+    settings::Settings,
 };
 
 const URL: &str = "https://avtr.icu/upload-bulk";
 
-const FLUSH_INTERVAL: Duration = Duration::from_mins(2);
-const FLUSH_THRESHOLD: usize = 100;
-
-const RETRY_LIMIT: usize = 5;
+/// VRCX search API, asked by `vrc-log lookup`
+pub const SEARCH_URL: &str = "https://avtr.icu/vrcx";
+
+pub const DESCRIPTOR: Descriptor = Descriptor {
+    kind: ProviderKind::CUTEDB,
+    id: "CUTEDB",
+    aliases: &[],
+    name: "CuteDB - Cutest Avatar Search",
+    enabled_by_default: true,
+    build,
+    capabilities: Capabilities {
+        probe:      Some(probe),
+        payloads:   Some(|settings, avatar_ids, _| payloads(settings, avatar_ids)),
+        search_url: Some(SEARCH_URL),
+    },
+};
 
 const LOG_NAME: &str = "CuteDB";
 
 pub struct CuteDB {
-    sender: Sender<String>,
+    sender: Sender<Submission>,
 }
 
-pub struct CuteDBActor {
-    client:         Client,
-    buffer:         Vec<String>,
-    channel:        Receiver<String>,
-    flush_interval: Duration,
-    last_flush:     Instant,
+/// # The bulk upload batches are flushed to
+pub struct Upload {
+    client:  Client,
+    url:     String,
+    retry:   RetryPolicy,
+    limiter: RateLimiter,
+    timeout: Duration,
 }
 
-impl CuteDBActor {
+pub type CuteDBActor = BatchActor<Upload>;
+
+impl BatchActor<Upload> {
     #[must_use]
-    pub fn new() -> (Self, Sender<String>) {
-        let (tx, rx) = flume::bounded(FLUSH_THRESHOLD);
-
-        (
-            Self {
-                client:         Client::default(),
-                buffer:         Vec::new(),
-                channel:        rx,
-                flush_interval: FLUSH_INTERVAL,
-                last_flush:     Instant::now(),
-            },
-            tx,
+    pub fn new(settings: &Settings, client: Client) -> (Self, Sender<Submission>) {
+        let provider_settings = settings.provider_settings(ProviderKind::CUTEDB);
+        let upload = Upload {
+            client,
+            url: settings.url(ProviderKind::CUTEDB, URL),
+            retry: provider_settings.retry.into(),
+            limiter: RateLimiter::new(settings.rate_limit(ProviderKind::CUTEDB)),
+            timeout: settings.timeout(ProviderKind::CUTEDB),
+        };
+
+        Self::from_flush(
+            LOG_NAME,
+            upload,
+            provider_settings.flush_threshold,
+            provider_settings.flush_interval(),
         )
     }
 
-    /// # Errors
-    /// Will never return `Err` (as of now)
-    pub async fn run(&mut self) -> Result<()> {
-        while let Ok(id) = self.channel.recv_async().await {
-            self.buffer.push(id);
-
-            if self.buffer.len() >= FLUSH_THRESHOLD
-                || self.last_flush.elapsed() > self.flush_interval
-            {
-                match self.flush_buffer().await {
-                    Ok(()) => (),
-                    Err(err) => error!("[{LOG_NAME}]: Failed to flush buffer: {err}"),
-                }
-            }
-        }
+    #[must_use]
+    pub fn new_with_url_and_flush_interval(
+        capacity: usize,
+        url: String,
+        flush_interval: Duration,
+    ) -> (Self, Sender<Submission>) {
+        let upload = Upload {
+            client: Client::default(),
+            url,
+            retry: RetryPolicy::default(),
+            limiter: RateLimiter::default(),
+            timeout: Duration::from_secs(5),
+        };
+
+        Self::from_flush(LOG_NAME, upload, capacity, flush_interval)
+    }
+}
 
-        if !self.buffer.is_empty()
-            && let Err(err) = self.flush_buffer().await
-        {
-            error!("[{LOG_NAME}]: Failed to flush buffer on shutdown: {err}");
+#[async_trait::async_trait]
+impl Flush for Upload {
+    async fn flush(&self, avatar_ids: &[String]) -> Result<Option<bool>> {
+        let json = body(avatar_ids);
+
+        debug!("[{LOG_NAME}] Sending {json:#?}");
+        let request = || {
+            self.client
+                .post(&self.url)
+                .header("User-Agent", USER_AGENT)
+                .json(&json)
+                .timeout(self.timeout)
+        };
+        let response = self.retry.send(LOG_NAME, &self.limiter, request).await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+        debug!("[{LOG_NAME}] {status} | {text}");
+        if status != StatusCode::OK {
+            bail!("[{LOG_NAME}] Unknown Error: {status} | {text}");
         }
 
-        Ok(())
+        // The bulk upload doesn't say which avatars were new
+        Ok(None)
     }
+}
 
-    /// # Errors
-    /// Will return `Err` if `Response::send` or `Response::text` fails.
-    pub async fn flush_buffer(&mut self) -> Result<()> {
-        let json: Vec<_> = self.buffer.iter().map(|id| json!({ "id": id })).collect();
-
-        let mut current_try = 0;
-        let mut success = false;
-        while current_try < RETRY_LIMIT && !success {
-            if current_try != 0 {
-                tokio::time::sleep(Duration::from_secs(10)).await;
-            }
-            debug!("[{LOG_NAME}] (try {current_try}) Sending {json:#?}");
-            current_try += 1;
-
-            let response = self
-                .client
-                .post(URL)
-                .header("User-Agent", USER_AGENT)
-                .json(&json)
-                .timeout(Duration::from_secs(5))
-                .send()
+/// # Batches survive crashes in the outbox when there's a cache
+fn build(context: Context) -> BoxFuture<'static, Result<Built>> {
+    Box::pin(async move {
+        let (actor, sender) = CuteDBActor::new(context.settings, context.client);
+        let provider = crate::provider!(CuteDB::new(sender));
+        let provider = Guarded::from_settings(context.settings, provider);
+        let mut actor = actor.with_breaker(provider.breaker());
+        #[cfg(feature = "cache")]
+        if let Some(cache) = &context.cache {
+            actor = actor
+                .with_outbox(cache.outbox(ProviderKind::CUTEDB))
                 .await?;
-
-            let status = response.status();
-            let text = response.text().await?;
-            debug!("[{LOG_NAME}] {status} | {text}");
-
-            success = match status {
-                StatusCode::OK => true,
-                StatusCode::TOO_MANY_REQUESTS => {
-                    warn!("[{LOG_NAME}] 429 Rate Limit, trying again in 10 seconds");
-                    false
-                }
-                _ => {
-                    error!("[{LOG_NAME}] Unknown Error: {status} | {text}");
-                    false
-                }
-            };
         }
 
-        if current_try >= RETRY_LIMIT {
-            bail!("[{LOG_NAME}] Failed after {current_try} retries to flush buffer, aborting");
-        }
+        Ok(Built::guarded(provider).with_actor(LOG_NAME, async move { actor.run().await }))
+    })
+}
 
-        self.buffer.clear();
-        self.last_flush = Instant::now();
+fn body(avatar_ids: &[String]) -> Value {
+    avatar_ids.iter().map(|id| json!({ "id": id })).collect()
+}
 
-        Ok(())
-    }
+/// # One request per `flush_threshold` avatar IDs, without attribution
+#[must_use]
+pub fn payloads(settings: &Settings, avatar_ids: &[String]) -> Vec<Payload> {
+    let url = settings.url(ProviderKind::CUTEDB, URL);
+    let flush_threshold = settings
+        .provider_settings(ProviderKind::CUTEDB)
+        .flush_threshold;
+    avatar_ids
+        .chunks(flush_threshold.max(1))
+        .map(|chunk| Payload::new(Method::POST, &url).json(&body(chunk)))
+        .collect()
+}
+
+/// # Uploads `TEST_AVATAR_ID`, which is already known
+#[must_use]
+pub fn probe(settings: &Settings) -> Probe {
+    let json = body(&[TEST_AVATAR_ID.to_string()]);
+    let url = settings.url(ProviderKind::CUTEDB, URL);
+    Probe::new(ProviderKind::CUTEDB, Method::POST, url).json(&json)
 }
 
 impl CuteDB {
     #[must_use]
-    pub const fn new(sender: Sender<String>) -> Self {
+    pub const fn new(sender: Sender<Submission>) -> Self {
         Self { sender }
     }
 }
@@ -139,7 +182,15 @@ impl Provider for CuteDB {
     }
 
     async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
-        self.sender.send_async(avatar_id.to_string()).await?;
-        Ok(true)
+        let (submission, receipt) = Submission::new(avatar_id);
+        self.sender.send_async(submission).await?;
+        // Unknown counts as not new, `submit_avatar_id` tells the two apart
+        Ok(receipt.await?.unwrap_or_default())
+    }
+
+    async fn submit_avatar_id(&self, avatar_id: &str) -> Result<Delivery> {
+        let (submission, receipt) = Submission::new(avatar_id);
+        self.sender.send_async(submission).await?;
+        Ok(Delivery::Pending(receipt))
     }
 }
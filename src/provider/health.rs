@@ -0,0 +1,333 @@
+use std::{fmt, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use crate::{
+    provider::{Delivery, Provider, ProviderKind, submit_each},
+    settings::{HealthSettings, Settings},
+};
+
+/// # Returned instead of contacting a provider that is marked down
+///
+/// The avatar goes to the retry queue like after any other error,
+/// but without logging it, the state change was already logged once.
+#[derive(Clone, Debug)]
+pub struct ProviderDown {
+    pub name: String,
+}
+
+impl fmt::Display for ProviderDown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] Marked down, skipped until it recovers", self.name)
+    }
+}
+
+impl std::error::Error for ProviderDown {}
+
+#[derive(Default)]
+struct Health {
+    /// Failures in a row, reset by any success
+    failures:   u32,
+    /// Set while the provider is down, probed again once it passed
+    down_until: Option<Instant>,
+    /// A probe is in flight, everything else keeps being skipped
+    probing:    bool,
+}
+
+/// # Health of a provider, shared with the actor flushing its batches
+///
+/// Once `failure_threshold` submissions failed in a row, the provider is skipped for `cooldown_secs`.
+/// The first submission after that probes it, and either brings it back up or starts another cooldown.
+#[derive(Clone)]
+pub struct Breaker {
+    name:     String,
+    settings: HealthSettings,
+    health:   Arc<Mutex<Health>>,
+}
+
+impl Breaker {
+    #[must_use]
+    pub fn new(name: String, settings: HealthSettings) -> Self {
+        Self {
+            name,
+            settings,
+            health: Arc::default(),
+        }
+    }
+
+    /// # Whether the provider is marked down right now
+    #[must_use]
+    pub fn is_down(&self) -> bool {
+        self.health.lock().down_until.is_some()
+    }
+
+    fn check(&self) -> Result<(), ProviderDown> {
+        let name = &self.name;
+        let mut health = self.health.lock();
+        match health.down_until {
+            Some(until) if health.probing || Instant::now() < until => {
+                drop(health);
+                Err(ProviderDown { name: name.clone() })
+            }
+            Some(_) => {
+                health.probing = true;
+                drop(health);
+                info!("[{name}] Checking if it is back up");
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// # Count a submission or flush, marking the provider down or back up
+    pub fn record(&self, succeeded: bool) {
+        let name = &self.name;
+        let cooldown = self.settings.cooldown();
+        let mut health = self.health.lock();
+        if succeeded {
+            let was_down = health.down_until.take().is_some();
+            health.failures = 0;
+            health.probing = false;
+            drop(health);
+            if was_down {
+                info!("[{name}] Back up, sending avatars again");
+            }
+            return;
+        }
+
+        health.failures = health.failures.saturating_add(1);
+        if health.probing {
+            health.probing = false;
+            health.down_until = Some(Instant::now() + cooldown);
+            drop(health);
+            debug!("[{name}] Still down, checking again in {cooldown:?}");
+        } else if health.down_until.is_none() && health.failures >= self.settings.failure_threshold
+        {
+            health.down_until = Some(Instant::now() + cooldown);
+            let failures = health.failures;
+            drop(health);
+            warn!(
+                "[{name}] Down after {failures} failures in a row, queueing avatars for {cooldown:?}"
+            );
+        }
+    }
+}
+
+/// # Skips a provider while its `Breaker` has it marked down
+///
+/// Batching providers only hand avatars to their actor here,
+/// the actor reports whether its flushes succeeded through the same `Breaker`.
+pub struct Guarded {
+    inner:   Arc<Box<dyn Provider>>,
+    breaker: Breaker,
+}
+
+impl Guarded {
+    #[must_use]
+    pub fn new(inner: Arc<Box<dyn Provider>>, settings: HealthSettings) -> Self {
+        let breaker = Breaker::new(inner.name(), settings);
+        Self { inner, breaker }
+    }
+
+    /// # Guarded by the health settings of its kind
+    #[must_use]
+    pub fn from_settings(settings: &Settings, inner: Arc<Box<dyn Provider>>) -> Self {
+        let health = settings.provider_settings(inner.kind()).health;
+        Self::new(inner, health)
+    }
+
+    /// # Handle for the actor flushing the provider's batches
+    #[must_use]
+    pub fn breaker(&self) -> Breaker {
+        self.breaker.clone()
+    }
+
+    /// # Whether the provider is marked down right now
+    #[must_use]
+    pub fn is_down(&self) -> bool {
+        self.breaker.is_down()
+    }
+
+    /// # Count the result, unless it was only handed to a batching actor
+    fn record(&self, result: &Result<Delivery>) {
+        if !matches!(result, Ok(Delivery::Pending(_))) {
+            self.breaker.record(result.is_ok());
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for Guarded {
+    fn kind(&self) -> ProviderKind {
+        self.inner.kind()
+    }
+
+    fn bit(&self) -> u32 {
+        self.inner.bit()
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    fn max_concurrency(&self) -> usize {
+        self.inner.max_concurrency()
+    }
+
+    fn sends_in_bulk(&self) -> bool {
+        self.inner.sends_in_bulk()
+    }
+
+    async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
+        self.breaker.check()?;
+        let result = self.inner.send_avatar_id(avatar_id).await;
+        self.breaker.record(result.is_ok());
+
+        result
+    }
+
+    async fn submit_avatar_id(&self, avatar_id: &str) -> Result<Delivery> {
+        self.breaker.check()?;
+        let result = self.inner.submit_avatar_id(avatar_id).await;
+        self.record(&result);
+
+        result
+    }
+
+    async fn send_avatar_ids(&self, avatar_ids: &[String]) -> Vec<Result<Delivery>> {
+        // One ID at a time through the guard, so a provider going down mid-chunk stops being tried
+        if !self.inner.sends_in_bulk() {
+            return submit_each(self, avatar_ids).await;
+        }
+
+        if let Err(down) = self.breaker.check() {
+            return avatar_ids
+                .iter()
+                .map(|_| Err(down.clone().into()))
+                .collect();
+        }
+
+        let results = self.inner.send_avatar_ids(avatar_ids).await;
+        if !results.is_empty() {
+            self.breaker.record(results.iter().any(Result::is_ok));
+        }
+
+        results
+    }
+}
+
+/// # Whether the error only says the provider was skipped
+#[must_use]
+pub fn is_down(error: &anyhow::Error) -> bool {
+    error.is::<ProviderDown>()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, AtomicU32, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct FlakyProvider {
+        calls:   Arc<AtomicU32>,
+        healthy: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Provider for FlakyProvider {
+        fn kind(&self) -> ProviderKind {
+            ProviderKind::VRCWB
+        }
+
+        async fn send_avatar_id(&self, _avatar_id: &str) -> Result<bool> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if !self.healthy.load(Ordering::SeqCst) {
+                anyhow::bail!("connection refused");
+            }
+
+            Ok(true)
+        }
+    }
+
+    fn guarded(provider: &FlakyProvider) -> Guarded {
+        let settings = HealthSettings {
+            failure_threshold: 3,
+            cooldown_secs:     60,
+        };
+
+        Guarded::new(crate::provider!(provider.clone()), settings)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_provider_after_consecutive_failures_then_probes() {
+        let provider = FlakyProvider::default();
+        let guarded = guarded(&provider);
+
+        for _ in 0..3 {
+            let error = guarded.send_avatar_id("avtr_a").await.unwrap_err();
+            assert!(!is_down(&error));
+        }
+        assert!(guarded.is_down());
+
+        let error = guarded.send_avatar_id("avtr_a").await.unwrap_err();
+        assert!(is_down(&error));
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 3);
+
+        // A failed probe starts another cooldown
+        tokio::time::advance(Duration::from_mins(1)).await;
+        assert!(guarded.send_avatar_id("avtr_a").await.is_err());
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 4);
+        assert!(is_down(
+            &guarded.send_avatar_id("avtr_a").await.unwrap_err()
+        ));
+
+        tokio::time::advance(Duration::from_mins(1)).await;
+        provider.healthy.store(true, Ordering::SeqCst);
+        assert!(guarded.send_avatar_id("avtr_a").await.unwrap());
+        assert!(!guarded.is_down());
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn success_resets_the_failure_count() {
+        let provider = FlakyProvider::default();
+        let guarded = guarded(&provider);
+
+        for _ in 0..2 {
+            assert!(guarded.send_avatar_id("avtr_a").await.is_err());
+        }
+        provider.healthy.store(true, Ordering::SeqCst);
+        assert!(guarded.send_avatar_id("avtr_a").await.is_ok());
+        provider.healthy.store(false, Ordering::SeqCst);
+        for _ in 0..2 {
+            assert!(guarded.send_avatar_id("avtr_a").await.is_err());
+        }
+
+        assert!(!guarded.is_down());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_mid_chunk_once_down() {
+        let provider = FlakyProvider::default();
+        let guarded = guarded(&provider);
+
+        let avatar_ids = (0..10).map(|i| format!("avtr_{i}")).collect::<Vec<_>>();
+        let results = guarded.send_avatar_ids(&avatar_ids).await;
+
+        assert_eq!(results.len(), 10);
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 3);
+        assert!(
+            results
+                .iter()
+                .skip(3)
+                .all(|result| { result.as_ref().is_err_and(is_down) })
+        );
+    }
+}
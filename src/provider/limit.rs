@@ -0,0 +1,162 @@
+use std::{sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+use crate::settings::RateLimitSettings;
+
+/// # Token bucket and concurrency limit in front of a provider
+///
+/// Clones share the same bucket, so a provider and its retry queue can't add up to more than the limit.
+#[derive(Clone)]
+pub struct RateLimiter {
+    /// `None` when requests aren't limited per second
+    bucket:          Option<Arc<Mutex<Bucket>>>,
+    concurrency:     Arc<Semaphore>,
+    max_concurrency: usize,
+}
+
+struct Bucket {
+    tokens:  f64,
+    burst:   f64,
+    rate:    f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// How long until a token is available, taking it if there is one
+    fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    /// Doesn't limit anything
+    fn default() -> Self {
+        Self {
+            bucket:          None,
+            concurrency:     Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            max_concurrency: Semaphore::MAX_PERMITS,
+        }
+    }
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(settings: RateLimitSettings) -> Self {
+        let burst = f64::from(settings.burst.max(1));
+        let bucket = (settings.requests_per_sec > 0.0).then(|| {
+            Arc::new(Mutex::new(Bucket {
+                tokens: burst,
+                burst,
+                rate: settings.requests_per_sec,
+                updated: Instant::now(),
+            }))
+        });
+        let permits = settings.max_concurrency.clamp(1, Semaphore::MAX_PERMITS);
+
+        Self {
+            bucket,
+            concurrency: Arc::new(Semaphore::new(permits)),
+            max_concurrency: permits,
+        }
+    }
+
+    /// # Requests allowed in flight at the same time
+    #[must_use]
+    pub const fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    /// # Wait for a free slot and a token
+    ///
+    /// The request may start once this returns, and counts as in flight until the permit is dropped.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        let Ok(permit) = self.concurrency.clone().acquire_owned().await else {
+            unreachable!("the concurrency semaphore is never closed");
+        };
+
+        if let Some(bucket) = &self.bucket {
+            loop {
+                let wait = bucket.lock().take();
+                match wait {
+                    Some(wait) => tokio::time::sleep(wait).await,
+                    None => break,
+                }
+            }
+        }
+
+        permit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests_per_sec: f64, burst: u32, max_concurrency: usize) -> RateLimiter {
+        RateLimiter::new(RateLimitSettings {
+            requests_per_sec,
+            burst,
+            max_concurrency,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bursts_then_spaces_out_requests() {
+        let limiter = limiter(2.0, 3, 10);
+        let started = Instant::now();
+
+        for _ in 0..3 {
+            drop(limiter.acquire().await);
+        }
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        for _ in 0..4 {
+            drop(limiter.acquire().await);
+        }
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        assert!(started.elapsed() < Duration::from_millis(2100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_requests_in_flight() {
+        let limiter = limiter(0.0, 1, 2);
+
+        let first = limiter.acquire().await;
+        let _second = limiter.acquire().await;
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), limiter.acquire())
+                .await
+                .is_err()
+        );
+
+        drop(first);
+        let _third = limiter.acquire().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clones_share_the_bucket() {
+        let limiter = limiter(1.0, 1, 10);
+        let clone = limiter.clone();
+        let started = Instant::now();
+
+        drop(limiter.acquire().await);
+        drop(clone.acquire().await);
+
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use reqwest::{Client, StatusCode};
+use serde_json::Value;
+
+use crate::{
+    USER_AGENT,
+    provider::{AvatarMetadata, ProviderKind, avatar_metadata, check, registry},
+    settings::Settings,
+};
+
+/// VRCX asks for this many results, an ID only ever matches one
+const RESULTS: &str = "10";
+
+/// # Where a provider is asked whether it has an avatar
+pub struct Search {
+    pub name:    String,
+    /// `None` for providers without a search API
+    pub url:     Option<String>,
+    pub timeout: Duration,
+}
+
+/// # What a provider knows about an avatar
+#[derive(Debug, PartialEq, Eq)]
+pub enum Found {
+    /// The provider has it, with whatever details it returned
+    Known(AvatarMetadata),
+    Unknown,
+    /// The provider can't be asked
+    NoSearchApi,
+}
+
+/// # How a provider answered the lookup
+pub struct Lookup {
+    pub name:  String,
+    /// `Err` when it couldn't be reached or answered with something else than avatars
+    pub found: Result<Found, String>,
+}
+
+impl Search {
+    /// # Search the provider for the avatar ID, the way VRCX does
+    pub async fn run(self, client: &Client, avatar_id: &str) -> Lookup {
+        let found = match &self.url {
+            Some(url) => find(client, url, self.timeout, avatar_id).await,
+            None => Ok(Found::NoSearchApi),
+        };
+
+        Lookup {
+            name: self.name,
+            found,
+        }
+    }
+}
+
+async fn find(
+    client: &Client,
+    url: &str,
+    timeout: Duration,
+    avatar_id: &str,
+) -> Result<Found, String> {
+    let response = client
+        .get(url)
+        .header("User-Agent", USER_AGENT)
+        .query(&[("search", avatar_id), ("n", RESULTS)])
+        .timeout(timeout)
+        .send()
+        .await
+        .map_err(|error| check::describe(&error))?;
+
+    let status = response.status();
+    let text = response
+        .text()
+        .await
+        .map_err(|error| check::describe(&error))?;
+    debug!("[Lookup] {url} | {status} | {text}");
+    match status {
+        StatusCode::OK => parse(&text, avatar_id),
+        // Some answer an empty search with 404
+        StatusCode::NOT_FOUND => Ok(Found::Unknown),
+        _ => Err(format!("Unexpected answer: {status}")),
+    }
+}
+
+/// # Find the avatar among the search results
+fn parse(text: &str, avatar_id: &str) -> Result<Found, String> {
+    let Ok(Value::Array(avatars)) = serde_json::from_str(text) else {
+        return Err(String::from("The response isn't a list of avatars"));
+    };
+
+    Ok(avatars
+        .iter()
+        .find(|avatar| avatar.get("id").and_then(Value::as_str) == Some(avatar_id))
+        .map_or(Found::Unknown, |avatar| {
+            Found::Known(avatar_metadata(avatar))
+        }))
+}
+
+/// # Searches for every enabled provider, `url` is `None` for those without a search API
+#[must_use]
+pub fn searches(settings: &Settings) -> Vec<Search> {
+    #[cfg_attr(not(feature = "custom"), allow(unused_mut))]
+    let mut searches = registry::descriptors()
+        .into_iter()
+        .filter(|descriptor| settings.is_enabled(descriptor.kind))
+        .filter(|descriptor| descriptor.kind != ProviderKind::CUSTOM)
+        .map(|descriptor| {
+            let kind = descriptor.kind;
+            let search_url = descriptor.capabilities.search_url;
+            Search {
+                name:    kind.to_string(),
+                url:     search_url.map(|default| settings.search_url(kind, default)),
+                timeout: settings.timeout(kind),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Added one by one, none of the `custom_providers` have a search API
+    #[cfg(feature = "custom")]
+    if settings.is_enabled(ProviderKind::CUSTOM) {
+        let custom = settings
+            .custom_providers
+            .iter()
+            .filter(|config| config.enabled);
+        searches.extend(custom.map(|config| Search {
+            name:    config.name.clone(),
+            url:     None,
+            timeout: Duration::ZERO,
+        }));
+    }
+    searches.sort_by(|a, b| a.name.cmp(&b.name));
+
+    searches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AVATAR_ID: &str = "avtr_c38a1615-5bf5-42b4-84eb-a8b6c37cbd11";
+
+    #[test]
+    fn finds_the_avatar_among_the_results() {
+        let results = format!(
+            r#"[
+                {{"id":"avtr_other","name":"Other"}},
+                {{"id":"{AVATAR_ID}","name":"Robot","authorName":"VRChat","authorId":"usr_1","thumbnailImageUrl":"https://example.com/robot.png"}}
+            ]"#
+        );
+
+        let Ok(Found::Known(metadata)) = parse(&results, AVATAR_ID) else {
+            panic!("the avatar should be found");
+        };
+        assert_eq!(metadata.name.as_deref(), Some("Robot"));
+        assert_eq!(metadata.author.as_deref(), Some("VRChat"));
+        assert_eq!(metadata.author_id.as_deref(), Some("usr_1"));
+        assert_eq!(
+            metadata.thumbnail_url.as_deref(),
+            Some("https://example.com/robot.png")
+        );
+    }
+
+    #[test]
+    fn only_an_exact_id_counts() {
+        let results = r#"[{"id":"avtr_other","name":"Robot"}]"#;
+
+        assert_eq!(parse(results, AVATAR_ID), Ok(Found::Unknown));
+        assert_eq!(parse("[]", AVATAR_ID), Ok(Found::Unknown));
+        assert!(parse("<html>", AVATAR_ID).is_err());
+        assert!(parse(r#"{"error":"rate limited"}"#, AVATAR_ID).is_err());
+    }
+}
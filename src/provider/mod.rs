@@ -1,11 +1,18 @@
+use std::fmt;
+
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
-use strum::{Display, EnumIter};
+use futures::{StreamExt, stream::FuturesOrdered};
+use itertools::Itertools;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de, ser};
+use serde_json::Value;
+use tokio::sync::oneshot;
 
 #[cfg(feature = "avtrdb")]
 pub mod avtrdb;
 #[cfg(feature = "avtrzip")]
 pub mod avtrzip;
+#[cfg(feature = "custom")]
+pub mod custom;
 #[cfg(feature = "cutedb")]
 pub mod cutedb;
 #[cfg(feature = "kitsunedb")]
@@ -19,38 +26,176 @@ pub mod vrcdb;
 #[cfg(feature = "vrcwb")]
 pub mod vrcwb;
 
+pub mod batch;
+pub mod buffer;
+pub mod check;
+pub mod health;
+pub mod limit;
+pub mod lookup;
 pub mod prelude;
+pub mod preview;
+pub mod registry;
+pub mod retry;
+
+/// # A provider, told apart by the bit marking avatar IDs sent to it in the cache
+///
+/// The built-in providers have constants, whether or not their feature is enabled.
+/// Names and config file keys come from the `registry`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProviderKind(u32);
+
+impl ProviderKind {
+    pub const AVTRDB: Self = Self(1 << 0);
+    pub const NSVR: Self = Self(1 << 1);
+    pub const PAW: Self = Self(1 << 2);
+    pub const VRCDB: Self = Self(1 << 3);
+    pub const VRCWB: Self = Self(1 << 4);
+    pub const AVTRZIP: Self = Self(1 << 5);
+    pub const KITSUNEDB: Self = Self(1 << 6);
+    pub const CUTEDB: Self = Self(1 << 7);
+    /// Enables the `custom_providers`, which each get their own bit
+    pub const CUSTOM: Self = Self(1 << 8);
+
+    /// # A provider from another crate, see `registry::register`
+    #[must_use]
+    pub const fn new(bit: u32) -> Self {
+        Self(bit)
+    }
+
+    /// # Bit marking avatar IDs sent to this provider in the cache
+    #[must_use]
+    pub const fn bit(self) -> u32 {
+        self.0
+    }
+
+    /// # Every registered provider
+    pub fn iter() -> impl Iterator<Item = Self> {
+        registry::descriptors()
+            .into_iter()
+            .map(|descriptor| descriptor.kind)
+    }
+
+    /// # Find the registered `ProviderKind` stored in the cache as this bit
+    #[must_use]
+    pub fn from_bit(bit: u32) -> Option<Self> {
+        Self::iter().find(|kind| kind.bit() == bit)
+    }
+
+    #[must_use]
+    pub fn descriptor(self) -> Option<registry::Descriptor> {
+        registry::get(self)
+    }
+}
+
+impl fmt::Display for ProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.descriptor() {
+            Some(descriptor) => f.write_str(descriptor.name),
+            None => write!(f, "Provider {}", self.0),
+        }
+    }
+}
+
+impl Serialize for ProviderKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(descriptor) = self.descriptor() else {
+            return Err(ser::Error::custom(format!("{self} isn't registered")));
+        };
+
+        serializer.serialize_str(descriptor.id)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProviderKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        registry::find(&id)
+            .map(|descriptor| descriptor.kind)
+            .ok_or_else(|| de::Error::custom(format!("unknown provider {id}")))
+    }
+}
+
+/// # Resolves once a batching provider ingested the avatar ID
+///
+/// Some(true): New/Unique | Some(false): Duplicate/Existing | None: The provider didn't say.
+/// Dropped without a value if the batch wasn't delivered before shutdown.
+pub type Receipt = oneshot::Receiver<Option<bool>>;
+
+/// # Details a provider returned about an avatar it already knew
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AvatarMetadata {
+    pub name:          Option<String>,
+    pub author:        Option<String>,
+    pub author_id:     Option<String>,
+    pub platform:      Option<String>,
+    pub thumbnail_url: Option<String>,
+}
+
+/// # Read the details from an avatar object, as PAW and the VRCX search APIs return them
+#[must_use]
+pub(crate) fn avatar_metadata(avatar: &Value) -> AvatarMetadata {
+    let string = |pointers: &[&str]| {
+        pointers
+            .iter()
+            .find_map(|pointer| avatar.pointer(pointer)?.as_str())
+            .map(String::from)
+    };
+
+    AvatarMetadata {
+        name:          string(&["/name"]),
+        author:        string(&["/author/name", "/authorName", "/author"]),
+        author_id:     string(&["/author/id", "/authorId"]),
+        platform:      avatar
+            .get("platforms")
+            .and_then(Value::as_array)
+            .map(|platforms| platforms.iter().filter_map(Value::as_str).join(", "))
+            .or_else(|| string(&["/platform"])),
+        thumbnail_url: string(&["/thumbnailImageUrl", "/thumbnail", "/imageUrl"]),
+    }
+}
+
+/// # What the provider answered for an avatar ID
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Outcome {
+    /// True: New/Unique | False: Duplicate/Existing.
+    pub unique:   bool,
+    pub metadata: Option<AvatarMetadata>,
+}
+
+impl From<bool> for Outcome {
+    fn from(unique: bool) -> Self {
+        Self {
+            unique,
+            metadata: None,
+        }
+    }
+}
+
+/// # How far an avatar ID got after handing it to the provider
+pub enum Delivery {
+    /// The provider answered right away.
+    Confirmed(Outcome),
+    /// The provider batches IDs, the receipt resolves after the batch was flushed.
+    Pending(Receipt),
+}
+
+/// # An avatar ID queued for a batching provider's actor
+pub struct Submission {
+    pub avatar_id: String,
+    pub receipt:   oneshot::Sender<Option<bool>>,
+}
+
+impl Submission {
+    #[must_use]
+    pub fn new(avatar_id: &str) -> (Self, Receipt) {
+        let (tx, rx) = oneshot::channel();
+        let submission = Self {
+            avatar_id: avatar_id.to_string(),
+            receipt:   tx,
+        };
 
-#[repr(u32)]
-#[derive(
-    EnumIter, Display, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Hash,
-)]
-pub enum ProviderKind {
-    #[cfg(feature = "avtrdb")]
-    #[strum(to_string = "avtrDB - Avatar Search")]
-    AVTRDB  = 1 << 0,
-    #[cfg(feature = "nsvr")]
-    #[strum(to_string = "NSVR - NekoSune Community")]
-    #[serde(alias = "VRCDS")]
-    NSVR    = 1 << 1,
-    #[cfg(feature = "paw")]
-    #[strum(to_string = "PAW - Puppy's Avatar World")]
-    PAW     = 1 << 2,
-    #[cfg(feature = "vrcdb")]
-    #[strum(to_string = "VRCDB - Avatar Search")]
-    VRCDB   = 1 << 3,
-    #[cfg(feature = "vrcwb")]
-    #[strum(to_string = "VRCWB - World Balancer")]
-    VRCWB   = 1 << 4,
-    #[cfg(feature = "avtrzip")]
-    #[strum(to_string = "avtr․zip - Advanced Avatar Search")]
-    AVTRZIP = 1 << 5,
-    #[cfg(feature = "kitsunedb")]
-    #[strum(to_string = "KitsuneDB - Avatar Database")]
-    KITSUNEDB = 1 << 6,
-    #[cfg(feature = "cutedb")]
-    #[strum(to_string = "CuteDB - Cutest Avatar Search")]
-    CUTEDB    = 1 << 7,
+        (submission, rx)
+    }
 }
 
 #[async_trait]
@@ -58,6 +203,26 @@ pub trait Provider: Sync + Send {
     /// # Return the `ProviderKind`
     fn kind(&self) -> ProviderKind;
 
+    /// # Bit marking avatar IDs sent to this provider in the cache
+    fn bit(&self) -> u32 {
+        self.kind().bit()
+    }
+
+    /// # Name shown in the console
+    fn name(&self) -> String {
+        self.kind().to_string()
+    }
+
+    /// # Avatar IDs `submit_each` hands to the provider at the same time
+    fn max_concurrency(&self) -> usize {
+        1
+    }
+
+    /// # Whether `send_avatar_ids` sends the chunk in one request instead of one ID at a time
+    fn sends_in_bulk(&self) -> bool {
+        false
+    }
+
     /// # Send avatar ID to the provider
     ///
     /// True: New/Unique | False: Duplicate/Existing.
@@ -65,6 +230,53 @@ pub trait Provider: Sync + Send {
     /// # Errors
     /// Will return `Err` if anything errors
     async fn send_avatar_id(&self, avatar_id: &str) -> anyhow::Result<bool>;
+
+    /// # Hand avatar ID to the provider without waiting for a batch to be flushed
+    ///
+    /// Batching providers override this to return `Delivery::Pending`,
+    /// providers that know about the avatar can attach `AvatarMetadata`.
+    ///
+    /// # Errors
+    /// Will return `Err` if anything errors
+    async fn submit_avatar_id(&self, avatar_id: &str) -> anyhow::Result<Delivery> {
+        let unique = self.send_avatar_id(avatar_id).await?;
+        Ok(Delivery::Confirmed(unique.into()))
+    }
+
+    /// # Hand a chunk of avatar IDs to the provider
+    ///
+    /// Returns one result per avatar ID, in the same order.
+    /// Providers with bulk endpoints override this, the rest submit one ID at a time.
+    async fn send_avatar_ids(&self, avatar_ids: &[String]) -> Vec<anyhow::Result<Delivery>> {
+        submit_each(self, avatar_ids).await
+    }
+}
+
+/// # Submit avatar IDs up to `Provider::max_concurrency` at a time, the default for `Provider::send_avatar_ids`
+///
+/// Results stay in the same order as the avatar IDs.
+pub async fn submit_each<P: Provider + ?Sized>(
+    provider: &P,
+    avatar_ids: &[String],
+) -> Vec<anyhow::Result<Delivery>> {
+    let limit = provider.max_concurrency().max(1);
+    let mut results = Vec::with_capacity(avatar_ids.len());
+    let mut in_flight = FuturesOrdered::new();
+    let mut avatar_ids = avatar_ids.iter();
+    loop {
+        while in_flight.len() < limit
+            && let Some(avatar_id) = avatar_ids.next()
+        {
+            in_flight.push_back(provider.submit_avatar_id(avatar_id));
+        }
+
+        let Some(result) = in_flight.next().await else {
+            break;
+        };
+        results.push(result);
+    }
+
+    results
 }
 
 // https://stackoverflow.com/a/72239266
@@ -74,3 +286,62 @@ macro_rules! provider {
         std::sync::Arc::new(Box::new($x) as Box<dyn $crate::provider::Provider>)
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use anyhow::Result;
+
+    use super::*;
+
+    /// Answers slower for earlier IDs, and remembers how many were in flight at once
+    #[derive(Default)]
+    struct SlowProvider {
+        in_flight:     AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Provider for SlowProvider {
+        fn kind(&self) -> ProviderKind {
+            ProviderKind::iter().next().unwrap()
+        }
+
+        fn max_concurrency(&self) -> usize {
+            3
+        }
+
+        async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+
+            let index = avatar_id.trim_start_matches("avtr_").parse::<u64>()?;
+            tokio::time::sleep(Duration::from_millis(100 - index * 10)).await;
+
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(index % 2 == 0)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn submit_each_keeps_order_within_the_concurrency_limit() {
+        let provider = SlowProvider::default();
+        let avatar_ids = (0..8).map(|i| format!("avtr_{i}")).collect::<Vec<_>>();
+
+        let results = submit_each(&provider, &avatar_ids).await;
+
+        let unique = results
+            .into_iter()
+            .map(|result| match result.unwrap() {
+                Delivery::Confirmed(outcome) => outcome.unique,
+                Delivery::Pending(_) => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(unique, [true, false, true, false, true, false, true, false]);
+        assert_eq!(provider.max_in_flight.load(Ordering::SeqCst), 3);
+    }
+}
@@ -2,65 +2,129 @@ use std::time::Duration;
 
 use anyhow::{Result, bail};
 use async_trait::async_trait;
-use reqwest::{Client, StatusCode};
-use serde_json::json;
+use futures::future::BoxFuture;
+use reqwest::{Client, Method, StatusCode};
+use serde_json::{Value, json};
 
 use crate::{
     USER_AGENT,
-    provider::{Provider, ProviderKind},
+    provider::{
+        Provider,
+        ProviderKind,
+        check::{Probe, TEST_ATTRIBUTION, TEST_AVATAR_ID},
+        limit::RateLimiter,
+        preview::Payload,
+        registry::{Built, Capabilities, Context, Descriptor},
+        retry::RetryPolicy,
+    },
     settings::Settings,
 };
 
 const URL: &str = "https://api-avatar.nekosunevr.co.uk/v1/vrchat/avatars/store/putavatarExternal";
 
+/// VRCX search API, asked by `vrc-log lookup`
+pub const SEARCH_URL: &str = "https://vrcavatarsearch.nekosunevr.co.uk/vrcx_search";
+
+pub const DESCRIPTOR: Descriptor = Descriptor {
+    kind: ProviderKind::NSVR,
+    id: "NSVR",
+    aliases: &["VRCDS"],
+    name: "NSVR - NekoSune Community",
+    enabled_by_default: true,
+    build,
+    capabilities: Capabilities {
+        probe:      Some(probe),
+        payloads:   Some(payloads),
+        search_url: Some(SEARCH_URL),
+    },
+};
+
 pub struct NSVR<'s> {
     settings: &'s Settings,
     client:   Client,
+    url:      String,
+    retry:    RetryPolicy,
+    limiter:  RateLimiter,
+    timeout:  Duration,
 }
 
 impl<'s> NSVR<'s> {
     #[must_use]
-    pub fn new(settings: &'s Settings) -> Self {
+    pub fn new(settings: &'s Settings, client: Client) -> Self {
+        Self::new_with_url(settings, client, settings.url(ProviderKind::NSVR, URL))
+    }
+
+    #[must_use]
+    pub fn new_with_url(settings: &'s Settings, client: Client, url: String) -> Self {
         Self {
             settings,
-            client: Client::default(),
+            client,
+            url,
+            retry: settings.provider_settings(ProviderKind::NSVR).retry.into(),
+            limiter: RateLimiter::new(settings.rate_limit(ProviderKind::NSVR)),
+            timeout: settings.timeout(ProviderKind::NSVR),
         }
     }
 }
 
+fn build(context: Context) -> BoxFuture<'static, Result<Built>> {
+    Box::pin(async move {
+        let provider = NSVR::new(context.settings, context.client);
+        Ok(Built::provider(
+            context.settings,
+            crate::provider!(provider),
+        ))
+    })
+}
+
+fn body(avatar_id: &str, attribution: &str) -> Value {
+    json!({ "id": avatar_id, "userid": attribution })
+}
+
+/// # One request per avatar ID
+#[must_use]
+pub fn payloads(settings: &Settings, avatar_ids: &[String], attribution: &str) -> Vec<Payload> {
+    let url = settings.url(ProviderKind::NSVR, URL);
+    avatar_ids
+        .iter()
+        .map(|avatar_id| Payload::new(Method::POST, &url).json(&body(avatar_id, attribution)))
+        .collect()
+}
+
+/// # Submits `TEST_AVATAR_ID`, which is already known
+#[must_use]
+pub fn probe(settings: &Settings) -> Probe {
+    let json = body(TEST_AVATAR_ID, TEST_ATTRIBUTION);
+    let url = settings.url(ProviderKind::NSVR, URL);
+    Probe::new(ProviderKind::NSVR, Method::POST, url)
+        .json(&json)
+        .accepted(&[200, 404, 503])
+}
+
 #[async_trait]
 impl Provider for NSVR<'_> {
     fn kind(&self) -> ProviderKind {
         ProviderKind::NSVR
     }
 
+    fn max_concurrency(&self) -> usize {
+        self.limiter.max_concurrency()
+    }
+
     async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
         let kind = self.kind();
-        let json = json!({
-            "id": avatar_id,
-            "userid": self.settings.attribution.get_user_id().await,
-        });
+        let json = body(avatar_id, &self.settings.attribution.get_user_id().await);
 
         debug!("[{kind}] Sending {json:#?}");
 
-        let response = match self
-            .client
-            .post(URL)
-            .header("User-Agent", USER_AGENT)
-            .json(&json)
-            .timeout(Duration::from_secs(5))
-            .send()
-            .await
-        {
-            Ok(response) => response,
-            Err(error) => {
-                // Ignore for cache purposes, it goes offline too often.
-                // TODO: Remove when API is more stable.
-                warn!("[{kind}] {error}");
-                return Ok(false);
-            }
+        let request = || {
+            self.client
+                .post(&self.url)
+                .header("User-Agent", USER_AGENT)
+                .json(&json)
+                .timeout(self.timeout)
         };
-
+        let response = self.retry.send(kind, &self.limiter, request).await?;
         let status = response.status();
         let text = response.text().await?;
         debug!("[{kind}] {status} | {text}");
@@ -68,11 +132,6 @@ impl Provider for NSVR<'_> {
         let unique = match status {
             StatusCode::SERVICE_UNAVAILABLE | StatusCode::OK => false,
             StatusCode::NOT_FOUND => true,
-            StatusCode::TOO_MANY_REQUESTS => {
-                warn!("[{kind}] 429 Rate Limit, Please Wait 1 Minute...");
-                tokio::time::sleep(Duration::from_mins(1)).await;
-                Box::pin(self.send_avatar_id(avatar_id)).await?
-            }
             _ => bail!("[{kind}] {status} | {text}"),
         };
 
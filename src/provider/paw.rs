@@ -2,27 +2,69 @@ use std::time::Duration;
 
 use anyhow::{bail, Result};
 use async_trait::async_trait;
-use reqwest::{Client, StatusCode};
+use futures::future::BoxFuture;
+use reqwest::{Client, Method, StatusCode};
 use serde::Deserialize;
 use serde_json::Value;
 
 use crate::{
-    provider::{Provider, ProviderKind},
+    provider::{
+        check::{Probe, TEST_AVATAR_ID},
+        limit::RateLimiter,
+        preview::Payload,
+        registry::{Built, Capabilities, Context, Descriptor},
+        retry::RetryPolicy,
+        avatar_metadata,
+        Delivery,
+        Outcome,
+        Provider,
+        ProviderKind,
+    },
     settings::Settings,
     USER_AGENT,
 };
 
 const URL: &str = "https://paw-api.amelia.fun/update";
 
+/// VRCX search API, asked by `vrc-log lookup`
+pub const SEARCH_URL: &str = "https://paw-api.amelia.fun/vrcx_search";
+
+pub const DESCRIPTOR: Descriptor = Descriptor {
+    kind:               ProviderKind::PAW,
+    id:                 "PAW",
+    aliases:            &[],
+    name:               "PAW - Puppy's Avatar World",
+    enabled_by_default: true,
+    build,
+    capabilities:       Capabilities {
+        probe:      Some(probe),
+        payloads:   Some(|settings, avatar_ids, _| payloads(settings, avatar_ids)),
+        search_url: Some(SEARCH_URL),
+    },
+};
+
 pub struct Paw {
-    client: Client,
+    client:  Client,
+    url:     String,
+    retry:   RetryPolicy,
+    limiter: RateLimiter,
+    timeout: Duration,
 }
 
 impl Paw {
     #[must_use]
-    pub fn new(_settings: &Settings) -> Self {
+    pub fn new(settings: &Settings, client: Client) -> Self {
+        Self::new_with_url(settings, client, settings.url(ProviderKind::PAW, URL))
+    }
+
+    #[must_use]
+    pub fn new_with_url(settings: &Settings, client: Client, url: String) -> Self {
         Self {
-            client: Client::default(),
+            client,
+            url,
+            retry: settings.provider_settings(ProviderKind::PAW).retry.into(),
+            limiter: RateLimiter::new(settings.rate_limit(ProviderKind::PAW)),
+            timeout: settings.timeout(ProviderKind::PAW),
         }
     }
 }
@@ -36,42 +78,85 @@ struct PawResponse {
     avatar:  Option<Value>,
 }
 
-#[async_trait]
-impl Provider for Paw {
-    fn kind(&self) -> ProviderKind {
-        ProviderKind::PAW
+impl PawResponse {
+    fn into_outcome(self) -> Outcome {
+        let avatar = self.avatar.filter(|a| {
+            !(a.is_null() || (a.is_array() && a.as_array().is_some_and(Vec::is_empty)))
+        });
+
+        Outcome {
+            unique:   avatar.is_none(),
+            metadata: avatar.as_ref().map(avatar_metadata),
+        }
     }
+}
 
-    async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
+impl Paw {
+    async fn push(&self, avatar_id: &str) -> Result<Outcome> {
         let kind = self.kind();
-        let response = self
-            .client
-            .post(URL)
-            .header("User-Agent", USER_AGENT)
-            .query(&[("avatarId", avatar_id)])
-            .timeout(Duration::from_secs(3))
-            .send()
-            .await?;
+        let request = || {
+            self.client
+                .post(&self.url)
+                .header("User-Agent", USER_AGENT)
+                .query(&[("avatarId", avatar_id)])
+                .timeout(self.timeout)
+        };
+        let response = self.retry.send(kind, &self.limiter, request).await?;
 
         let status = response.status();
         let text = response.text().await?;
         debug!("[{kind}] {status} | {text}");
 
-        let unique = match status {
-            StatusCode::OK => {
-                let data = serde_json::from_str::<PawResponse>(&text)?;
-                data.avatar.as_ref().is_none_or(|a| {
-                    a.is_null() || (a.is_array() && a.as_array().is_some_and(Vec::is_empty))
-                })
-            }
-            StatusCode::TOO_MANY_REQUESTS => {
-                warn!("[{kind}] 429 Rate Limit, Please Wait 10 seconds...");
-                tokio::time::sleep(Duration::from_secs(10)).await;
-                Box::pin(self.send_avatar_id(avatar_id)).await?
-            }
+        let outcome = match status {
+            StatusCode::OK => serde_json::from_str::<PawResponse>(&text)?.into_outcome(),
             _ => bail!("[{kind}] {status} | {text}"),
         };
 
-        Ok(unique)
+        Ok(outcome)
+    }
+}
+
+fn build(context: Context) -> BoxFuture<'static, Result<Built>> {
+    Box::pin(async move {
+        let provider = Paw::new(context.settings, context.client);
+        Ok(Built::provider(context.settings, crate::provider!(provider)))
+    })
+}
+
+/// # One request per avatar ID, without attribution or a body
+#[must_use]
+pub fn payloads(settings: &Settings, avatar_ids: &[String]) -> Vec<Payload> {
+    let url = settings.url(ProviderKind::PAW, URL);
+    avatar_ids
+        .iter()
+        .map(|avatar_id| Payload::new(Method::POST, format!("{url}?avatarId={avatar_id}")))
+        .collect()
+}
+
+/// # Looks up `TEST_AVATAR_ID`, which is already known
+#[must_use]
+pub fn probe(settings: &Settings) -> Probe {
+    let url = settings.url(ProviderKind::PAW, URL);
+    let url = format!("{url}?avatarId={TEST_AVATAR_ID}");
+    Probe::new(ProviderKind::PAW, Method::POST, url)
+        .parses(|text| serde_json::from_str::<PawResponse>(text).is_ok())
+}
+
+#[async_trait]
+impl Provider for Paw {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::PAW
+    }
+
+    fn max_concurrency(&self) -> usize {
+        self.limiter.max_concurrency()
+    }
+
+    async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
+        Ok(self.push(avatar_id).await?.unique)
+    }
+
+    async fn submit_avatar_id(&self, avatar_id: &str) -> Result<Delivery> {
+        Ok(Delivery::Confirmed(self.push(avatar_id).await?))
     }
 }
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, fmt};
+
+use reqwest::Method;
+
+use crate::{provider::registry, settings::Settings};
+
+/// # Request a provider would send, printed instead of sent by `--dry-run`
+pub struct Payload {
+    pub method:  Method,
+    pub url:     String,
+    pub headers: Vec<(String, String)>,
+    pub body:    Option<String>,
+}
+
+impl Payload {
+    #[must_use]
+    pub fn new(method: Method, url: impl Into<String>) -> Self {
+        Self {
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    #[must_use]
+    pub fn json(mut self, body: &serde_json::Value) -> Self {
+        self.body = Some(body.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.method, self.url)?;
+        for (header, value) in &self.headers {
+            write!(f, "\n{header}: {value}")?;
+        }
+        if let Some(body) = &self.body {
+            write!(f, "\n{body}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// # What a provider would be sent
+pub struct Preview {
+    pub name:       String,
+    pub avatar_ids: Vec<String>,
+    pub payloads:   Vec<Payload>,
+}
+
+/// # Avatar IDs the cache doesn't mark as sent with this bit, sorted
+#[must_use]
+pub(crate) fn unsent(checked: &HashMap<String, u32>, bit: u32) -> Vec<String> {
+    let mut avatar_ids = checked
+        .iter()
+        .filter(|(_, bits)| *bits & bit == 0)
+        .map(|(id, _)| id.clone())
+        .collect::<Vec<_>>();
+    avatar_ids.sort();
+
+    avatar_ids
+}
+
+/// # What every enabled provider would be sent
+///
+/// `checked` maps the avatar IDs to their provider bits, like `Cache::check_all_ids` returns them.
+#[must_use]
+pub(crate) fn previews(
+    settings: &Settings,
+    checked: &HashMap<String, u32>,
+    attribution: &str,
+) -> Vec<Preview> {
+    #[cfg_attr(not(feature = "custom"), allow(unused_mut))]
+    let mut previews = registry::descriptors()
+        .into_iter()
+        .filter(|descriptor| settings.is_enabled(descriptor.kind))
+        .filter_map(|descriptor| {
+            let payloads = descriptor.capabilities.payloads?;
+            let avatar_ids = unsent(checked, descriptor.kind.bit());
+
+            Some(Preview {
+                name: descriptor.name.to_string(),
+                payloads: payloads(settings, &avatar_ids, attribution),
+                avatar_ids,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // One preview for each of the `custom_providers`
+    #[cfg(feature = "custom")]
+    if settings.is_enabled(super::ProviderKind::CUSTOM) {
+        previews.extend(super::custom::previews(settings, checked, attribution));
+    }
+
+    previews
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_out_avatars_already_sent() {
+        let checked = HashMap::from([
+            (String::from("avtr_c"), 0b01),
+            (String::from("avtr_b"), 0b00),
+            (String::from("avtr_a"), 0b10),
+        ]);
+
+        assert_eq!(unsent(&checked, 0b10), ["avtr_b", "avtr_c"]);
+        assert_eq!(unsent(&checked, 0b11), ["avtr_b"]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "avtrdb", feature = "nsvr"))]
+    fn shows_every_request_with_the_attribution() {
+        use crate::provider::ProviderKind;
+
+        let settings = Settings {
+            providers: HashMap::from([(ProviderKind::AVTRDB, true), (ProviderKind::NSVR, true)]),
+            ..Default::default()
+        };
+        let checked = HashMap::from([
+            (String::from("avtr_a"), ProviderKind::NSVR.bit()),
+            (String::from("avtr_b"), 0),
+        ]);
+
+        let previews = previews(&settings, &checked, "1234");
+        let payloads = |index: usize| {
+            previews[index]
+                .payloads
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(previews[0].avatar_ids, ["avtr_a", "avtr_b"]);
+        assert_eq!(
+            payloads(0),
+            [concat!(
+                "POST https://api.avtrdb.com/v3/avatar/ingest\n",
+                r#"{"attribution":"1234","avatar_ids":["avtr_a","avtr_b"]}"#
+            )]
+        );
+        assert_eq!(previews[1].avatar_ids, ["avtr_b"]);
+        assert_eq!(
+            payloads(1),
+            [concat!(
+                "POST https://api-avatar.nekosunevr.co.uk/v1/vrchat/avatars/store/putavatarExternal\n",
+                r#"{"id":"avtr_b","userid":"1234"}"#
+            )]
+        );
+    }
+}
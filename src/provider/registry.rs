@@ -0,0 +1,268 @@
+use std::sync::{Arc, LazyLock, PoisonError, RwLock};
+
+use anyhow::{Result, bail};
+use futures::future::BoxFuture;
+use reqwest::Client;
+
+#[cfg(feature = "cache")]
+use crate::cache::Cache;
+use crate::{
+    provider::{Provider, ProviderKind, check::Probe, health::Guarded, preview::Payload},
+    settings::Settings,
+};
+
+/// # Build an enabled provider, along with the actor flushing its batches if it has one
+pub type Build = fn(Context) -> BoxFuture<'static, Result<Built>>;
+
+/// # Requests submitting the avatar IDs, credited to the attribution
+pub type Payloads = fn(&Settings, &[String], &str) -> Vec<Payload>;
+
+/// # Everything a provider is built from
+#[derive(Clone)]
+pub struct Context {
+    pub settings: &'static Settings,
+    pub client:   Client,
+    /// Keeps batches across restarts, `None` keeps them in memory only
+    #[cfg(feature = "cache")]
+    pub cache:    Option<Cache>,
+}
+
+/// # Actor flushing a batching provider's buffer
+///
+/// Runs until every sender of its provider is dropped, then flushes what's left.
+pub struct Actor {
+    pub name: String,
+    pub run:  BoxFuture<'static, Result<()>>,
+}
+
+/// # Providers a descriptor built, with their actors
+#[derive(Default)]
+pub struct Built {
+    pub providers: Vec<Arc<Box<dyn Provider>>>,
+    pub actors:    Vec<Actor>,
+}
+
+impl Built {
+    /// # A provider guarded by its health settings
+    #[must_use]
+    pub fn provider(settings: &Settings, provider: Arc<Box<dyn Provider>>) -> Self {
+        Self::guarded(Guarded::from_settings(settings, provider))
+    }
+
+    /// # A provider already guarded, when its actor needs the `Breaker`
+    #[must_use]
+    pub fn guarded(provider: Guarded) -> Self {
+        Self {
+            providers: vec![crate::provider!(provider)],
+            actors:    Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_actor(
+        mut self,
+        name: &str,
+        run: impl Future<Output = Result<()>> + Send + 'static,
+    ) -> Self {
+        self.actors.push(Actor {
+            name: name.to_string(),
+            run:  Box::pin(run),
+        });
+        self
+    }
+}
+
+/// # What a provider supports besides submitting avatars
+#[derive(Clone, Copy, Default)]
+pub struct Capabilities {
+    /// Harmless request `vrc-log providers check` sends
+    pub probe:      Option<fn(&Settings) -> Probe>,
+    /// Requests `--dry-run` prints
+    pub payloads:   Option<Payloads>,
+    /// VRCX search API `vrc-log lookup` asks
+    pub search_url: Option<&'static str>,
+}
+
+/// # A provider VRC-LOG can submit to
+#[derive(Clone, Copy)]
+pub struct Descriptor {
+    pub kind: ProviderKind,
+    /// Key in the config file
+    pub id: &'static str,
+    /// Keys older config files still use
+    pub aliases: &'static [&'static str],
+    pub name: &'static str,
+    /// Selected in the setup wizard
+    pub enabled_by_default: bool,
+    pub build: Build,
+    pub capabilities: Capabilities,
+}
+
+static REGISTRY: LazyLock<RwLock<Vec<Descriptor>>> = LazyLock::new(|| RwLock::new(built_in()));
+
+fn built_in() -> Vec<Descriptor> {
+    vec![
+        #[cfg(feature = "avtrdb")]
+        super::avtrdb::DESCRIPTOR,
+        #[cfg(feature = "nsvr")]
+        super::nsvr::DESCRIPTOR,
+        #[cfg(feature = "paw")]
+        super::paw::DESCRIPTOR,
+        #[cfg(feature = "vrcdb")]
+        super::vrcdb::DESCRIPTOR,
+        #[cfg(feature = "vrcwb")]
+        super::vrcwb::DESCRIPTOR,
+        #[cfg(feature = "avtrzip")]
+        super::avtrzip::DESCRIPTOR,
+        #[cfg(feature = "kitsunedb")]
+        super::kitsunedb::DESCRIPTOR,
+        #[cfg(feature = "cutedb")]
+        super::cutedb::DESCRIPTOR,
+        #[cfg(feature = "custom")]
+        super::custom::DESCRIPTOR,
+    ]
+}
+
+/// # Add a provider from another crate
+///
+/// Register it before the settings are loaded, its entries in the config file are dropped otherwise.
+/// Bits `1 << 9` to `1 << 15` are free, the built-in and custom providers take the others.
+///
+/// # Errors
+/// Will return `Err` if the bit isn't a single free one, or the ID is taken
+pub fn register(descriptor: Descriptor) -> Result<()> {
+    let bit = descriptor.kind.bit();
+    if !bit.is_power_of_two() {
+        bail!("[{}] {bit} isn't a single bit", descriptor.name);
+    }
+    #[cfg(feature = "custom")]
+    if super::custom::bit(0).is_some_and(|first| bit >= first) {
+        bail!(
+            "[{}] Bit {bit} is taken by custom providers",
+            descriptor.name
+        );
+    }
+
+    let mut registry = REGISTRY.write().unwrap_or_else(PoisonError::into_inner);
+    let ids = |descriptor: &Descriptor| {
+        let mut ids = descriptor.aliases.to_vec();
+        ids.push(descriptor.id);
+        ids
+    };
+    if let Some(taken) = registry.iter().find(|registered| {
+        registered.kind == descriptor.kind
+            || ids(registered)
+                .iter()
+                .any(|id| ids(&descriptor).contains(id))
+    }) {
+        bail!(
+            "[{}] The bit or ID is taken by {}",
+            descriptor.name,
+            taken.name
+        );
+    }
+
+    registry.push(descriptor);
+    drop(registry);
+
+    Ok(())
+}
+
+/// # Every registered provider, the built-in ones first
+#[must_use]
+pub fn descriptors() -> Vec<Descriptor> {
+    REGISTRY
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+#[must_use]
+pub fn get(kind: ProviderKind) -> Option<Descriptor> {
+    descriptors()
+        .into_iter()
+        .find(|descriptor| descriptor.kind == kind)
+}
+
+/// # Look a provider up by its key in the config file
+#[must_use]
+pub fn find(id: &str) -> Option<Descriptor> {
+    descriptors()
+        .into_iter()
+        .find(|descriptor| descriptor.id == id || descriptor.aliases.contains(&id))
+}
+
+/// # Build every enabled provider
+///
+/// # Errors
+/// Will return `Err` if a provider is misconfigured
+pub async fn build(context: Context) -> Result<Built> {
+    let mut built = Built::default();
+    for descriptor in descriptors() {
+        if !context.settings.is_enabled(descriptor.kind) {
+            continue;
+        }
+
+        let Built { providers, actors } = (descriptor.build)(context.clone()).await?;
+        built.providers.extend(providers);
+        built.actors.extend(actors);
+    }
+
+    Ok(built)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(kind: ProviderKind, id: &'static str) -> Descriptor {
+        Descriptor {
+            kind,
+            id,
+            aliases: &[],
+            name: id,
+            enabled_by_default: false,
+            build: |_| Box::pin(async { Ok(Built::default()) }),
+            capabilities: Capabilities::default(),
+        }
+    }
+
+    #[test]
+    fn built_in_providers_have_their_own_bit_and_id() {
+        let descriptors = built_in();
+        for (index, descriptor) in descriptors.iter().enumerate() {
+            assert!(descriptor.kind.bit().is_power_of_two());
+            for other in &descriptors[index + 1..] {
+                assert_ne!(descriptor.kind, other.kind);
+                assert_ne!(descriptor.id, other.id);
+            }
+        }
+    }
+
+    #[test]
+    fn registers_providers_from_other_crates() {
+        let kind = ProviderKind::new(1 << 15);
+        register(descriptor(kind, "THIRD_PARTY")).unwrap();
+
+        assert_eq!(
+            find("THIRD_PARTY").map(|descriptor| descriptor.kind),
+            Some(kind)
+        );
+        assert_eq!(kind.to_string(), "THIRD_PARTY");
+        assert!(ProviderKind::iter().any(|registered| registered == kind));
+
+        // Taken bits and IDs
+        assert!(register(descriptor(kind, "OTHER")).is_err());
+        assert!(register(descriptor(ProviderKind::new(1 << 14), "THIRD_PARTY")).is_err());
+        assert!(register(descriptor(ProviderKind::new(0b11 << 12), "TWO_BITS")).is_err());
+    }
+
+    #[cfg(feature = "nsvr")]
+    #[test]
+    fn finds_providers_by_their_old_ids() {
+        assert_eq!(
+            find("VRCDS").map(|descriptor| descriptor.kind),
+            Some(ProviderKind::NSVR)
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::{fmt::Display, time::Duration};
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
+use reqwest::{RequestBuilder, Response, StatusCode, header::RETRY_AFTER};
+
+use crate::{provider::limit::RateLimiter, settings::RetrySettings};
+
+/// # Retries rate limited requests with exponential backoff and jitter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay:   Duration,
+    pub max_delay:    Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetrySettings::default().into()
+    }
+}
+
+impl From<RetrySettings> for RetryPolicy {
+    fn from(settings: RetrySettings) -> Self {
+        Self {
+            max_attempts: settings.max_attempts.max(1),
+            base_delay:   Duration::from_secs(settings.base_delay_secs),
+            max_delay:    Duration::from_secs(settings.max_delay_secs),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// # Delay after the attempt, doubling each time with up to half of it as jitter
+    #[must_use]
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let jitter = delay.mul_f64(fastrand::f64()) / 2;
+
+        delay.saturating_sub(jitter)
+    }
+
+    /// # Send the request, retrying rate limits, gateway errors and timeouts
+    ///
+    /// `request` builds a fresh request for every attempt, each waiting for the `limiter`.
+    /// Other statuses are returned as-is for the provider to handle.
+    ///
+    /// # Errors
+    /// Will return `Err` if the request keeps failing, or the provider asks to wait longer than `max_delay`
+    pub async fn send<N, F>(&self, name: N, limiter: &RateLimiter, request: F) -> Result<Response>
+    where
+        N: Display + Send,
+        F: Fn() -> RequestBuilder + Send + Sync,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let last_attempt = attempt >= self.max_attempts;
+            let permit = limiter.acquire().await;
+            let result = request().send().await;
+            drop(permit);
+
+            let (reason, retry_after) = match result {
+                Ok(response) if is_retryable(response.status()) && last_attempt => {
+                    let status = response.status();
+                    bail!("[{name}] Giving up after {attempt} attempts: {status}");
+                }
+                Ok(response) if is_retryable(response.status()) => {
+                    (response.status().to_string(), retry_after(&response))
+                }
+                Ok(response) => return Ok(response),
+                Err(error) if is_transient(&error) && !last_attempt => (error.to_string(), None),
+                Err(error) => return Err(error.into()),
+            };
+
+            let delay = retry_after.unwrap_or_else(|| self.backoff(attempt));
+            if delay > self.max_delay {
+                bail!("[{name}] {reason}, asked to wait {delay:?} which is too long");
+            }
+
+            warn!("[{name}] {reason}, trying again in {delay:?}");
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Other errors mean something the provider has to decide on, like NSVR's 503 for known avatars
+const fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+fn is_transient(error: &reqwest::Error) -> bool {
+    error.is_timeout() || error.is_connect()
+}
+
+/// Seconds or an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_with_jitter_and_caps() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay:   Duration::from_secs(2),
+            max_delay:    Duration::from_mins(1),
+        };
+
+        for _ in 0..100 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_secs(1) && first <= Duration::from_secs(2));
+
+            let third = policy.backoff(3);
+            assert!(third >= Duration::from_secs(4) && third <= Duration::from_secs(8));
+
+            assert!(policy.backoff(u32::MAX) <= Duration::from_mins(1));
+        }
+    }
+
+    #[test]
+    fn always_attempts_once() {
+        let settings = RetrySettings {
+            max_attempts: 0,
+            ..Default::default()
+        };
+        assert_eq!(RetryPolicy::from(settings).max_attempts, 1);
+    }
+}
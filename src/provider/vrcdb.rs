@@ -2,55 +2,129 @@ use std::time::Duration;
 
 use anyhow::{Result, bail};
 use async_trait::async_trait;
-use reqwest::{Client, StatusCode};
-use serde_json::json;
+use futures::future::BoxFuture;
+use reqwest::{Client, Method, StatusCode};
+use serde_json::{Value, json};
 
 use crate::{
     USER_AGENT,
-    provider::{Provider, ProviderKind},
+    provider::{
+        Provider,
+        ProviderKind,
+        check::{Probe, TEST_ATTRIBUTION, TEST_AVATAR_ID},
+        limit::RateLimiter,
+        preview::Payload,
+        registry::{Built, Capabilities, Context, Descriptor},
+        retry::RetryPolicy,
+    },
     settings::Settings,
 };
 
 const URL: &str = "https://search.bs002.de/api/Avatar/putavatar";
 
+/// VRCX search API, asked by `vrc-log lookup`
+pub const SEARCH_URL: &str = "https://vrcx.vrcdb.com/avatars/Avatar/VRCX";
+
+pub const DESCRIPTOR: Descriptor = Descriptor {
+    kind: ProviderKind::VRCDB,
+    id: "VRCDB",
+    aliases: &[],
+    name: "VRCDB - Avatar Search",
+    enabled_by_default: true,
+    build,
+    capabilities: Capabilities {
+        probe:      Some(probe),
+        payloads:   Some(payloads),
+        search_url: Some(SEARCH_URL),
+    },
+};
+
 pub struct VrcDB<'s> {
     settings: &'s Settings,
     client:   Client,
+    url:      String,
+    retry:    RetryPolicy,
+    limiter:  RateLimiter,
+    timeout:  Duration,
 }
 
 impl<'s> VrcDB<'s> {
     #[must_use]
-    pub fn new(settings: &'s Settings) -> Self {
+    pub fn new(settings: &'s Settings, client: Client) -> Self {
+        Self::new_with_url(settings, client, settings.url(ProviderKind::VRCDB, URL))
+    }
+
+    #[must_use]
+    pub fn new_with_url(settings: &'s Settings, client: Client, url: String) -> Self {
         Self {
             settings,
-            client: Client::default(),
+            client,
+            url,
+            retry: settings.provider_settings(ProviderKind::VRCDB).retry.into(),
+            limiter: RateLimiter::new(settings.rate_limit(ProviderKind::VRCDB)),
+            timeout: settings.timeout(ProviderKind::VRCDB),
         }
     }
 }
 
+fn build(context: Context) -> BoxFuture<'static, Result<Built>> {
+    Box::pin(async move {
+        let provider = VrcDB::new(context.settings, context.client);
+        Ok(Built::provider(
+            context.settings,
+            crate::provider!(provider),
+        ))
+    })
+}
+
+fn body(avatar_id: &str, attribution: &str) -> Value {
+    json!({ "id": avatar_id, "userid": attribution })
+}
+
+/// # One request per avatar ID
+#[must_use]
+pub fn payloads(settings: &Settings, avatar_ids: &[String], attribution: &str) -> Vec<Payload> {
+    let url = settings.url(ProviderKind::VRCDB, URL);
+    avatar_ids
+        .iter()
+        .map(|avatar_id| Payload::new(Method::PUT, &url).json(&body(avatar_id, attribution)))
+        .collect()
+}
+
+/// # Submits `TEST_AVATAR_ID`, which is already known
+#[must_use]
+pub fn probe(settings: &Settings) -> Probe {
+    let json = body(TEST_AVATAR_ID, TEST_ATTRIBUTION);
+    let url = settings.url(ProviderKind::VRCDB, URL);
+    Probe::new(ProviderKind::VRCDB, Method::PUT, url)
+        .json(&json)
+        .accepted(&[200, 404, 500])
+}
+
 #[async_trait]
 impl Provider for VrcDB<'_> {
     fn kind(&self) -> ProviderKind {
         ProviderKind::VRCDB
     }
 
+    fn max_concurrency(&self) -> usize {
+        self.limiter.max_concurrency()
+    }
+
     async fn send_avatar_id(&self, avatar_id: &str) -> Result<bool> {
         let kind = self.kind();
-        let json = json!({
-            "id": avatar_id,
-            "userid": self.settings.attribution.get_user_id().await,
-        });
+        let json = body(avatar_id, &self.settings.attribution.get_user_id().await);
 
         debug!("[{kind}] Sending {json:#?}");
 
-        let response = self
-            .client
-            .put(URL)
-            .header("User-Agent", USER_AGENT)
-            .json(&json)
-            .timeout(Duration::from_secs(3))
-            .send()
-            .await?;
+        let request = || {
+            self.client
+                .put(&self.url)
+                .header("User-Agent", USER_AGENT)
+                .json(&json)
+                .timeout(self.timeout)
+        };
+        let response = self.retry.send(kind, &self.limiter, request).await?;
 
         let status = response.status();
         let text = response.text().await?;
@@ -59,11 +133,6 @@ impl Provider for VrcDB<'_> {
         let unique = match status {
             StatusCode::OK => false,
             StatusCode::NOT_FOUND => true,
-            StatusCode::TOO_MANY_REQUESTS => {
-                warn!("[{kind}] 429 Rate Limit, Please Wait 1 Minute...");
-                tokio::time::sleep(Duration::from_mins(1)).await;
-                Box::pin(self.send_avatar_id(avatar_id)).await?
-            }
             StatusCode::INTERNAL_SERVER_ERROR => {
                 info!("^ Pending in Queue: {kind}");
                 debug!("New Avatars can take up to a day to be processed");
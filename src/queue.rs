@@ -0,0 +1,170 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::{
+    blocklist::Blocklist,
+    cache::{Cache, RetryEntry},
+    print_colorized,
+    provider::{Delivery, Provider, health},
+    settings::Settings,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+const BATCH_SIZE: usize = 100;
+
+const BASE_DELAY: Duration = Duration::from_mins(1);
+const MAX_DELAY: Duration = Duration::from_hours(6);
+
+/// # Delay before the next retry, doubling per attempt
+#[must_use]
+pub fn backoff(attempts: u32) -> Duration {
+    BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts))
+        .min(MAX_DELAY)
+}
+
+/// # Queue a failed submission for the background retry task
+///
+/// # Errors
+/// Will return `Err` if `Cache::enqueue_retry` errors
+pub async fn enqueue(
+    cache: &Cache,
+    avatar_id: &str,
+    provider_bit: u32,
+    error: &anyhow::Error,
+) -> Result<()> {
+    cache
+        .enqueue_retry(avatar_id, provider_bit, &error.to_string(), backoff(0))
+        .await
+}
+
+/// # Retry queued submissions until they succeed or hit the give-up limit
+///
+/// Entries for disabled providers stay queued until the provider is enabled again,
+/// entries for providers that have the avatar by now are dropped instead of sent again.
+///
+/// # Errors
+/// Will return `Err` if the cache errors
+pub async fn run(
+    providers: Vec<Arc<Box<dyn Provider>>>,
+    settings: &Settings,
+    cache: Cache,
+) -> Result<()> {
+    let provider_bits = providers
+        .iter()
+        .fold(0, |bits, provider| bits | provider.bit());
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let entries = cache.due_retries(provider_bits, BATCH_SIZE).await?;
+        let allowed = allowed_ids(settings, &cache, &entries).await?;
+        let sent = cache
+            .check_all_ids(entries.iter().map(|entry| entry.avatar_id.clone()))
+            .await?;
+
+        for entry in entries {
+            // Blocked or known as the user's own after it was queued, or sent since
+            let already_sent = sent
+                .get(&entry.avatar_id)
+                .is_some_and(|bits| bits & entry.provider_bit != 0);
+            if !allowed.contains(&entry.avatar_id) || already_sent {
+                cache
+                    .remove_retry(&entry.avatar_id, entry.provider_bit, false)
+                    .await?;
+                continue;
+            }
+
+            let Some(provider) = providers
+                .iter()
+                .find(|provider| provider.bit() == entry.provider_bit)
+            else {
+                continue;
+            };
+
+            let kind = provider.name();
+            let id = &entry.avatar_id;
+            match provider.submit_avatar_id(id).await {
+                // The provider's outbox marks the bit once the batch is flushed
+                Ok(Delivery::Pending(_)) => {
+                    cache.remove_retry(id, entry.provider_bit, false).await?;
+                }
+                Ok(Delivery::Confirmed(outcome)) => {
+                    cache.remove_retry(id, entry.provider_bit, true).await?;
+                    if let Some(metadata) = outcome.metadata {
+                        cache.store_metadata(id, metadata).await?;
+                    }
+                    if outcome.unique {
+                        print_colorized(format!("{id} You were first to send this to {kind}!"));
+                    } else {
+                        debug!("vrcx://avatar/{id} was already found on {kind}");
+                    }
+                }
+                // Skipping doesn't count as an attempt
+                Err(error) if health::is_down(&error) => {
+                    cache
+                        .postpone_retry(id, entry.provider_bit, BASE_DELAY)
+                        .await?;
+                }
+                Err(error) if entry.attempts + 1 >= settings.retry_queue.give_up_after => {
+                    warn!(
+                        "Giving up on {id} for {kind} after {} attempts: {error}",
+                        entry.attempts + 1
+                    );
+                    cache.remove_retry(id, entry.provider_bit, false).await?;
+                }
+                Err(error) => {
+                    let delay = backoff(entry.attempts + 1);
+                    debug!("Retrying {id} for {kind} in {delay:?}: {error}");
+                    cache
+                        .reschedule_retry(id, entry.provider_bit, &error.to_string(), delay)
+                        .await?;
+                }
+            }
+        }
+    }
+}
+
+async fn allowed_ids(
+    settings: &Settings,
+    cache: &Cache,
+    entries: &[RetryEntry],
+) -> Result<HashSet<String>> {
+    let mut blocklist = Blocklist::load_or_warn();
+    if settings.exclude_own_avatars {
+        blocklist.extend(cache.own_ids().await?);
+    }
+    let avatar_ids = entries
+        .iter()
+        .map(|entry| entry.avatar_id.clone())
+        .collect::<Vec<_>>();
+    let author_ids = if blocklist.has_authors() {
+        cache.author_ids(&avatar_ids).await?
+    } else {
+        HashMap::new()
+    };
+
+    Ok(blocklist
+        .filter(avatar_ids, &author_ids)
+        .into_iter()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_capped() {
+        assert_eq!(backoff(0), Duration::from_mins(1));
+        assert_eq!(backoff(1), Duration::from_mins(2));
+        assert_eq!(backoff(3), Duration::from_mins(8));
+        assert_eq!(backoff(20), MAX_DELAY);
+        assert_eq!(backoff(u32::MAX), MAX_DELAY);
+    }
+}
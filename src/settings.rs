@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 use anyhow::Result;
 use derive_config::DeriveTomlConfig;
@@ -9,10 +9,16 @@ use inquire::{
     list_option::ListOption,
     validator::{ErrorMessage, Validation},
 };
-use serde::{Deserialize, Serialize};
-use strum::{Display, IntoEnumIterator};
+use itertools::Itertools;
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use strum::Display;
 
-use crate::{discord, discord::DEVELOPER_ID, provider::ProviderKind};
+#[cfg(feature = "discord")]
+use crate::discord;
+use crate::provider::{ProviderKind, registry};
+
+/// Credited when the user chooses to stay anonymous
+pub const DEVELOPER_ID: &str = "358558305997684739";
 
 #[derive(Display, Deserialize, Serialize, Default)]
 pub enum Attribution {
@@ -27,29 +33,401 @@ pub enum Attribution {
 
 impl Attribution {
     #[must_use]
+    #[cfg_attr(not(feature = "discord"), allow(clippy::unused_async))]
     pub async fn get_user_id(&self) -> String {
         match self {
             Self::Anonymous => DEVELOPER_ID.to_string(),
             Self::DiscordID(id) => id.clone(),
+            #[cfg(feature = "discord")]
             Self::DiscordRPC(id) => discord::get_user()
                 .await
                 .and_then(|u| u.id)
                 .unwrap_or_else(|| id.clone()),
+            // Without Discord RPC, the ID it last reported is used
+            #[cfg(not(feature = "discord"))]
+            Self::DiscordRPC(id) => id.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryQueueSettings {
+    /// Failed submissions are dropped after this many retries
+    pub give_up_after: u32,
+}
+
+impl Default for RetryQueueSettings {
+    fn default() -> Self {
+        Self { give_up_after: 10 }
+    }
+}
+
+/// # Which addresses providers are connected to
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum IpVersion {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+/// # How requests reach the providers, shared by all of them
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpSettings {
+    /// `http://`, `https://` or `socks5://` proxy every request goes through
+    pub proxy: Option<String>,
+    /// PEM files trusted next to the system certificates, for proxies inspecting TLS
+    pub root_certificates: Vec<PathBuf>,
+    pub ip_version: IpVersion,
+    /// Per request, providers can override it
+    pub timeout_secs: u64,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            root_certificates: Vec::new(),
+            ip_version: IpVersion::Any,
+            timeout_secs: 5,
+        }
+    }
+}
+
+/// # How rate limited or failed requests are retried
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetrySettings {
+    /// Requests are given up on after this many attempts, including the first
+    pub max_attempts:    u32,
+    /// Doubled after every attempt, with jitter
+    pub base_delay_secs: u64,
+    /// Longest wait between attempts, longer `Retry-After` headers are given up on
+    pub max_delay_secs:  u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts:    5,
+            base_delay_secs: 2,
+            max_delay_secs:  60,
+        }
+    }
+}
+
+/// # When a failing provider is skipped instead of tried for every avatar
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HealthSettings {
+    /// The provider is marked down after this many failures in a row
+    pub failure_threshold: u32,
+    /// Avatars are queued for this long before the provider is tried again
+    pub cooldown_secs:     u64,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown_secs:     300,
+        }
+    }
+}
+
+impl HealthSettings {
+    #[must_use]
+    pub const fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.cooldown_secs)
+    }
+}
+
+/// # How hard a provider may be hit, checked before every request
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Requests started per second on average, 0 disables the limit
+    pub requests_per_sec: f64,
+    /// Requests that can start at once after a quiet period
+    pub burst: u32,
+    /// Requests in flight at the same time
+    pub max_concurrency: usize,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            requests_per_sec: 2.0,
+            burst: 5,
+            max_concurrency: 4,
+        }
+    }
+}
+
+impl RateLimitSettings {
+    /// Batching providers send one large request per flush
+    pub const BATCHED: Self = Self {
+        requests_per_sec: 0.2,
+        burst: 1,
+        max_concurrency: 1,
+    };
+
+    /// # Defaults kept well under what each service tolerates
+    #[must_use]
+    pub fn for_kind(kind: ProviderKind) -> Self {
+        match kind {
+            #[cfg(feature = "avtrdb")]
+            ProviderKind::AVTRDB => Self::BATCHED,
+            #[cfg(feature = "cutedb")]
+            ProviderKind::CUTEDB => Self::BATCHED,
+            #[cfg(feature = "kitsunedb")]
+            ProviderKind::KITSUNEDB => Self::BATCHED,
+            #[allow(unreachable_patterns)]
+            _ => Self::default(),
+        }
+    }
+}
+
+/// # Tuning for a single provider
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct ProviderSettings {
+    /// Batching providers flush once this many avatars are buffered
+    pub flush_threshold: usize,
+    /// Batching providers flush buffered avatars at least this often
+    pub flush_interval_secs: u64,
+    pub retry: RetrySettings,
+    pub health: HealthSettings,
+    /// Falls back to `RateLimitSettings::for_kind`
+    pub rate_limit: Option<RateLimitSettings>,
+    /// Falls back to `http.timeout_secs`
+    pub timeout_secs: Option<u64>,
+    /// Replaces the provider's endpoint, for mirrors, staging servers or local stand-ins
+    pub url: Option<String>,
+    /// Replaces the provider's VRCX search API, asked by `vrc-log lookup`
+    pub search_url: Option<String>,
+}
+
+impl Default for ProviderSettings {
+    fn default() -> Self {
+        Self {
+            flush_threshold: 100,
+            flush_interval_secs: 120,
+            retry: RetrySettings::default(),
+            health: HealthSettings::default(),
+            rate_limit: None,
+            timeout_secs: None,
+            url: None,
+            search_url: None,
         }
     }
 }
 
+impl ProviderSettings {
+    #[must_use]
+    pub const fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.flush_interval_secs)
+    }
+}
+
+/// # HTTP provider declared in the config file
+///
+/// `{avatar_id}`, `{avatar_ids}` (a JSON array, sent in bulk) and `{attribution}`
+/// are replaced in the URL, headers and body.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CustomProviderSettings {
+    pub name: String,
+    /// The cache remembers the provider by it, so it mustn't change, from 0 to 15
+    pub id: u8,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Falls back to `http.timeout_secs`
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Status codes meaning the avatar was new
+    #[serde(default = "default_unique_status")]
+    pub unique_status: Vec<u16>,
+    /// Status codes meaning the provider already had the avatar, anything else is an error
+    #[serde(default)]
+    pub duplicate_status: Vec<u16>,
+    /// Send avatars in batches instead of one request per avatar
+    #[serde(default)]
+    pub batch: Option<ProviderSettings>,
+    #[serde(default)]
+    pub retry: RetrySettings,
+    #[serde(default)]
+    pub health: HealthSettings,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+}
+
+impl CustomProviderSettings {
+    #[must_use]
+    pub fn timeout(&self, http: &HttpSettings) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(http.timeout_secs))
+    }
+}
+
+/// One cache bit each, above the bits of the built-in providers
+pub const MAX_CUSTOM_PROVIDERS: usize = 16;
+
+const fn default_true() -> bool {
+    true
+}
+
+fn default_method() -> String {
+    String::from("POST")
+}
+
+fn default_unique_status() -> Vec<u16> {
+    vec![200]
+}
+
 #[derive(DeriveTomlConfig, Deserialize, Serialize, Default)]
 pub struct Settings {
-    pub attribution:     Attribution,
+    pub attribution: Attribution,
     pub clear_amplitude: bool,
-    pub print_scanned:   bool,
-    pub providers:       HashMap<ProviderKind, bool>,
+    pub print_scanned: bool,
+    #[serde(deserialize_with = "registered")]
+    pub providers: HashMap<ProviderKind, bool>,
+    #[serde(default)]
+    pub retry_queue: RetryQueueSettings,
+    /// Never submit avatars the logged in user wore or uploaded
+    ///
+    /// Uploads they never wore are only matched once a provider returned them as the author.
+    #[serde(default = "default_true")]
+    pub exclude_own_avatars: bool,
+    /// Overrides for providers that shouldn't use the defaults
+    #[serde(default, deserialize_with = "registered")]
+    pub provider_settings: HashMap<ProviderKind, ProviderSettings>,
+    /// Providers without built-in support, used when `CUSTOM` is enabled
+    #[serde(default, deserialize_with = "custom_providers")]
+    pub custom_providers: Vec<CustomProviderSettings>,
+    #[serde(default)]
+    pub http: HttpSettings,
+}
+
+/// # Settings the setup wizard asks about
+pub struct WizardAnswers {
+    pub attribution:         Attribution,
+    pub clear_amplitude:     bool,
+    pub print_scanned:       bool,
+    pub providers:           HashMap<ProviderKind, bool>,
+    pub exclude_own_avatars: bool,
+}
+
+/// # Read a map keyed by provider, leaving out providers that aren't registered
+///
+/// Those are built without their feature, or come from a crate that isn't registering them anymore.
+fn registered<'de, D, V>(deserializer: D) -> Result<HashMap<ProviderKind, V>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    let map = HashMap::<String, V>::deserialize(deserializer)?;
+
+    Ok(map
+        .into_iter()
+        .filter_map(|(id, value)| {
+            let Some(descriptor) = registry::find(&id) else {
+                debug!("Skipping {id} from the config file, it isn't a registered provider");
+                return None;
+            };
+
+            Some((descriptor.kind, value))
+        })
+        .collect())
+}
+
+/// # Read the custom providers, rejecting IDs the cache couldn't tell apart
+fn custom_providers<'de, D>(deserializer: D) -> Result<Vec<CustomProviderSettings>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let providers = Vec::<CustomProviderSettings>::deserialize(deserializer)?;
+    if providers.len() > MAX_CUSTOM_PROVIDERS {
+        return Err(D::Error::custom(format!(
+            "Only {MAX_CUSTOM_PROVIDERS} custom providers are supported"
+        )));
+    }
+
+    let mut names = HashMap::new();
+    for provider in &providers {
+        if usize::from(provider.id) >= MAX_CUSTOM_PROVIDERS {
+            return Err(D::Error::custom(format!(
+                "{} has ID {}, custom provider IDs go up to {}",
+                provider.name,
+                provider.id,
+                MAX_CUSTOM_PROVIDERS - 1
+            )));
+        }
+        if let Some(other) = names.insert(provider.id, &provider.name) {
+            return Err(D::Error::custom(format!(
+                "{other} and {} both have ID {}",
+                provider.name, provider.id
+            )));
+        }
+    }
+
+    Ok(providers)
 }
 
 impl Settings {
+    #[must_use]
+    pub fn is_enabled(&self, kind: ProviderKind) -> bool {
+        self.providers.get(&kind).copied().unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn provider_settings(&self, kind: ProviderKind) -> ProviderSettings {
+        self.provider_settings
+            .get(&kind)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// # Endpoint of a provider, `default` unless overridden
+    #[must_use]
+    pub fn url(&self, kind: ProviderKind, default: &str) -> String {
+        self.provider_settings(kind)
+            .url
+            .unwrap_or_else(|| default.to_string())
+    }
+
+    /// # Search API of a provider, `default` unless overridden
+    #[must_use]
+    pub fn search_url(&self, kind: ProviderKind, default: &str) -> String {
+        self.provider_settings(kind)
+            .search_url
+            .unwrap_or_else(|| default.to_string())
+    }
+
+    #[must_use]
+    pub fn timeout(&self, kind: ProviderKind) -> Duration {
+        let secs = self.provider_settings(kind).timeout_secs;
+        Duration::from_secs(secs.unwrap_or(self.http.timeout_secs))
+    }
+
+    #[must_use]
+    pub fn rate_limit(&self, kind: ProviderKind) -> RateLimitSettings {
+        self.provider_settings(kind)
+            .rate_limit
+            .unwrap_or_else(|| RateLimitSettings::for_kind(kind))
+    }
+
     /// # Setup Wizard
     ///
+    /// Only changes what it asks about, everything else is kept from the `previous` settings.
+    ///
     /// # Errors
     ///
     /// Will return `Err` if prompts fail.
@@ -57,20 +435,37 @@ impl Settings {
     /// # Panics
     ///
     /// Will panic if Discord user ID doesn't exist.
-    pub async fn try_wizard() -> Result<Self> {
+    #[cfg_attr(not(feature = "discord"), allow(clippy::unused_async))]
+    pub async fn try_wizard(previous: Option<Self>) -> Result<Self> {
+        #[cfg_attr(not(feature = "discord"), allow(unused_mut))]
         let mut attributions = vec![
             Attribution::Anonymous,
             Attribution::DiscordID(String::new()),
         ];
+        #[cfg(feature = "discord")]
         if let Some(user) = discord::get_user().await {
             attributions.insert(1, Attribution::DiscordRPC(user.id.unwrap()));
         }
         let attribution = Select::new("How do you want to be credited?", attributions).prompt()?;
         let providers = {
-            let providers = ProviderKind::iter().collect::<Vec<_>>();
+            let descriptors = registry::descriptors();
+            let providers = descriptors
+                .iter()
+                .map(|descriptor| descriptor.kind)
+                .collect::<Vec<_>>();
+            // Providers added since are offered with their own default
+            let defaults = descriptors
+                .iter()
+                .positions(|descriptor| {
+                    previous
+                        .as_ref()
+                        .and_then(|previous| previous.providers.get(&descriptor.kind).copied())
+                        .unwrap_or(descriptor.enabled_by_default)
+                })
+                .collect::<Vec<_>>();
             let enabled = MultiSelect::new("Select which providers to use:", providers.clone())
                 .with_page_size(providers.len())
-                .with_default(&[0, 1, 2, 3, 4, 5, 6, 7])
+                .with_default(&defaults)
                 .with_validator(|list: &[ListOption<&ProviderKind>]| {
                     if list.is_empty() {
                         let message = String::from("You must select at least one.");
@@ -87,23 +482,125 @@ impl Settings {
                 .collect()
         };
 
+        let answered =
+            |answer: fn(&Self) -> bool, default| previous.as_ref().map_or(default, answer);
+
         let clear_amplitude = Confirm::new(
             "Clear amplitude file after reading? (Helps with privacy by removing tracked data)",
         )
-        .with_default(true)
+        .with_default(answered(|previous| previous.clear_amplitude, true))
         .prompt()?;
 
         let print_scanned = Confirm::new(
             "Print all scanned avatar ids instead of just the uniquely discovered ones",
         )
-        .with_default(false)
+        .with_default(answered(|previous| previous.print_scanned, false))
+        .prompt()?;
+
+        let exclude_own_avatars = Confirm::new(
+            "Never submit avatars you wear or uploaded yourself? (Keeps your private avatars private)",
+        )
+        .with_default(answered(|previous| previous.exclude_own_avatars, true))
         .prompt()?;
 
-        Ok(Self {
+        Ok(previous.unwrap_or_default().with_answers(WizardAnswers {
             attribution,
             clear_amplitude,
             print_scanned,
             providers,
-        })
+            exclude_own_avatars,
+        }))
+    }
+
+    /// # Apply the setup wizard's answers, keeping everything it doesn't ask about
+    #[must_use]
+    pub fn with_answers(self, answers: WizardAnswers) -> Self {
+        Self {
+            attribution: answers.attribution,
+            clear_amplitude: answers.clear_amplitude,
+            print_scanned: answers.print_scanned,
+            providers: answers.providers,
+            exclude_own_avatars: answers.exclude_own_avatars,
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wizard_keeps_what_it_doesnt_ask_about() {
+        let custom = serde_json::from_value::<CustomProviderSettings>(serde_json::json!({
+            "name": "Community DB",
+            "id": 0,
+            "url": "https://example.com/{avatar_id}",
+        }))
+        .unwrap();
+        let previous = Settings {
+            custom_providers: vec![custom],
+            provider_settings: HashMap::from([(
+                ProviderKind::PAW,
+                ProviderSettings {
+                    timeout_secs: Some(3),
+                    ..Default::default()
+                },
+            )]),
+            http: HttpSettings {
+                proxy: Some(String::from("socks5://localhost:1080")),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let settings = previous.with_answers(WizardAnswers {
+            attribution:         Attribution::DiscordID(String::from("1234")),
+            clear_amplitude:     false,
+            print_scanned:       true,
+            providers:           HashMap::from([(ProviderKind::PAW, true)]),
+            exclude_own_avatars: false,
+        });
+
+        assert_eq!(settings.custom_providers.len(), 1);
+        assert_eq!(settings.custom_providers[0].name, "Community DB");
+        assert_eq!(settings.timeout(ProviderKind::PAW), Duration::from_secs(3));
+        assert_eq!(
+            settings.http.proxy.as_deref(),
+            Some("socks5://localhost:1080")
+        );
+        assert!(settings.print_scanned);
+        assert!(settings.is_enabled(ProviderKind::PAW));
+    }
+
+    #[test]
+    fn custom_providers_need_their_own_id() {
+        #[derive(Debug, Deserialize)]
+        struct Config {
+            #[serde(deserialize_with = "custom_providers")]
+            #[allow(dead_code)]
+            custom_providers: Vec<CustomProviderSettings>,
+        }
+
+        let provider = |id: usize| {
+            serde_json::json!({
+                "name": format!("Community DB {id}"),
+                "id": id,
+                "url": "https://example.com/{avatar_id}",
+            })
+        };
+        let config = |ids: &[usize]| {
+            serde_json::from_value::<Config>(serde_json::json!({
+                "custom_providers": ids.iter().copied().map(provider).collect::<Vec<_>>(),
+            }))
+        };
+
+        let error = |ids: &[usize]| config(ids).unwrap_err().to_string();
+
+        assert!(config(&[3, 0]).is_ok());
+        assert!(error(&[3, 3]).contains("both have ID 3"));
+        assert!(error(&[MAX_CUSTOM_PROVIDERS]).contains("IDs go up to 15"));
+        let ids = (0..MAX_CUSTOM_PROVIDERS).chain([0]).collect::<Vec<_>>();
+        assert!(error(&ids).contains("Only 16 custom providers"));
     }
 }
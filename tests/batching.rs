@@ -0,0 +1,263 @@
+#![cfg(all(feature = "avtrdb", feature = "cutedb", feature = "kitsunedb"))]
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Response, Server};
+use vrc_log::{
+    provider::{
+        Provider,
+        ProviderKind,
+        Submission,
+        avtrdb::{AvtrDB, AvtrDBActor},
+        cutedb::CuteDBActor,
+        health::{self, Guarded},
+        kitsunedb::KitsuneDBActor,
+    },
+    settings::{HealthSettings, ProviderSettings, RateLimitSettings, Settings},
+};
+
+const AVATAR_ID: &str = "avtr_c38a1615-5bf5-42b4-84eb-a8b6c37cbd11";
+const OTHER_AVATAR_ID: &str = "avtr_08f3ba8b-1c5b-4d7e-9f51-3f2d5b0e2c4a";
+const INGEST_RESPONSE: &str = r#"{"avatars_enqueued":1,"invalid_ids":0,"ticket":"test"}"#;
+
+/// Long enough that only the threshold can trigger a flush
+const NEVER: Duration = Duration::from_hours(1);
+
+fn settings() -> &'static Settings {
+    Box::leak(Box::default())
+}
+
+/// Without the rate limit, which would hold every request after the first for seconds
+fn unlimited_settings(kind: ProviderKind) -> &'static Settings {
+    let provider_settings = ProviderSettings {
+        rate_limit: Some(RateLimitSettings {
+            requests_per_sec: 0.0,
+            burst: 1,
+            max_concurrency: 1,
+        }),
+        ..Default::default()
+    };
+
+    Box::leak(Box::new(Settings {
+        provider_settings: [(kind, provider_settings)].into(),
+        ..Default::default()
+    }))
+}
+
+#[tokio::test]
+async fn avtrdb_flushes_on_timer_while_channel_is_open() {
+    let server = Server::start(200, INGEST_RESPONSE).await;
+    let (mut actor, sender) = AvtrDBActor::new_with_base_url_and_flush_interval(
+        settings(),
+        100,
+        server.url.clone(),
+        Duration::from_millis(200),
+    );
+    tokio::spawn(async move { actor.run().await });
+
+    let (submission, receipt) = Submission::new(AVATAR_ID);
+    sender.send_async(submission).await.unwrap();
+
+    // The sender stays alive, so only the timer can flush here
+    let unique = tokio::time::timeout(Duration::from_secs(5), receipt)
+        .await
+        .expect("the buffer should flush without another avatar arriving")
+        .unwrap();
+    assert_eq!(unique, Some(true));
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "POST");
+    assert_eq!(requests[0].path, "/avatar/ingest");
+    assert!(requests[0].body.contains(AVATAR_ID));
+    drop(sender);
+}
+
+#[tokio::test]
+async fn kitsunedb_flushes_on_timer_while_channel_is_open() {
+    let server = Server::start(200, INGEST_RESPONSE).await;
+    let (mut actor, sender) = KitsuneDBActor::new_with_base_url_and_flush_interval(
+        settings(),
+        100,
+        server.url.clone(),
+        Duration::from_millis(200),
+    );
+    tokio::spawn(async move { actor.run().await });
+
+    let (submission, receipt) = Submission::new(AVATAR_ID);
+    sender.send_async(submission).await.unwrap();
+
+    let unique = tokio::time::timeout(Duration::from_secs(5), receipt)
+        .await
+        .expect("the buffer should flush without another avatar arriving")
+        .unwrap();
+    assert_eq!(unique, Some(true));
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].path, "/ingest");
+    drop(sender);
+}
+
+#[tokio::test]
+async fn cutedb_flushes_on_timer_while_channel_is_open() {
+    let server = Server::start(200, "{}").await;
+    let (mut actor, sender) = CuteDBActor::new_with_url_and_flush_interval(
+        100,
+        server.url.clone(),
+        Duration::from_millis(200),
+    );
+    tokio::spawn(async move { actor.run().await });
+
+    let (submission, receipt) = Submission::new(AVATAR_ID);
+    sender.send_async(submission).await.unwrap();
+
+    // The bulk upload doesn't report new avatars, so it's never known
+    let unique = tokio::time::timeout(Duration::from_secs(5), receipt)
+        .await
+        .expect("the buffer should flush without another avatar arriving")
+        .unwrap();
+    assert_eq!(unique, None);
+    assert_eq!(server.requests().len(), 1);
+    drop(sender);
+}
+
+#[tokio::test]
+async fn avtrdb_flushes_once_threshold_is_reached() {
+    let server = Server::start(200, INGEST_RESPONSE).await;
+    let (mut actor, sender) =
+        AvtrDBActor::new_with_base_url_and_flush_interval(settings(), 2, server.url.clone(), NEVER);
+    tokio::spawn(async move { actor.run().await });
+
+    let (first, first_receipt) = Submission::new(AVATAR_ID);
+    sender.send_async(first).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(server.requests().is_empty());
+
+    let (second, second_receipt) = Submission::new(OTHER_AVATAR_ID);
+    sender.send_async(second).await.unwrap();
+
+    let (first, second) = tokio::time::timeout(Duration::from_secs(5), async {
+        (first_receipt.await.unwrap(), second_receipt.await.unwrap())
+    })
+    .await
+    .expect("reaching the threshold should flush the buffer");
+
+    // One of two avatars was new, so neither is known to be unique or a duplicate
+    assert_eq!((first, second), (None, None));
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].body.contains(AVATAR_ID) && requests[0].body.contains(OTHER_AVATAR_ID));
+    drop(sender);
+}
+
+#[tokio::test]
+async fn avtrdb_sends_at_most_the_threshold_after_a_failed_flush() {
+    const THIRD_AVATAR_ID: &str = "avtr_5f0e8b6a-2d3c-4e1f-8a7b-9c6d5e4f3a2b";
+
+    let server = Server::start_with(vec![
+        Response::new(500, ""),
+        Response::new(200, INGEST_RESPONSE),
+    ])
+    .await;
+    let (mut actor, sender) = AvtrDBActor::new_with_base_url_and_flush_interval(
+        unlimited_settings(ProviderKind::AVTRDB),
+        2,
+        server.url.clone(),
+        NEVER,
+    );
+    let actor = tokio::spawn(async move { actor.run().await });
+
+    let mut receipts = Vec::new();
+    for avatar_id in [AVATAR_ID, OTHER_AVATAR_ID, THIRD_AVATAR_ID] {
+        let (submission, receipt) = Submission::new(avatar_id);
+        sender.send_async(submission).await.unwrap();
+        receipts.push(receipt);
+    }
+
+    // The failed batch is sent again, without the avatar that arrived since
+    let third = receipts.pop().unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        for receipt in receipts {
+            receipt.await.unwrap();
+        }
+    })
+    .await
+    .expect("the next avatar should flush the kept batch");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    for request in &requests {
+        assert!(request.body.contains(AVATAR_ID) && request.body.contains(OTHER_AVATAR_ID));
+        assert!(!request.body.contains(THIRD_AVATAR_ID));
+    }
+
+    // Whatever is left goes out on shutdown
+    drop(sender);
+    actor.await.unwrap().unwrap();
+    third.await.unwrap();
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests[2].body.contains(THIRD_AVATAR_ID));
+}
+
+#[tokio::test]
+async fn avtrdb_is_marked_down_after_failed_flushes() {
+    let server = Server::start(500, "").await;
+    let (actor, sender) = AvtrDBActor::new_with_base_url_and_flush_interval(
+        unlimited_settings(ProviderKind::AVTRDB),
+        1,
+        server.url.clone(),
+        NEVER,
+    );
+    let health = HealthSettings {
+        failure_threshold: 2,
+        cooldown_secs:     60,
+    };
+    let provider = Guarded::new(vrc_log::provider!(AvtrDB::new(sender)), health);
+    let mut actor = actor.with_breaker(provider.breaker());
+    tokio::spawn(async move { actor.run().await });
+
+    // Handing the avatar to the actor succeeds, only the flushes fail
+    provider.submit_avatar_id(AVATAR_ID).await.unwrap();
+    provider.submit_avatar_id(OTHER_AVATAR_ID).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !provider.is_down() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("failed flushes should mark the provider down");
+
+    let Err(error) = provider.submit_avatar_id(AVATAR_ID).await else {
+        panic!("a provider marked down shouldn't take avatars");
+    };
+    assert!(health::is_down(&error));
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn cutedb_flushes_once_threshold_is_reached() {
+    let server = Server::start(200, "{}").await;
+    let (mut actor, sender) =
+        CuteDBActor::new_with_url_and_flush_interval(2, server.url.clone(), NEVER);
+    tokio::spawn(async move { actor.run().await });
+
+    for avatar_id in [AVATAR_ID, OTHER_AVATAR_ID] {
+        let (submission, _) = Submission::new(avatar_id);
+        sender.send_async(submission).await.unwrap();
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.requests().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("reaching the threshold should flush the buffer");
+    assert_eq!(server.requests().len(), 1);
+    drop(sender);
+}
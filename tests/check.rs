@@ -0,0 +1,76 @@
+#![cfg(feature = "custom")]
+// The request templates use the same braces as format strings
+#![allow(clippy::literal_string_with_formatting_args)]
+
+mod common;
+
+use std::collections::HashMap;
+
+use common::Server;
+use reqwest::{Client, StatusCode};
+use vrc_log::{
+    provider::{
+        ProviderKind,
+        check::{self, TEST_AVATAR_ID},
+    },
+    settings::{
+        CustomProviderSettings,
+        HealthSettings,
+        RateLimitSettings,
+        RetrySettings,
+        Settings,
+    },
+};
+
+fn settings(url: String) -> Settings {
+    let config = CustomProviderSettings {
+        name: String::from("Community DB"),
+        id: 0,
+        enabled: true,
+        url,
+        method: String::from("POST"),
+        body: Some(String::from(r#"{"id": "{avatar_id}"}"#)),
+        headers: HashMap::new(),
+        timeout_secs: None,
+        unique_status: vec![404],
+        duplicate_status: vec![200],
+        batch: None,
+        retry: RetrySettings::default(),
+        health: HealthSettings::default(),
+        rate_limit: RateLimitSettings::default(),
+    };
+
+    Settings {
+        providers: HashMap::from([(ProviderKind::CUSTOM, true)]),
+        custom_providers: vec![config],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn reports_a_provider_answering_the_test_avatar() {
+    let server = Server::start(200, "").await;
+
+    let probes = check::probes(&settings(server.url.clone()));
+    assert_eq!(probes.len(), 1);
+    let report = probes.into_iter().next().unwrap().run(&Client::new()).await;
+
+    assert!(report.healthy());
+    assert!(!report.https);
+    assert_eq!(report.answer.as_ref().unwrap().status, StatusCode::OK);
+    assert!(server.requests()[0].body.contains(TEST_AVATAR_ID));
+}
+
+#[tokio::test]
+async fn reports_unexpected_statuses_and_unreachable_providers() {
+    let server = Server::start(500, "").await;
+    let probe = check::probes(&settings(server.url.clone())).remove(0);
+    let report = probe.run(&Client::new()).await;
+    assert!(!report.healthy());
+    assert!(!report.answer.unwrap().accepted);
+
+    // Nothing listens on the discard port
+    let probe = check::probes(&settings(String::from("http://127.0.0.1:9"))).remove(0);
+    let report = probe.run(&Client::new()).await;
+    assert!(report.answer.is_err());
+}
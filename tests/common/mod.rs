@@ -0,0 +1,173 @@
+#![allow(dead_code)]
+
+#[cfg(all(
+    feature = "avtrdb",
+    feature = "avtrzip",
+    feature = "cutedb",
+    feature = "kitsunedb",
+    feature = "nsvr",
+    feature = "paw",
+    feature = "vrcdb",
+    feature = "vrcwb",
+))]
+pub mod stand_in;
+
+use std::{
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+/// A request the stand-in received
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path:   String,
+    pub body:   String,
+}
+
+/// A response the stand-in answers with
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub status:  u16,
+    pub headers: Vec<(String, String)>,
+    pub body:    String,
+    /// Waited before answering, to run into timeouts
+    pub delay:   Duration,
+}
+
+impl Response {
+    pub fn new(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// 429, optionally asking to wait `retry_after` seconds
+    pub fn rate_limited(retry_after: Option<u64>) -> Self {
+        let response = Self::new(429, "");
+        match retry_after {
+            Some(seconds) => response.with_header("Retry-After", &seconds.to_string()),
+            None => response,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub const fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    fn to_http(&self) -> String {
+        let mut http = format!(
+            "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            let _ = write!(http, "{name}: {value}\r\n");
+        }
+        http.push_str("\r\n");
+        http.push_str(&self.body);
+
+        http
+    }
+}
+
+/// # Local HTTP stand-in for provider APIs
+///
+/// Answers requests with the given responses in order, repeating the last one,
+/// and records what it received.
+pub struct Server {
+    pub url:  String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    pub async fn start(status: u16, body: &str) -> Self {
+        Self::start_with(vec![Response::new(status, body)]).await
+    }
+
+    pub async fn start_with(responses: Vec<Response>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let Some(request) = read_request(&mut stream).await else {
+                    continue;
+                };
+
+                let count = {
+                    let mut recorded = recorded.lock().unwrap();
+                    recorded.push(request);
+                    recorded.len()
+                };
+                let response = responses[count.min(responses.len()) - 1].clone();
+
+                // Answered on its own, so a slow response doesn't hold up the retry
+                tokio::spawn(async move {
+                    tokio::time::sleep(response.delay).await;
+                    let _ = stream
+                        .get_mut()
+                        .write_all(response.to_http().as_bytes())
+                        .await;
+                    let _ = stream.get_mut().shutdown().await;
+                });
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn read_request<R: AsyncBufReadExt + Unpin>(stream: &mut R) -> Option<Request> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        stream.read_line(&mut header).await.ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().ok()?;
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await.ok()?;
+
+    Some(Request {
+        method,
+        path,
+        body: String::from_utf8(body).ok()?,
+    })
+}
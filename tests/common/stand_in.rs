@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use reqwest::Client;
+use vrc_log::{
+    provider::{
+        Provider,
+        ProviderKind,
+        registry::{self, Actor, Built, Context},
+    },
+    settings::{ProviderSettings, RateLimitSettings, RetrySettings, Settings},
+};
+
+use super::Response;
+
+pub const INGESTED: &str = r#"{"avatars_enqueued":1,"invalid_ids":0,"ticket":"test"}"#;
+pub const ALREADY_INGESTED: &str = r#"{"avatars_enqueued":0,"invalid_ids":0,"ticket":"test"}"#;
+
+/// # How a provider's API answers
+#[derive(Clone)]
+pub struct Api {
+    pub kind:      ProviderKind,
+    /// `None` when the API doesn't tell new avatars apart
+    pub unique:    Option<Response>,
+    pub duplicate: Response,
+    /// Whether the body is read, so malformed JSON matters
+    pub json:      bool,
+}
+
+/// # Every built-in provider's API
+pub fn apis() -> Vec<Api> {
+    let status = |kind, unique, duplicate| Api {
+        kind,
+        unique: Some(Response::new(unique, "")),
+        duplicate: Response::new(duplicate, ""),
+        json: false,
+    };
+    let json = |kind, unique: Option<&str>, duplicate| Api {
+        kind,
+        unique: unique.map(|body| Response::new(200, body)),
+        duplicate: Response::new(200, duplicate),
+        json: true,
+    };
+
+    vec![
+        json(ProviderKind::AVTRDB, Some(INGESTED), ALREADY_INGESTED),
+        json(
+            ProviderKind::AVTRZIP,
+            Some(r#"{"success":true,"isNew":true}"#),
+            r#"{"success":true,"isNew":false}"#,
+        ),
+        Api {
+            kind:      ProviderKind::CUTEDB,
+            unique:    None,
+            duplicate: Response::new(200, ""),
+            json:      false,
+        },
+        json(ProviderKind::KITSUNEDB, Some(INGESTED), ALREADY_INGESTED),
+        status(ProviderKind::NSVR, 404, 200),
+        json(
+            ProviderKind::PAW,
+            Some(r#"{"success":true,"code":200,"avatar":null}"#),
+            r#"{"success":true,"code":200,"avatar":{"name":"Robot","authorName":"VRChat"}}"#,
+        ),
+        status(ProviderKind::VRCDB, 404, 200),
+        status(ProviderKind::VRCWB, 404, 200),
+    ]
+}
+
+/// # Settings pointing the given providers at their stand-ins
+///
+/// Retries don't back off, requests time out after a second and batches flush every avatar.
+pub fn settings(urls: &[(ProviderKind, String)]) -> &'static Settings {
+    let provider_settings = |url: &String| ProviderSettings {
+        flush_threshold: 1,
+        retry: RetrySettings {
+            max_attempts:    3,
+            base_delay_secs: 0,
+            max_delay_secs:  5,
+        },
+        rate_limit: Some(RateLimitSettings {
+            requests_per_sec: 0.0,
+            burst: 1,
+            max_concurrency: 4,
+        }),
+        timeout_secs: Some(1),
+        url: Some(url.clone()),
+        ..Default::default()
+    };
+
+    Box::leak(Box::new(Settings {
+        providers: urls.iter().map(|(kind, _)| (*kind, true)).collect(),
+        provider_settings: urls
+            .iter()
+            .map(|(kind, url)| (*kind, provider_settings(url)))
+            .collect(),
+        ..Default::default()
+    }))
+}
+
+/// # Build a provider through the registry like main.rs does, running its actor if it batches
+///
+/// Batches are only kept in memory, a stand-in's outbox would outlive the test.
+pub async fn provider(kind: ProviderKind, settings: &'static Settings) -> Arc<Box<dyn Provider>> {
+    let descriptor = registry::get(kind).expect("the provider should be registered");
+    let Built {
+        mut providers,
+        actors,
+    } = (descriptor.build)(Context {
+        settings,
+        client: Client::new(),
+        #[cfg(feature = "cache")]
+        cache: None,
+    })
+    .await
+    .expect("the stand-in settings should build the provider");
+
+    for Actor { run, .. } in actors {
+        tokio::spawn(run);
+    }
+
+    providers.pop().expect("the provider should be enabled")
+}
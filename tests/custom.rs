@@ -0,0 +1,213 @@
+#![cfg(feature = "custom")]
+// The request templates use the same braces as format strings
+#![allow(clippy::literal_string_with_formatting_args)]
+
+mod common;
+
+use std::{collections::HashMap, time::Duration};
+
+use common::Server;
+use reqwest::Client;
+#[cfg(feature = "cache")]
+use vrc_log::{
+    cache::Cache,
+    provider::{
+        ProviderKind,
+        registry::{Built, Context},
+    },
+};
+use vrc_log::{
+    provider::{Delivery, Provider, custom},
+    settings::{
+        CustomProviderSettings,
+        HealthSettings,
+        ProviderSettings,
+        RateLimitSettings,
+        RetrySettings,
+        Settings,
+    },
+};
+
+const AVATAR_ID: &str = "avtr_c38a1615-5bf5-42b4-84eb-a8b6c37cbd11";
+const OTHER_AVATAR_ID: &str = "avtr_08f3ba8b-1c5b-4d7e-9f51-3f2d5b0e2c4a";
+
+fn custom_provider(url: String, body: &str) -> CustomProviderSettings {
+    CustomProviderSettings {
+        name: String::from("Community DB"),
+        id: 0,
+        enabled: true,
+        url,
+        method: String::from("POST"),
+        body: Some(body.to_string()),
+        headers: HashMap::from([(String::from("X-Source"), String::from("vrc-log"))]),
+        timeout_secs: None,
+        unique_status: vec![404],
+        duplicate_status: vec![200],
+        batch: None,
+        retry: RetrySettings::default(),
+        health: HealthSettings::default(),
+        rate_limit: RateLimitSettings::default(),
+    }
+}
+
+fn settings(custom_providers: Vec<CustomProviderSettings>) -> &'static Settings {
+    Box::leak(Box::new(Settings {
+        custom_providers,
+        ..Default::default()
+    }))
+}
+
+#[tokio::test]
+async fn maps_status_codes_like_the_config_says() {
+    let new = Server::start(404, "").await;
+    let known = Server::start(200, "").await;
+    let broken = Server::start(500, "").await;
+    let body = r#"{"id":"{avatar_id}","userid":"{attribution}"}"#;
+    let with_id = |id, url| CustomProviderSettings {
+        id,
+        ..custom_provider(url, body)
+    };
+    let settings = settings(vec![
+        with_id(2, new.url.clone()),
+        with_id(0, known.url.clone()),
+        with_id(5, broken.url.clone()),
+    ]);
+
+    let (providers, actors) = custom::providers(settings, &Client::new()).unwrap();
+    assert!(actors.is_empty());
+    assert_eq!(
+        providers.iter().map(Provider::bit).collect::<Vec<_>>(),
+        [1 << 18, 1 << 16, 1 << 21]
+    );
+    assert_eq!(providers[0].name(), "Community DB");
+
+    assert!(providers[0].send_avatar_id(AVATAR_ID).await.unwrap());
+    assert!(!providers[1].send_avatar_id(AVATAR_ID).await.unwrap());
+    assert!(providers[2].send_avatar_id(AVATAR_ID).await.is_err());
+
+    let requests = new.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "POST");
+    assert!(
+        requests[0]
+            .body
+            .starts_with(&format!(r#"{{"id":"{AVATAR_ID}","userid":""#))
+    );
+}
+
+#[tokio::test]
+async fn batches_when_configured() {
+    let server = Server::start(404, "").await;
+    let mut config = custom_provider(server.url.clone(), "{avatar_ids}");
+    config.batch = Some(ProviderSettings {
+        flush_threshold: 100,
+        flush_interval_secs: 1,
+        ..Default::default()
+    });
+    let settings = settings(vec![config]);
+
+    let (providers, actors) = custom::providers(settings, &Client::new()).unwrap();
+    for mut actor in actors {
+        tokio::spawn(async move { actor.run().await });
+    }
+
+    let mut receipts = Vec::new();
+    for avatar_id in [AVATAR_ID, OTHER_AVATAR_ID] {
+        let Delivery::Pending(receipt) = providers[0].submit_avatar_id(avatar_id).await.unwrap()
+        else {
+            panic!("batching providers should hand out receipts");
+        };
+        receipts.push(receipt);
+    }
+
+    for receipt in receipts {
+        let unique = tokio::time::timeout(Duration::from_secs(5), receipt)
+            .await
+            .expect("the timer should flush the batch")
+            .unwrap();
+        assert_eq!(unique, Some(true));
+    }
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0].body,
+        format!(r#"["{AVATAR_ID}","{OTHER_AVATAR_ID}"]"#)
+    );
+}
+
+#[cfg(feature = "cache")]
+#[tokio::test]
+async fn batches_replay_their_outbox() {
+    let server = Server::start(404, "").await;
+    let mut config = custom_provider(server.url.clone(), "{avatar_ids}");
+    config.batch = Some(ProviderSettings {
+        flush_threshold: 100,
+        ..Default::default()
+    });
+    let settings = settings(vec![config]);
+
+    // Left unsent by a previous run
+    let cache = Cache::new_in_memory().await.unwrap();
+    let outbox = cache.outbox(ProviderKind::new(1 << 16));
+    outbox.push(AVATAR_ID).await.unwrap();
+
+    let Built { actors, .. } = (custom::DESCRIPTOR.build)(Context {
+        settings,
+        client: Client::new(),
+        cache: Some(cache.clone()),
+    })
+    .await
+    .unwrap();
+    for actor in actors {
+        tokio::spawn(actor.run);
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !outbox.load().await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the replayed batch should be flushed on start");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].body, format!(r#"["{AVATAR_ID}"]"#));
+    let checked = cache.check_all_ids(vec![AVATAR_ID.into()]).await.unwrap();
+    assert_eq!(checked[AVATAR_ID], 1 << 16);
+}
+
+#[test]
+fn rejects_requests_missing_the_avatar_id() {
+    let settings = settings(vec![custom_provider(
+        String::from("https://example.com/"),
+        "{}",
+    )]);
+    assert!(custom::providers(settings, &Client::new()).is_err());
+}
+
+#[tokio::test]
+async fn sends_chunks_in_one_request_without_batching() {
+    let server = Server::start(404, "").await;
+    let settings = settings(vec![custom_provider(server.url.clone(), "{avatar_ids}")]);
+
+    let (providers, actors) = custom::providers(settings, &Client::new()).unwrap();
+    assert!(actors.is_empty());
+
+    let avatar_ids = [AVATAR_ID, OTHER_AVATAR_ID].map(String::from);
+    let results = providers[0].send_avatar_ids(&avatar_ids).await;
+    assert_eq!(results.len(), 2);
+    assert!(
+        results
+            .into_iter()
+            .all(|result| matches!(result, Ok(Delivery::Confirmed(outcome)) if outcome.unique))
+    );
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0].body,
+        format!(r#"["{AVATAR_ID}","{OTHER_AVATAR_ID}"]"#)
+    );
+}
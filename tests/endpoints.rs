@@ -0,0 +1,122 @@
+#![cfg(all(feature = "nsvr", feature = "paw"))]
+
+mod common;
+
+use std::collections::HashMap;
+
+use common::Server;
+use reqwest::Client;
+use vrc_log::{
+    provider::{
+        Provider,
+        ProviderKind,
+        check,
+        lookup::{self, Found},
+        nsvr::NSVR,
+        paw::Paw,
+    },
+    settings::{ProviderSettings, Settings},
+};
+
+const AVATAR_ID: &str = "avtr_00000000-0000-0000-0000-000000000000";
+
+fn settings(kind: ProviderKind, url: String) -> Settings {
+    let provider_settings = ProviderSettings {
+        url: Some(url),
+        ..Default::default()
+    };
+
+    Settings {
+        providers: HashMap::from([(kind, true)]),
+        provider_settings: HashMap::from([(kind, provider_settings)]),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn providers_send_to_the_configured_url() {
+    let server = Server::start(404, "").await;
+    let settings = settings(ProviderKind::NSVR, format!("{}store", server.url));
+
+    let unique = NSVR::new(&settings, Client::new())
+        .send_avatar_id(AVATAR_ID)
+        .await
+        .unwrap();
+
+    assert!(unique);
+    let requests = server.requests();
+    assert_eq!(requests[0].path, "/store");
+    assert!(requests[0].body.contains(AVATAR_ID));
+}
+
+#[tokio::test]
+async fn constructors_override_the_url() {
+    let server = Server::start(200, r#"{"success": true, "code": 200, "avatar": null}"#).await;
+
+    let provider = Paw::new_with_url(&Settings::default(), Client::new(), server.url.clone());
+    assert!(provider.send_avatar_id(AVATAR_ID).await.unwrap());
+
+    assert_eq!(server.requests()[0].path, format!("/?avatarId={AVATAR_ID}"));
+}
+
+#[test]
+fn probes_use_the_configured_url() {
+    let url = String::from("http://127.0.0.1:8080/update");
+    let probes = check::probes(&settings(ProviderKind::PAW, url.clone()));
+
+    assert_eq!(probes.len(), 1);
+    assert!(probes[0].url.starts_with(&url));
+}
+
+#[test]
+#[cfg(all(feature = "avtrdb", feature = "kitsunedb"))]
+fn batching_probes_use_the_url_batches_are_sent_to() {
+    use vrc_log::provider::{avtrdb, kitsunedb};
+
+    // Without a trailing `/`, joining replaces the last segment
+    let url = String::from("http://127.0.0.1:8080/api/v3");
+    for (kind, payloads) in [
+        (
+            ProviderKind::AVTRDB,
+            avtrdb::payloads as fn(&Settings, &[String], &str) -> _,
+        ),
+        (ProviderKind::KITSUNEDB, kitsunedb::payloads),
+    ] {
+        let settings = settings(kind, url.clone());
+        let probes = check::probes(&settings);
+        let payloads = payloads(&settings, &[AVATAR_ID.to_string()], "");
+
+        assert_eq!(probes[0].url, payloads[0].url);
+        assert!(probes[0].url.starts_with("http://127.0.0.1:8080/api/"));
+    }
+}
+
+#[tokio::test]
+async fn lookups_search_the_configured_url() {
+    let results = format!(r#"[{{"id":"{AVATAR_ID}","name":"Robot","authorName":"VRChat"}}]"#);
+    let server = Server::start(200, &results).await;
+    let mut settings = settings(ProviderKind::NSVR, String::new());
+    settings
+        .provider_settings
+        .get_mut(&ProviderKind::NSVR)
+        .unwrap()
+        .search_url = Some(format!("{}vrcx_search", server.url));
+
+    let searches = lookup::searches(&settings);
+    assert_eq!(searches.len(), 1);
+    let lookup = searches
+        .into_iter()
+        .next()
+        .unwrap()
+        .run(&Client::new(), AVATAR_ID)
+        .await;
+
+    let Ok(Found::Known(metadata)) = lookup.found else {
+        panic!("NSVR should have the avatar");
+    };
+    assert_eq!(metadata.name.as_deref(), Some("Robot"));
+    assert_eq!(
+        server.requests()[0].path,
+        format!("/vrcx_search?search={AVATAR_ID}&n=10")
+    );
+}
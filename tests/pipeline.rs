@@ -0,0 +1,142 @@
+#![cfg(all(
+    feature = "cache",
+    feature = "avtrdb",
+    feature = "avtrzip",
+    feature = "cutedb",
+    feature = "kitsunedb",
+    feature = "nsvr",
+    feature = "paw",
+    feature = "vrcdb",
+    feature = "vrcwb",
+))]
+
+mod common;
+
+use std::{fs::OpenOptions, io::Write, time::Duration};
+
+use common::{
+    Server,
+    stand_in::{self, INGESTED},
+};
+use vrc_log::{cache::Cache, provider::ProviderKind, settings::Settings};
+
+const AVATAR_ID: &str = "avtr_c38a1615-5bf5-42b4-84eb-a8b6c37cbd11";
+const LATER_AVATAR_ID: &str = "avtr_08f3ba8b-1c5b-4d7e-9f51-3f2d5b0e2c4a";
+
+async fn wait_until_sent(cache: &Cache, avatar_id: &str, bits: u32) {
+    loop {
+        let checked = cache.check_all_ids([avatar_id.to_string()]).await.unwrap();
+        if checked[avatar_id] & bits == bits {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn submits_avatars_from_watched_logs() {
+    let directory = tempfile::tempdir().unwrap();
+    let log = directory.path().join("output_log_2026-01-01_00-00-00.txt");
+    std::fs::write(
+        &log,
+        format!("[Behaviour] Entering Room: Test World\n[Behaviour] Switching to {AVATAR_ID}\n"),
+    )
+    .unwrap();
+
+    let nsvr = Server::start(404, "").await;
+    let avtrdb = Server::start(200, INGESTED).await;
+    let settings = stand_in::settings(&[
+        (ProviderKind::NSVR, nsvr.url.clone()),
+        (ProviderKind::AVTRDB, avtrdb.url.clone()),
+    ]);
+    let providers = vec![
+        stand_in::provider(ProviderKind::NSVR, settings).await,
+        stand_in::provider(ProviderKind::AVTRDB, settings).await,
+    ];
+    let bits = providers
+        .iter()
+        .fold(0, |bits, provider| bits | provider.bit());
+    let cache = Cache::new_at_location(&directory.path().join("avatars.sqlite"))
+        .await
+        .unwrap();
+
+    let (tx, rx) = flume::unbounded();
+    let _watcher = vrc_log::watch(tx.clone(), directory.path(), 100).unwrap();
+    let pipeline = vrc_log::process_avatars(providers, settings, cache.clone(), (tx, rx));
+
+    let submitted = async {
+        wait_until_sent(&cache, AVATAR_ID, bits).await;
+
+        // Picked up on the next poll, like lines VRChat appends while running
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        writeln!(file, "[Behaviour] Switching to {LATER_AVATAR_ID}").unwrap();
+        wait_until_sent(&cache, LATER_AVATAR_ID, bits).await;
+    };
+
+    tokio::select! {
+        result = pipeline => panic!("The pipeline stopped: {result:?}"),
+        result = tokio::time::timeout(Duration::from_secs(10), submitted) => {
+            result.expect("every provider should receive both avatars");
+        }
+    }
+
+    // Files are re-read whole, but avatars already sent aren't sent again
+    for server in [&nsvr, &avtrdb] {
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].body.contains(AVATAR_ID));
+        assert!(requests[1].body.contains(LATER_AVATAR_ID));
+    }
+
+    let found = cache.search(AVATAR_ID, 1).await.unwrap();
+    assert_eq!(found[0].worlds.as_deref(), Some("Test World"));
+}
+
+#[tokio::test]
+async fn holds_amplitude_until_the_log_names_own_avatars() {
+    let directory = tempfile::tempdir().unwrap();
+    let amplitude = directory.path().join("amplitude.cache");
+    std::fs::write(&amplitude, format!("{AVATAR_ID}\n{LATER_AVATAR_ID}\n")).unwrap();
+    let log = directory.path().join("output_log_2026-01-01_00-00-00.txt");
+    std::fs::write(
+        &log,
+        format!("[API] Requesting Put avatars/{AVATAR_ID}/select\n"),
+    )
+    .unwrap();
+
+    let nsvr = Server::start(404, "").await;
+    let stand_in = stand_in::settings(&[(ProviderKind::NSVR, nsvr.url.clone())]);
+    let settings = Box::leak(Box::new(Settings {
+        providers: stand_in.providers.clone(),
+        provider_settings: stand_in.provider_settings.clone(),
+        exclude_own_avatars: true,
+        ..Default::default()
+    }));
+    let providers = vec![stand_in::provider(ProviderKind::NSVR, settings).await];
+    let cache = Cache::new_at_location(&directory.path().join("avatars.sqlite"))
+        .await
+        .unwrap();
+
+    // Amplitude is polled ten times as often, so it's read first
+    let (tx, rx) = flume::unbounded();
+    tx.send(amplitude).unwrap();
+    let pipeline = vrc_log::process_avatars(providers, settings, cache.clone(), (tx.clone(), rx));
+
+    let submitted = async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        tx.send(log).unwrap();
+        wait_until_sent(&cache, LATER_AVATAR_ID, ProviderKind::NSVR.bit()).await;
+    };
+
+    tokio::select! {
+        result = pipeline => panic!("The pipeline stopped: {result:?}"),
+        result = tokio::time::timeout(Duration::from_secs(10), submitted) => {
+            result.expect("the avatar that isn't the user's should be sent");
+        }
+    }
+
+    let requests = nsvr.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(!requests[0].body.contains(AVATAR_ID));
+    assert_eq!(cache.own_ids().await.unwrap(), [AVATAR_ID]);
+}
@@ -0,0 +1,159 @@
+#![cfg(all(
+    feature = "avtrdb",
+    feature = "avtrzip",
+    feature = "cutedb",
+    feature = "kitsunedb",
+    feature = "nsvr",
+    feature = "paw",
+    feature = "vrcdb",
+    feature = "vrcwb",
+))]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{
+    Response,
+    Server,
+    stand_in::{self, Api},
+};
+use futures::future::join_all;
+
+const AVATAR_ID: &str = "avtr_c38a1615-5bf5-42b4-84eb-a8b6c37cbd11";
+
+/// Batches that failed to flush don't answer until the next interval
+const WAIT: Duration = Duration::from_secs(3);
+
+/// # Submit `AVATAR_ID` through the provider's module to a stand-in answering with `responses`
+///
+/// `None` when the provider didn't answer in time.
+async fn submit(api: &Api, responses: Vec<Response>) -> (Option<anyhow::Result<bool>>, Server) {
+    let server = Server::start_with(responses).await;
+    let settings = stand_in::settings(&[(api.kind, server.url.clone())]);
+    let provider = stand_in::provider(api.kind, settings).await;
+    let result = tokio::time::timeout(WAIT, provider.send_avatar_id(AVATAR_ID))
+        .await
+        .ok();
+
+    (result, server)
+}
+
+/// Runs the test against every provider at once
+async fn for_each_api<F: Future<Output = ()>>(test: impl Fn(Api) -> F) {
+    join_all(stand_in::apis().into_iter().map(test)).await;
+}
+
+#[tokio::test]
+async fn tells_new_avatars_from_known_ones() {
+    for_each_api(|api| async move {
+        if let Some(unique) = api.unique.clone() {
+            let (result, server) = submit(&api, vec![unique]).await;
+            assert!(result.unwrap().unwrap(), "[{}] should be new", api.kind);
+            assert!(
+                server.requests()[0].path.contains(AVATAR_ID)
+                    || server.requests()[0].body.contains(AVATAR_ID),
+                "[{}] should send the avatar",
+                api.kind
+            );
+        }
+
+        let (result, _) = submit(&api, vec![api.duplicate.clone()]).await;
+        assert!(!result.unwrap().unwrap(), "[{}] should be known", api.kind);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn waits_as_long_as_retry_after_asks() {
+    for_each_api(|api| async move {
+        let started = Instant::now();
+        let responses = vec![Response::rate_limited(Some(1)), api.duplicate.clone()];
+        let (result, server) = submit(&api, responses).await;
+
+        assert!(!result.unwrap().unwrap(), "[{}] should retry", api.kind);
+        assert_eq!(server.requests().len(), 2, "[{}]", api.kind);
+        assert!(
+            started.elapsed() >= Duration::from_secs(1),
+            "[{}]",
+            api.kind
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn backs_off_without_retry_after() {
+    for_each_api(|api| async move {
+        let responses = vec![Response::rate_limited(None), api.duplicate.clone()];
+        let (result, server) = submit(&api, responses).await;
+
+        assert!(!result.unwrap().unwrap(), "[{}] should retry", api.kind);
+        assert_eq!(server.requests().len(), 2, "[{}]", api.kind);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn retries_gateway_errors_until_max_attempts() {
+    for_each_api(|api| async move {
+        let responses = vec![Response::new(502, ""), api.duplicate.clone()];
+        let (result, server) = submit(&api, responses).await;
+        assert!(!result.unwrap().unwrap(), "[{}] should retry", api.kind);
+        assert_eq!(server.requests().len(), 2, "[{}]", api.kind);
+
+        let (result, server) = submit(&api, vec![Response::new(502, "")]).await;
+        assert!(
+            !matches!(result, Some(Ok(_))),
+            "[{}] should give up",
+            api.kind
+        );
+        assert_eq!(server.requests().len(), 3, "[{}]", api.kind);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn leaves_server_errors_to_the_provider() {
+    for_each_api(|api| async move {
+        let (result, server) = submit(&api, vec![Response::new(500, "")]).await;
+
+        // VRCDB answers 500 for avatars still in its queue, the others fail
+        assert!(
+            !matches!(result, Some(Ok(false))),
+            "[{}] shouldn't count as known",
+            api.kind
+        );
+        assert_eq!(server.requests().len(), 1, "[{}]", api.kind);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn never_counts_malformed_json_as_new() {
+    for_each_api(|api| async move {
+        if !api.json {
+            return;
+        }
+
+        let (result, _) = submit(&api, vec![Response::new(200, "<html>")]).await;
+        assert!(
+            !matches!(result, Some(Ok(true))),
+            "[{}] shouldn't trust the response",
+            api.kind
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn retries_slow_responses_after_the_timeout() {
+    for_each_api(|api| async move {
+        let slow = api.duplicate.clone().delayed(Duration::from_secs(3));
+        let (result, server) = submit(&api, vec![slow, api.duplicate.clone()]).await;
+
+        assert!(!result.unwrap().unwrap(), "[{}] should retry", api.kind);
+        assert_eq!(server.requests().len(), 2, "[{}]", api.kind);
+    })
+    .await;
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{Response, Server};
+use reqwest::{Client, StatusCode};
+use vrc_log::provider::{limit::RateLimiter, retry::RetryPolicy};
+
+const POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: 3,
+    base_delay:   Duration::from_millis(10),
+    max_delay:    Duration::from_secs(5),
+};
+
+#[tokio::test]
+async fn retries_rate_limits_until_success() {
+    let server = Server::start_with(vec![
+        Response::new(429, "").with_header("Retry-After", "1"),
+        Response::new(502, ""),
+        Response::new(200, "ok"),
+    ])
+    .await;
+
+    let client = Client::new();
+    let started = Instant::now();
+    let response = POLICY
+        .send("Test", &RateLimiter::default(), || client.get(&server.url))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.requests().len(), 3);
+    assert!(
+        started.elapsed() >= Duration::from_secs(1),
+        "Retry-After must be honored"
+    );
+}
+
+#[tokio::test]
+async fn gives_up_after_max_attempts() {
+    let server = Server::start(429, "").await;
+
+    let client = Client::new();
+    let result = POLICY
+        .send("Test", &RateLimiter::default(), || client.get(&server.url))
+        .await;
+
+    assert!(result.is_err());
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn gives_up_when_asked_to_wait_too_long() {
+    let server = Server::start_with(vec![
+        Response::new(429, "").with_header("Retry-After", "3600"),
+    ])
+    .await;
+
+    let client = Client::new();
+    let result = POLICY
+        .send("Test", &RateLimiter::default(), || client.get(&server.url))
+        .await;
+
+    assert!(result.is_err());
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn returns_other_statuses_to_the_provider() {
+    let server = Server::start(503, "").await;
+
+    let client = Client::new();
+    let response = POLICY
+        .send("Test", &RateLimiter::default(), || client.get(&server.url))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(server.requests().len(), 1);
+}